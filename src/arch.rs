@@ -0,0 +1,230 @@
+// Copyright (c) 2018 King's College London
+// Created by the Software Development Team <http://soft-dev.org/>
+//
+// The Universal Permissive License (UPL), Version 1.0
+//
+// Subject to the condition set forth below, permission is hereby granted to any
+// person obtaining a copy of this software, associated documentation and/or
+// data (collectively the "Software"), free of charge and under any and all
+// copyright rights in the Software, and any and all patent rights owned or
+// freely licensable by each licensor hereunder covering either (i) the
+// unmodified Software as contributed to or provided by such licensor, or (ii)
+// the Larger Works (as defined below), to deal in both
+//
+// (a) the Software, and
+// (b) any piece of software and/or hardware listed in the lrgrwrks.txt file
+// if one is included with the Software (each a "Larger Work" to which the Software
+// is contributed by such licensors),
+//
+// without restriction, including without limitation the rights to copy, create
+// derivative works of, display, perform, and distribute the Software and make,
+// use, sell, offer for sale, import, export, have made, and have sold the
+// Software and the Larger Work(s), and to sublicense the foregoing rights on
+// either these or other terms.
+//
+// This license is subject to the following condition: The above copyright
+// notice and either this complete permission notice or at a minimum a reference
+// to the UPL must be included in all copies or substantial portions of the
+// Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// What we know about each architecture that LLVM can emit stackmaps for: chiefly, what the DWARF
+// register numbers in a stackmap location refer to.
+//
+// The register numbers come from the DWARF register mapping of each architecture's psABI:
+//   x86_64:  System V AMD64 ABI, section 3.6.2 "DWARF Register Number Mapping".
+//   AArch64: "DWARF for the Arm 64-bit Architecture", section 4.1.
+//   RISC-V:  RISC-V ELF psABI, "DWARF Register Numbers".
+//   ppc64le: 64-Bit ELF V2 ABI Specification, section 2.4 "DWARF Definition".
+
+use elf;
+
+// ELF machine types.
+const EM_PPC64: u16 = 21;
+const EM_X86_64: u16 = 62;
+const EM_AARCH64: u16 = 183;
+const EM_RISCV: u16 = 243;
+
+const X86_64_REGS: [&str; 33] = [
+    "rax", "rdx", "rcx", "rbx", "rsi", "rdi", "rbp", "rsp",
+    "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15",
+    "rip", "xmm0", "xmm1", "xmm2", "xmm3", "xmm4", "xmm5", "xmm6",
+    "xmm7", "xmm8", "xmm9", "xmm10", "xmm11", "xmm12", "xmm13", "xmm14",
+    "xmm15",
+];
+
+const AARCH64_REGS: [&str; 32] = [
+    "x0", "x1", "x2", "x3", "x4", "x5", "x6", "x7",
+    "x8", "x9", "x10", "x11", "x12", "x13", "x14", "x15",
+    "x16", "x17", "x18", "x19", "x20", "x21", "x22", "x23",
+    "x24", "x25", "x26", "x27", "x28", "x29", "x30", "sp",
+];
+
+const AARCH64_VREGS: [&str; 32] = [
+    "v0", "v1", "v2", "v3", "v4", "v5", "v6", "v7",
+    "v8", "v9", "v10", "v11", "v12", "v13", "v14", "v15",
+    "v16", "v17", "v18", "v19", "v20", "v21", "v22", "v23",
+    "v24", "v25", "v26", "v27", "v28", "v29", "v30", "v31",
+];
+
+const RISCV64_REGS: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2",
+    "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5",
+    "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7",
+    "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+const RISCV64_FREGS: [&str; 32] = [
+    "ft0", "ft1", "ft2", "ft3", "ft4", "ft5", "ft6", "ft7",
+    "fs0", "fs1", "fa0", "fa1", "fa2", "fa3", "fa4", "fa5",
+    "fa6", "fa7", "fs2", "fs3", "fs4", "fs5", "fs6", "fs7",
+    "fs8", "fs9", "fs10", "fs11", "ft8", "ft9", "ft10", "ft11",
+];
+
+const PPC64_REGS: [&str; 32] = [
+    "r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7",
+    "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15",
+    "r16", "r17", "r18", "r19", "r20", "r21", "r22", "r23",
+    "r24", "r25", "r26", "r27", "r28", "r29", "r30", "r31",
+];
+
+const PPC64_FREGS: [&str; 32] = [
+    "f0", "f1", "f2", "f3", "f4", "f5", "f6", "f7",
+    "f8", "f9", "f10", "f11", "f12", "f13", "f14", "f15",
+    "f16", "f17", "f18", "f19", "f20", "f21", "f22", "f23",
+    "f24", "f25", "f26", "f27", "f28", "f29", "f30", "f31",
+];
+
+const PPC64_VREGS: [&str; 32] = [
+    "v0", "v1", "v2", "v3", "v4", "v5", "v6", "v7",
+    "v8", "v9", "v10", "v11", "v12", "v13", "v14", "v15",
+    "v16", "v17", "v18", "v19", "v20", "v21", "v22", "v23",
+    "v24", "v25", "v26", "v27", "v28", "v29", "v30", "v31",
+];
+
+const PPC64_CREGS: [&str; 8] = [
+    "cr0", "cr1", "cr2", "cr3", "cr4", "cr5", "cr6", "cr7",
+];
+
+/// An architecture that we know the DWARF register numbers of.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Arch {
+    X86_64,
+    AArch64,
+    RiscV64,
+    PPC64LE,
+}
+
+impl Arch {
+    /// Get the architecture of an ELF binary whose header's `e_machine` field is `e_machine`. The
+    /// only 64-bit PowerPC that we know of is little-endian.
+    pub fn from_e_machine(e_machine: u16) -> Option<Arch> {
+        match e_machine {
+            EM_X86_64 => Some(Arch::X86_64),
+            EM_AARCH64 => Some(Arch::AArch64),
+            EM_RISCV => Some(Arch::RiscV64),
+            EM_PPC64 => Some(Arch::PPC64LE),
+            _ => None,
+        }
+    }
+
+    /// Get the architecture of `elf_file`. Unlike `from_e_machine()`, this rejects big-endian
+    /// PowerPC and 32-bit RISC-V.
+    pub (crate) fn from_elf(elf_file: &elf::File) -> Option<Arch> {
+        if elf_file.ehdr.class != elf::types::ELFCLASS64 {
+            return None;
+        }
+        match Arch::from_e_machine(elf_file.ehdr.machine.0)? {
+            Arch::PPC64LE if elf_file.ehdr.data != elf::types::ELFDATA2LSB => None,
+            arch => Some(arch),
+        }
+    }
+
+    /// Get the name of the register with DWARF number `dwarf_reg`, e.g. `rbp` for 6 on x86_64.
+    pub fn reg_name(self, dwarf_reg: u16) -> Option<&'static str> {
+        let r = usize::from(dwarf_reg);
+        let from = |table: &[&'static str], first: usize| {
+            r.checked_sub(first).and_then(|i| table.get(i)).cloned()
+        };
+        match self {
+            Arch::X86_64 => match dwarf_reg {
+                0..=32 => from(&X86_64_REGS, 0),
+                49 => Some("rflags"),
+                _ => None,
+            },
+            Arch::AArch64 => match dwarf_reg {
+                0..=31 => from(&AARCH64_REGS, 0),
+                64..=95 => from(&AARCH64_VREGS, 64),
+                _ => None,
+            },
+            Arch::RiscV64 => match dwarf_reg {
+                0..=31 => from(&RISCV64_REGS, 0),
+                32..=63 => from(&RISCV64_FREGS, 32),
+                _ => None,
+            },
+            Arch::PPC64LE => match dwarf_reg {
+                0..=31 => from(&PPC64_REGS, 0),
+                32..=63 => from(&PPC64_FREGS, 32),
+                65 => Some("lr"),
+                66 => Some("ctr"),
+                68..=75 => from(&PPC64_CREGS, 68),
+                76 => Some("xer"),
+                77..=108 => from(&PPC64_VREGS, 77),
+                _ => None,
+            },
+        }
+    }
+
+    /// Get the DWARF number of the stack pointer.
+    pub fn stack_pointer(self) -> u16 {
+        match self {
+            Arch::X86_64 => 7,      // rsp
+            Arch::AArch64 => 31,    // sp
+            Arch::RiscV64 => 2,     // sp
+            Arch::PPC64LE => 1,     // r1
+        }
+    }
+
+    /// Get the DWARF number of the frame pointer.
+    pub fn frame_pointer(self) -> u16 {
+        match self {
+            Arch::X86_64 => 6,      // rbp
+            Arch::AArch64 => 29,    // x29
+            Arch::RiscV64 => 8,     // s0
+            Arch::PPC64LE => 31,    // r31
+        }
+    }
+
+    /// Get the size of a pointer in bytes.
+    pub fn pointer_width(self) -> usize {
+        8
+    }
+
+    /// Get the DWARF numbers of the registers that a function must preserve for its caller. This
+    /// always includes the stack pointer, which a function must restore before returning. Only
+    /// the low 64 bits of the AArch64 vector registers listed are preserved.
+    pub fn callee_saved(self) -> &'static [u16] {
+        match self {
+            // rbx, rbp, rsp, r12-r15.
+            Arch::X86_64 => &[3, 6, 7, 12, 13, 14, 15],
+            // x19-x29, sp, v8-v15.
+            Arch::AArch64 => &[19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 31,
+                               72, 73, 74, 75, 76, 77, 78, 79],
+            // sp, s0-s11, fs0-fs11.
+            Arch::RiscV64 => &[2, 8, 9, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27,
+                               40, 41, 50, 51, 52, 53, 54, 55, 56, 57, 58, 59],
+            // r1, r14-r31, f14-f31, cr2-cr4, v20-v31.
+            Arch::PPC64LE => &[1, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29,
+                               30, 31, 46, 47, 48, 49, 50, 51, 52, 53, 54, 55, 56, 57, 58, 59, 60,
+                               61, 62, 63, 70, 71, 72, 97, 98, 99, 100, 101, 102, 103, 104, 105,
+                               106, 107, 108],
+        }
+    }
+}
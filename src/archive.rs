@@ -0,0 +1,195 @@
+// Copyright (c) 2018 King's College London
+// Created by the Software Development Team <http://soft-dev.org/>
+//
+// The Universal Permissive License (UPL), Version 1.0
+//
+// Subject to the condition set forth below, permission is hereby granted to any
+// person obtaining a copy of this software, associated documentation and/or
+// data (collectively the "Software"), free of charge and under any and all
+// copyright rights in the Software, and any and all patent rights owned or
+// freely licensable by each licensor hereunder covering either (i) the
+// unmodified Software as contributed to or provided by such licensor, or (ii)
+// the Larger Works (as defined below), to deal in both
+//
+// (a) the Software, and
+// (b) any piece of software and/or hardware listed in the lrgrwrks.txt file
+// if one is included with the Software (each a "Larger Work" to which the Software
+// is contributed by such licensors),
+//
+// without restriction, including without limitation the rights to copy, create
+// derivative works of, display, perform, and distribute the Software and make,
+// use, sell, offer for sale, import, export, have made, and have sold the
+// Software and the Larger Work(s), and to sublicense the foregoing rights on
+// either these or other terms.
+//
+// This license is subject to the following condition: The above copyright
+// notice and either this complete permission notice or at a minimum a reference
+// to the UPL must be included in all copies or substantial portions of the
+// Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Read the stackmaps of the object files in a static archive (including Rust `.rlib` files).
+//
+// The archive format isn't formally specified, but the System V/GNU and BSD variants that we
+// handle here are described in the ar(5) manual page of most systems.
+
+use std::fs;
+use std::path::Path;
+use std::slice;
+use std::str;
+use errors::{SMParserError, SMParserResult};
+use StackMapParser;
+
+const AR_MAGIC: &[u8] = b"!<arch>\n";
+const THIN_AR_MAGIC: &[u8] = b"!<thin>\n";
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const HEADER_TRAILER: &[u8] = b"`\n";
+
+// Names of special members.
+const GNU_SYMTAB_NAME: &str = "/";
+const GNU_SYMTAB64_NAME: &str = "/SYM64/";
+const GNU_STRTAB_NAME: &str = "//";
+const BSD_NAME_PREFIX: &str = "#1/";
+
+// Sizes in bytes.
+const SIZE_HEADER: usize = 60;
+
+/// The stackmaps of each object file in a static archive, keyed by member name.
+///
+/// Members that aren't ELF objects (e.g. archive symbol tables and the metadata in an `.rlib`) and
+/// objects without a stackmap section are left out. Since archives may contain several members of
+/// the same name, members are kept in archive order and `get()` finds the first of a given name.
+pub struct StackMapArchive {
+    members: Vec<(String, StackMapParser)>,
+}
+
+impl StackMapArchive {
+    /// Read the archive at `path`.
+    pub fn new(path: &Path) -> SMParserResult<Self> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+
+    /// Read the archive held in `bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> SMParserResult<Self> {
+        if bytes.starts_with(THIN_AR_MAGIC) {
+            return Err(SMParserError::Other(String::from("Thin archives aren't supported")));
+        }
+        if !bytes.starts_with(AR_MAGIC) {
+            return Err(SMParserError::Other(String::from("Not an archive")));
+        }
+
+        let mut members = Vec::new();
+        let mut long_names: &[u8] = &[];
+        let mut pos = AR_MAGIC.len();
+        while pos < bytes.len() {
+            // Header {
+            //     char[16]: Name
+            //     char[12]: Modification time
+            //     char[6]:  Owner ID
+            //     char[6]:  Group ID
+            //     char[8]:  Mode
+            //     char[10]: Size
+            //     char[2]:  Trailer ("`\n")
+            // }
+            let hdr = slice_at(bytes, pos, SIZE_HEADER)?;
+            if &hdr[58..] != HEADER_TRAILER {
+                return Err(SMParserError::Other(format!("Bad archive member header at offset {}", pos)));
+            }
+            let raw_name = ascii_field(&hdr[..16])?;
+            let size = ascii_field(&hdr[48..58])?.parse::<usize>().map_err(|_| {
+                SMParserError::Other(format!("Bad archive member size at offset {}", pos))
+            })?;
+            let mut data = slice_at(bytes, pos + SIZE_HEADER, size)?;
+            // Member data is padded to an even offset.
+            pos += SIZE_HEADER + size + size % 2;
+
+            let name = if raw_name == GNU_SYMTAB_NAME || raw_name == GNU_SYMTAB64_NAME {
+                continue;
+            } else if raw_name == GNU_STRTAB_NAME {
+                // GNU long names table: names terminated by "/\n".
+                long_names = data;
+                continue;
+            } else if let Some(len) = raw_name.strip_prefix(BSD_NAME_PREFIX) {
+                // BSD long name: the name (padded with NULs) precedes the member's data.
+                let len = parse_len(len)?;
+                let name = c_str(slice_at(data, 0, len)?)?;
+                data = &data[len..];
+                name
+            } else if let Some(off) = raw_name.strip_prefix('/') {
+                // GNU long name: an offset into the long names table.
+                let tail = match long_names.get(parse_len(off)?..) {
+                    Some(t) => t,
+                    None => return Err(SMParserError::Other(String::from("Archive is truncated"))),
+                };
+                let end = tail.iter().position(|&b| b == b'\n').unwrap_or(tail.len());
+                str_from(&tail[..end])?.trim_end_matches('/')
+            } else {
+                // GNU short names are terminated by a slash, BSD ones by spaces.
+                raw_name.trim_end_matches('/')
+            };
+
+            if !data.starts_with(ELF_MAGIC) {
+                continue;
+            }
+            match StackMapParser::from_bytes(data) {
+                Ok(p) => members.push((name.to_owned(), p)),
+                Err(SMParserError::NoStackMapSection) => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(Self{members})
+    }
+
+    /// Returns the number of members with stackmaps.
+    pub fn num_members(&self) -> usize {
+        self.members.len()
+    }
+
+    /// Get the parser for the first member named `name`.
+    pub fn get(&self, name: &str) -> Option<&StackMapParser> {
+        self.members.iter().find(|(n, _)| n == name).map(|(_, p)| p)
+    }
+
+    /// Make an iterator over the names and parsers of the members with stackmaps, in archive
+    /// order.
+    pub fn iter(&self) -> slice::Iter<'_, (String, StackMapParser)> {
+        self.members.iter()
+    }
+}
+
+/// Bounds-checked sub-slice of `bytes`.
+fn slice_at(bytes: &[u8], off: usize, len: usize) -> SMParserResult<&[u8]> {
+    match off.checked_add(len) {
+        Some(end) if end <= bytes.len() => Ok(&bytes[off..end]),
+        _ => Err(SMParserError::Other(String::from("Archive is truncated"))),
+    }
+}
+
+fn str_from(bytes: &[u8]) -> SMParserResult<&str> {
+    str::from_utf8(bytes)
+        .map_err(|_| SMParserError::Other(String::from("Archive member name is not valid UTF-8")))
+}
+
+/// Decode a space-padded header field.
+fn ascii_field(bytes: &[u8]) -> SMParserResult<&str> {
+    str_from(bytes).map(|s| s.trim_end_matches(' '))
+}
+
+/// Decode a NUL-padded name.
+fn c_str(bytes: &[u8]) -> SMParserResult<&str> {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    str_from(&bytes[..len])
+}
+
+fn parse_len(s: &str) -> SMParserResult<usize> {
+    s.parse::<usize>()
+        .map_err(|_| SMParserError::Other(format!("Bad archive member name '{}'", s)))
+}
@@ -0,0 +1,189 @@
+// Copyright (c) 2018 King's College London
+// Created by the Software Development Team <http://soft-dev.org/>
+//
+// The Universal Permissive License (UPL), Version 1.0
+//
+// Subject to the condition set forth below, permission is hereby granted to any
+// person obtaining a copy of this software, associated documentation and/or
+// data (collectively the "Software"), free of charge and under any and all
+// copyright rights in the Software, and any and all patent rights owned or
+// freely licensable by each licensor hereunder covering either (i) the
+// unmodified Software as contributed to or provided by such licensor, or (ii)
+// the Larger Works (as defined below), to deal in both
+//
+// (a) the Software, and
+// (b) any piece of software and/or hardware listed in the lrgrwrks.txt file
+// if one is included with the Software (each a "Larger Work" to which the Software
+// is contributed by such licensors),
+//
+// without restriction, including without limitation the rights to copy, create
+// derivative works of, display, perform, and distribute the Software and make,
+// use, sell, offer for sale, import, export, have made, and have sold the
+// Software and the Larger Work(s), and to sublicense the foregoing rights on
+// either these or other terms.
+//
+// This license is subject to the following condition: The above copyright
+// notice and either this complete permission notice or at a minimum a reference
+// to the UPL must be included in all copies or substantial portions of the
+// Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// The interface between the parsers and the library used to read the object file.
+//
+// The parsers only need a handful of things from an object file, which are described by the
+// `ObjectBackend` trait. We ship an implementation for the `elf` crate, which is what `new()` and
+// `from_bytes()` use, and (with the `object` feature) one for the `object` crate, so that a
+// binary already parsed with that crate needn't be parsed again.
+
+use std::io::Cursor;
+use elf;
+use arch::Arch;
+use compress::decompress_section;
+use errors::{SMParserError, SMParserResult};
+use reloc::{SMReloc, apply_dynamic_relocs, read_elf_relocs, static_relocs};
+
+const SHT_SYMTAB: u32 = 2;
+
+/// A symbol from the symbol table of an object file.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct BackendSymbol {
+    /// The name of the symbol.
+    pub name: String,
+    /// The index of the section that the symbol is defined in, if any.
+    pub section: Option<usize>,
+    /// The value of the symbol. In an object file, this is the offset into its section.
+    pub value: u64,
+    /// Is this a section symbol? Such symbols stand for the start of their section.
+    pub is_section: bool,
+}
+
+/// A static relocation, as stored in an object file.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BackendReloc {
+    /// The offset of the relocated field from the start of the section.
+    pub offset: u64,
+    /// The (format and architecture specific) relocation type.
+    pub kind: u32,
+    /// The index of the symbol relocated against, in the list returned by
+    /// `ObjectBackend::symbols()`.
+    pub symbol: usize,
+    /// The addend. Ignored if `implicit_addend` is set.
+    pub addend: i64,
+    /// Is the addend stored in the relocated field, rather than in the relocation?
+    pub implicit_addend: bool,
+}
+
+/// The operations that the parsers need from an object file. Sections are identified by their
+/// index in the section header table.
+pub trait ObjectBackend {
+    /// Find the section called `name`, returning its index.
+    fn section_by_name(&self, name: &str) -> Option<usize>;
+
+    /// Get the name of section `idx`.
+    fn section_name(&self, idx: usize) -> Option<String>;
+
+    /// Get the contents of section `idx`. Compressed sections must be decompressed. For a linked
+    /// binary, any "relative" dynamic relocations (e.g. `R_X86_64_RELATIVE`) against the section
+    /// should be applied, as LLD doesn't store the relocated value in the section.
+    fn section_data(&self, idx: usize) -> SMParserResult<Vec<u8>>;
+
+    /// Get the symbols of the object file, in symbol table order.
+    fn symbols(&self) -> SMParserResult<Vec<BackendSymbol>>;
+
+    /// Get the static relocations against section `idx`. Only object files have these.
+    fn relocations(&self, idx: usize) -> SMParserResult<Vec<BackendReloc>>;
+
+    /// Get the address that function addresses are relative to. Only PE images have this.
+    fn image_base(&self) -> u64 {
+        0
+    }
+
+    /// Get the architecture of the binary, if it's one that we know the registers of.
+    fn arch(&self) -> Option<Arch> {
+        None
+    }
+}
+
+/// Get the contents of the section called `name` from `backend`, and the static relocations
+/// against it. Returns `None` if there's no such section.
+pub (crate) fn section_by_name<B>(backend: &B, name: &str)
+                                  -> SMParserResult<Option<(Vec<u8>, Vec<SMReloc>)>>
+                                  where B: ObjectBackend + ?Sized {
+    let idx = match backend.section_by_name(name) {
+        Some(idx) => idx,
+        None => return Ok(None),
+    };
+    let data = backend.section_data(idx)?;
+    let relocs = static_relocs(backend, idx, &data)?;
+    Ok(Some((data, relocs)))
+}
+
+/// An `ObjectBackend` for a binary parsed with the `elf` crate.
+pub struct ElfBackend {
+    elf_file: elf::File,
+}
+
+impl ElfBackend {
+    /// Make a backend for an ELF binary parsed with the `elf` crate.
+    pub fn new(elf_file: elf::File) -> Self {
+        ElfBackend{elf_file}
+    }
+
+    /// Parse the ELF binary held in `bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> SMParserResult<Self> {
+        Ok(Self::new(elf::File::open_stream(&mut Cursor::new(bytes))?))
+    }
+
+    /// Get the underlying ELF file.
+    pub fn elf_file(&self) -> &elf::File {
+        &self.elf_file
+    }
+}
+
+impl ObjectBackend for ElfBackend {
+    fn section_by_name(&self, name: &str) -> Option<usize> {
+        self.elf_file.sections.iter().position(|s| s.shdr.name == name)
+    }
+
+    fn section_name(&self, idx: usize) -> Option<String> {
+        self.elf_file.sections.get(idx).map(|s| s.shdr.name.clone())
+    }
+
+    fn section_data(&self, idx: usize) -> SMParserResult<Vec<u8>> {
+        let sec = match self.elf_file.sections.get(idx) {
+            Some(s) => s,
+            None => return Err(SMParserError::Other(format!("No section with index {}", idx))),
+        };
+        let mut data = decompress_section(&sec.shdr, sec.data.clone())?;
+        apply_dynamic_relocs(&self.elf_file, &sec.shdr, &mut data)?;
+        Ok(data)
+    }
+
+    fn symbols(&self) -> SMParserResult<Vec<BackendSymbol>> {
+        let symtab = match self.elf_file.sections.iter().find(|s| s.shdr.shtype.0 == SHT_SYMTAB) {
+            Some(s) => s,
+            None => return Ok(Vec::new()),
+        };
+        Ok(self.elf_file.get_symbols(symtab)?.into_iter().map(|sym| BackendSymbol{
+            name: sym.name,
+            section: Some(sym.shndx as usize).filter(|&i| i != 0),
+            value: sym.value,
+            is_section: sym.symtype == elf::types::STT_SECTION,
+        }).collect())
+    }
+
+    fn relocations(&self, idx: usize) -> SMParserResult<Vec<BackendReloc>> {
+        read_elf_relocs(&self.elf_file, idx)
+    }
+
+    fn arch(&self) -> Option<Arch> {
+        Arch::from_elf(&self.elf_file)
+    }
+}
@@ -0,0 +1,370 @@
+// Copyright (c) 2018 King's College London
+// Created by the Software Development Team <http://soft-dev.org/>
+//
+// The Universal Permissive License (UPL), Version 1.0
+//
+// Subject to the condition set forth below, permission is hereby granted to any
+// person obtaining a copy of this software, associated documentation and/or
+// data (collectively the "Software"), free of charge and under any and all
+// copyright rights in the Software, and any and all patent rights owned or
+// freely licensable by each licensor hereunder covering either (i) the
+// unmodified Software as contributed to or provided by such licensor, or (ii)
+// the Larger Works (as defined below), to deal in both
+//
+// (a) the Software, and
+// (b) any piece of software and/or hardware listed in the lrgrwrks.txt file
+// if one is included with the Software (each a "Larger Work" to which the Software
+// is contributed by such licensors),
+//
+// without restriction, including without limitation the rights to copy, create
+// derivative works of, display, perform, and distribute the Software and make,
+// use, sell, offer for sale, import, export, have made, and have sold the
+// Software and the Larger Work(s), and to sublicense the foregoing rights on
+// either these or other terms.
+//
+// This license is subject to the following condition: The above copyright
+// notice and either this complete permission notice or at a minimum a reference
+// to the UPL must be included in all copies or substantial portions of the
+// Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Parse the basic block address map section (`.llvm_bb_addr_map`), which LLVM emits when given
+// `-basic-block-sections=labels`, and use it to find the basic block containing each stackmap
+// record.
+//
+// There's no specification of the format other than LLVM itself. See `decodeBBAddrMapImpl()` in
+// `llvm/lib/Object/ELF.cpp`. The versions we support are:
+//
+//  - The original, unversioned format (section type `SHT_LLVM_BB_ADDR_MAP_V0`, LLVM 12 to 14).
+//  - Version 0 (LLVM 15), which has the same layout as the unversioned format, but with a header.
+//  - Version 1 (LLVM 15), which gives block offsets relative to the end of the previous block.
+//  - Version 2 (LLVM 16 onwards), which adds block IDs and (from LLVM 17) optional features.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+use byteorder::{NativeEndian, ReadBytesExt};
+use backend::{ElfBackend, ObjectBackend};
+use errors::{SMParserError, SMParserResult};
+use reloc::static_relocs;
+use util::read_uleb128;
+use StackMapParser;
+
+// Section types.
+const SHT_LLVM_BB_ADDR_MAP_V0: u32 = 0x6fff_4c08;
+const SHT_LLVM_BB_ADDR_MAP: u32 = 0x6fff_4c0a;
+
+// The newest version of the format that we understand.
+const MAX_BB_ADDR_MAP_VERSION: u8 = 2;
+
+// Feature flags.
+const FEATURE_FUNC_ENTRY_COUNT: u8 = 1 << 0;
+const FEATURE_BB_FREQ: u8 = 1 << 1;
+const FEATURE_BR_PROB: u8 = 1 << 2;
+const FEATURE_MULTI_BB_RANGE: u8 = 1 << 3;
+const KNOWN_FEATURES: u8 = FEATURE_FUNC_ENTRY_COUNT | FEATURE_BB_FREQ | FEATURE_BR_PROB
+    | FEATURE_MULTI_BB_RANGE;
+
+// Basic block metadata flags.
+const MD_HAS_RETURN: u64 = 1 << 0;
+const MD_HAS_TAIL_CALL: u64 = 1 << 1;
+const MD_IS_EH_PAD: u64 = 1 << 2;
+const MD_CAN_FALL_THROUGH: u64 = 1 << 3;
+
+/// Represents a single basic block.
+#[derive(Debug, Eq, PartialEq)]
+pub struct BBEntry {
+    id: u32,            // Block ID. Before version 2 this is the index of the block.
+    offset: u64,        // Offset of the block from the start of its address range.
+    size: u64,
+    metadata: u64,
+}
+
+impl BBEntry {
+    /// Get the ID of the block, as used by the machine basic block. Before version 2 of the
+    /// format, this is the index of the block within the function.
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// Get the offset of the block from the start of the address range containing it.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Get the size of the block in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Get the raw metadata flags of the block.
+    pub fn metadata(&self) -> u64 {
+        self.metadata
+    }
+
+    /// Does the block end with a return?
+    pub fn has_return(&self) -> bool {
+        self.metadata & MD_HAS_RETURN != 0
+    }
+
+    /// Does the block end with a tail call?
+    pub fn has_tail_call(&self) -> bool {
+        self.metadata & MD_HAS_TAIL_CALL != 0
+    }
+
+    /// Is the block an exception handling landing pad?
+    pub fn is_eh_pad(&self) -> bool {
+        self.metadata & MD_IS_EH_PAD != 0
+    }
+
+    /// Can the block fall through to the next one?
+    pub fn can_fall_through(&self) -> bool {
+        self.metadata & MD_CAN_FALL_THROUGH != 0
+    }
+}
+
+/// A contiguous range of the basic blocks of a function. A function only has more than one of
+/// these if its blocks have been split across sections (e.g. into hot and cold parts).
+#[derive(Debug, Eq, PartialEq)]
+pub struct BBRange {
+    addr: u64,
+    blocks: Vec<BBEntry>,
+    key: (usize, u64),  // Where the range is, resolving any relocation (see `SMReloc::target()`).
+}
+
+impl BBRange {
+    /// Get the address of the start of the range.
+    pub fn addr(&self) -> u64 {
+        self.addr
+    }
+
+    /// Get the basic blocks in the range.
+    pub fn blocks(&self) -> &[BBEntry] {
+        &self.blocks
+    }
+
+    /// Find the block containing the instruction at `offset` from the start of the range.
+    fn block_at(&self, offset: u64) -> Option<&BBEntry> {
+        self.blocks.iter().find(|b| offset >= b.offset && offset < b.offset + b.size)
+            // A label right at the end of the final block still belongs to that block.
+            .or_else(|| self.blocks.iter().rev().find(|b| offset == b.offset + b.size))
+    }
+}
+
+/// Represents a single function entry in the basic block address map.
+#[derive(Debug, Eq, PartialEq)]
+pub struct BBFunc {
+    ranges: Vec<BBRange>,
+}
+
+impl BBFunc {
+    /// Get the function address.
+    pub fn addr(&self) -> u64 {
+        self.ranges[0].addr
+    }
+
+    /// Get the address ranges of the function. The first range starts at the function entry.
+    pub fn ranges(&self) -> &[BBRange] {
+        &self.ranges
+    }
+
+    /// Iterate over the basic blocks of the function, in all of its address ranges.
+    pub fn blocks(&self) -> impl Iterator<Item = &BBEntry> {
+        self.ranges.iter().flat_map(|r| r.blocks.iter())
+    }
+}
+
+/// The basic block containing a stackmap record.
+#[derive(Debug)]
+pub struct RecordBlock<'a> {
+    pub func: &'a BBFunc,
+    pub block: &'a BBEntry,
+}
+
+/// Parser for the basic block address map sections of an ELF binary.
+pub struct BBAddrMapParser {
+    funcs: Vec<BBFunc>,
+    by_key: HashMap<(usize, u64), usize>,   // Index into `funcs` by (resolved) function address.
+}
+
+impl BBAddrMapParser {
+    /// Make a parser for the basic block address maps of the ELF binary at `path`.
+    pub fn new(path: &Path) -> SMParserResult<Self> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+
+    /// Make a parser for the basic block address maps of the ELF binary held in `bytes`.
+    ///
+    /// An object file built with `-ffunction-sections` has one map section per function section,
+    /// so all of them are read.
+    pub fn from_bytes(bytes: &[u8]) -> SMParserResult<Self> {
+        let backend = ElfBackend::from_bytes(bytes)?;
+        let mut funcs = Vec::new();
+        for (idx, sec) in backend.elf_file().sections.iter().enumerate() {
+            let unversioned = match sec.shdr.shtype.0 {
+                SHT_LLVM_BB_ADDR_MAP_V0 => true,
+                SHT_LLVM_BB_ADDR_MAP => false,
+                _ => continue,
+            };
+            let data = backend.section_data(idx)?;
+            let relocs = static_relocs(&backend, idx, &data)?
+                .iter().map(|r| (r.offset(), r.target())).collect::<HashMap<_, _>>();
+            read_section(&data, unversioned, &relocs, &mut funcs)?;
+        }
+
+        let by_key = funcs.iter().enumerate().map(|(i, f)| (f.ranges[0].key, i)).collect();
+        Ok(Self{funcs, by_key})
+    }
+
+    /// Returns the number of function entries in the basic block address maps.
+    pub fn num_funcs(&self) -> usize {
+        self.funcs.len()
+    }
+
+    /// Make an iterator over the functions in the basic block address maps.
+    pub fn iter_functions(&self) -> impl Iterator<Item = &BBFunc> {
+        self.funcs.iter()
+    }
+
+    /// Find the function and basic block containing each record of `sm`, which must have been
+    /// made from the same binary. The results are in the same order as
+    /// `StackMapParser::iter_stackmaps()`, with `None` for any record that isn't covered by the
+    /// basic block address map.
+    ///
+    /// Addresses are matched at link-time, so the load bias of `sm` is ignored. In an object
+    /// file, functions are matched through the relocations against the two sections.
+    pub fn locate_records(&self, sm: &StackMapParser) -> SMParserResult<Vec<Option<RecordBlock<'_>>>> {
+        let mut located = Vec::new();
+        let mut recs = sm.iter_stackmaps();
+        for (idx, func) in sm.iter_functions().enumerate() {
+            let func = func?;
+            let key = sm.func_target(idx, &func);
+            let bb_func = self.by_key.get(&key).map(|&i| &self.funcs[i]);
+            for _ in 0..func.record_count() {
+                let rec = match recs.next() {
+                    Some(rec) => rec?,
+                    None => return Err(SMParserError::Other(
+                        String::from("Functions claim more records than the stackmap section has"))),
+                };
+                located.push(bb_func.and_then(|bb_func| {
+                    // The record's instruction may lie in any of the function's ranges in the same
+                    // section as the entry.
+                    let addr = key.1.wrapping_add(u64::from(rec.offset));
+                    bb_func.ranges.iter()
+                        .filter(|r| r.key.0 == key.0 && addr >= r.key.1)
+                        .filter_map(|r| r.block_at(addr - r.key.1))
+                        .next()
+                        .map(|block| RecordBlock{func: bb_func, block})
+                }));
+            }
+        }
+        Ok(located)
+    }
+}
+
+/// Read all of the function entries in one basic block address map section. `relocs` maps the
+/// offset of each relocated field to its target.
+pub (crate) fn read_section(data: &[u8], unversioned: bool, relocs: &HashMap<u64, (usize, u64)>,
+                            funcs: &mut Vec<BBFunc>) -> SMParserResult<()> {
+    let mut cursor = Cursor::new(data);
+    while cursor.position() < data.len() as u64 {
+        // Unversioned maps have no header, and no features.
+        let (version, features) = if unversioned {
+            (0, 0)
+        } else {
+            // uint8: Version
+            let version = cursor.read_u8()?;
+            // uint8: Features
+            let features = cursor.read_u8()?;
+            if version > MAX_BB_ADDR_MAP_VERSION {
+                return Err(SMParserError::Other(
+                    format!("Unsupported basic block address map version {}", version)));
+            }
+            if features & !KNOWN_FEATURES != 0 || (version < 2 && features != 0) {
+                return Err(SMParserError::Other(
+                    format!("Unsupported basic block address map features {:#x}", features)));
+            }
+            (version, features)
+        };
+
+        // ULEB128: NumBBRanges (only with the multiple ranges feature)
+        let num_ranges = if features & FEATURE_MULTI_BB_RANGE != 0 {
+            read_uleb128(&mut cursor)?
+        } else {
+            1
+        };
+        let mut ranges = Vec::new();
+        let mut num_blocks = 0;
+        for _ in 0..num_ranges {
+            // uint64: BaseAddress
+            let field_off = cursor.position();
+            let addr = cursor.read_u64::<NativeEndian>()?;
+            let key = relocs.get(&field_off).cloned().unwrap_or((0, addr));
+            // ULEB128: NumBlocks
+            let n = read_uleb128(&mut cursor)?;
+            let mut blocks = Vec::new();
+            let mut prev_end: u64 = 0;
+            for i in 0..n {
+                // ULEB128: ID (from version 2)
+                let id = if version >= 2 { read_uleb128(&mut cursor)? } else { i };
+                // ULEB128: Offset
+                let mut offset = read_uleb128(&mut cursor)?;
+                // ULEB128: Size
+                let size = read_uleb128(&mut cursor)?;
+                // ULEB128: Metadata
+                let metadata = read_uleb128(&mut cursor)?;
+                // Later versions give the offset from the end of the previous block.
+                if version >= 1 {
+                    offset = prev_end.checked_add(offset).ok_or_else(|| block_overflow(addr))?;
+                }
+                // Checking the end of every block here lets `block_at()` add up offsets freely.
+                prev_end = offset.checked_add(size).ok_or_else(|| block_overflow(addr))?;
+                blocks.push(BBEntry{id: id as u32, offset, size, metadata});
+            }
+            num_blocks += n;
+            ranges.push(BBRange{addr, blocks, key});
+        }
+
+        // Profile data follows the blocks. We don't use it, but we have to skip over it.
+        if features & FEATURE_FUNC_ENTRY_COUNT != 0 {
+            // ULEB128: FuncEntryCount
+            read_uleb128(&mut cursor)?;
+        }
+        if features & (FEATURE_BB_FREQ | FEATURE_BR_PROB) != 0 {
+            for _ in 0..num_blocks {
+                if features & FEATURE_BB_FREQ != 0 {
+                    // ULEB128: BlockFrequency
+                    read_uleb128(&mut cursor)?;
+                }
+                if features & FEATURE_BR_PROB != 0 {
+                    // ULEB128: NumSuccessors
+                    // (ULEB128: SuccessorID, ULEB128: BranchProbability)[NumSuccessors]
+                    let num_succs = read_uleb128(&mut cursor)?;
+                    for _ in 0..num_succs {
+                        read_uleb128(&mut cursor)?;
+                        read_uleb128(&mut cursor)?;
+                    }
+                }
+            }
+        }
+
+        if ranges.is_empty() {
+            return Err(SMParserError::Other(String::from("Function has no address ranges")));
+        }
+        funcs.push(BBFunc{ranges});
+    }
+    Ok(())
+}
+
+/// The error for a block of the range at `addr` that ends beyond the address space.
+fn block_overflow(addr: u64) -> SMParserError {
+    SMParserError::Other(format!("A basic block of the range at {:#x} is out of range", addr))
+}
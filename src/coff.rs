@@ -0,0 +1,212 @@
+// Copyright (c) 2018 King's College London
+// Created by the Software Development Team <http://soft-dev.org/>
+//
+// The Universal Permissive License (UPL), Version 1.0
+//
+// Subject to the condition set forth below, permission is hereby granted to any
+// person obtaining a copy of this software, associated documentation and/or
+// data (collectively the "Software"), free of charge and under any and all
+// copyright rights in the Software, and any and all patent rights owned or
+// freely licensable by each licensor hereunder covering either (i) the
+// unmodified Software as contributed to or provided by such licensor, or (ii)
+// the Larger Works (as defined below), to deal in both
+//
+// (a) the Software, and
+// (b) any piece of software and/or hardware listed in the lrgrwrks.txt file
+// if one is included with the Software (each a "Larger Work" to which the Software
+// is contributed by such licensors),
+//
+// without restriction, including without limitation the rights to copy, create
+// derivative works of, display, perform, and distribute the Software and make,
+// use, sell, offer for sale, import, export, have made, and have sold the
+// Software and the Larger Work(s), and to sublicense the foregoing rights on
+// either these or other terms.
+//
+// This license is subject to the following condition: The above copyright
+// notice and either this complete permission notice or at a minimum a reference
+// to the UPL must be included in all copies or substantial portions of the
+// Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Locate the stackmap section in COFF objects and PE images.
+//
+// The comments in this file reference the "PE Format" page of the Microsoft documentation found
+// here:
+// https://docs.microsoft.com/en-us/windows/win32/debug/pe-format
+
+use std::cmp;
+use std::str;
+use byteorder::{ByteOrder, LittleEndian};
+use arch::Arch;
+use errors::{SMParserError, SMParserResult};
+use util::STACKMAP_SECTION_NAME;
+
+// Machine types of the COFF objects we recognise: i386, x86_64 and AArch64.
+const IMAGE_FILE_MACHINE_I386: u16 = 0x14c;
+const IMAGE_FILE_MACHINE_AMD64: u16 = 0x8664;
+const IMAGE_FILE_MACHINE_ARM64: u16 = 0xaa64;
+const COFF_MACHINES: [u16; 3] =
+    [IMAGE_FILE_MACHINE_I386, IMAGE_FILE_MACHINE_AMD64, IMAGE_FILE_MACHINE_ARM64];
+
+const PE_SIGNATURE: &[u8] = b"PE\0\0";
+const PE32_MAGIC: u16 = 0x10b;
+const PE32_PLUS_MAGIC: u16 = 0x20b;
+
+// Sizes in bytes.
+const SIZE_FILE_HEADER: usize = 20;
+const SIZE_SECTION_HEADER: usize = 40;
+const SIZE_SYMBOL: usize = 18;
+const SIZE_SHORT_NAME: usize = 8;
+
+/// Offsets into the file.
+const OFFS_PE_SIGNATURE_PTR: usize = 0x3c;
+
+/// The contents of a COFF stackmap section, the image base that its addresses are relative to,
+/// and the architecture of the binary (if we know its registers).
+pub (crate) struct CoffSection {
+    pub data: Vec<u8>,
+    pub image_base: u64,
+    pub arch: Option<Arch>,
+}
+
+/// Returns true if `bytes` looks like a PE image or a COFF object.
+pub (crate) fn is_coff(bytes: &[u8]) -> bool {
+    if bytes.starts_with(b"MZ") {
+        return true;
+    }
+    bytes.len() >= SIZE_FILE_HEADER && COFF_MACHINES.contains(&LittleEndian::read_u16(bytes))
+}
+
+/// Find the stackmap section in a COFF object or a PE image and copy out its contents.
+pub (crate) fn stackmap_section(bytes: &[u8]) -> SMParserResult<CoffSection> {
+    // A PE image starts with an MS-DOS stub, which tells us where the PE signature (and the COFF
+    // file header directly after it) can be found. A COFF object starts with the file header.
+    let is_image = bytes.starts_with(b"MZ");
+    let hdr_off = if is_image {
+        let sig_off = read_u32(bytes, OFFS_PE_SIGNATURE_PTR)? as usize;
+        if slice(bytes, sig_off, PE_SIGNATURE.len())? != PE_SIGNATURE {
+            return Err(SMParserError::Other(String::from("Missing PE signature")));
+        }
+        sig_off + PE_SIGNATURE.len()
+    } else {
+        0
+    };
+
+    // COFF File Header {
+    //     uint16: Machine
+    let arch = match read_u16(bytes, hdr_off)? {
+        IMAGE_FILE_MACHINE_AMD64 => Some(Arch::X86_64),
+        IMAGE_FILE_MACHINE_ARM64 => Some(Arch::AArch64),
+        _ => None,
+    };
+    //     uint16: NumberOfSections
+    let num_secs = read_u16(bytes, hdr_off + 2)?;
+    //     uint32: TimeDateStamp
+    //     uint32: PointerToSymbolTable
+    let sym_tab_off = read_u32(bytes, hdr_off + 8)? as usize;
+    //     uint32: NumberOfSymbols
+    let num_syms = read_u32(bytes, hdr_off + 12)? as usize;
+    //     uint16: SizeOfOptionalHeader
+    let opt_hdr_size = read_u16(bytes, hdr_off + 16)? as usize;
+    //     uint16: Characteristics
+    // }
+
+    // The string table (holding section names longer than 8 bytes) follows the symbol table.
+    let str_tab_off = sym_tab_off + num_syms * SIZE_SYMBOL;
+
+    // Only images have an optional header, and it's this that holds the image base.
+    let opt_hdr_off = hdr_off + SIZE_FILE_HEADER;
+    let image_base = if is_image {
+        match read_u16(bytes, opt_hdr_off)? {
+            PE32_MAGIC => u64::from(read_u32(bytes, opt_hdr_off + 28)?),
+            PE32_PLUS_MAGIC => read_u64(bytes, opt_hdr_off + 24)?,
+            m => return Err(SMParserError::Other(format!("Unknown PE optional header magic {:#x}", m))),
+        }
+    } else {
+        0
+    };
+
+    let sec_tab_off = opt_hdr_off + opt_hdr_size;
+    for i in 0..usize::from(num_secs) {
+        let sh_off = sec_tab_off + i * SIZE_SECTION_HEADER;
+        // Section Header {
+        //     uint8[8]: Name
+        let name = section_name(bytes, slice(bytes, sh_off, SIZE_SHORT_NAME)?, str_tab_off)?;
+        // Linkers writing images may truncate long section names to 8 bytes.
+        if name != STACKMAP_SECTION_NAME
+            && !(is_image && name == &STACKMAP_SECTION_NAME[..SIZE_SHORT_NAME]) {
+            continue;
+        }
+        //     uint32: VirtualSize
+        let virt_size = read_u32(bytes, sh_off + 8)? as usize;
+        //     uint32: VirtualAddress
+        //     uint32: SizeOfRawData
+        let raw_size = read_u32(bytes, sh_off + 16)? as usize;
+        //     uint32: PointerToRawData
+        let raw_off = read_u32(bytes, sh_off + 20)? as usize;
+        //     ...
+        // }
+
+        // In an image the raw data is padded up to the file alignment, whereas the virtual size
+        // is the real size of the section. Objects always have a virtual size of zero.
+        let size = if is_image && virt_size != 0 {
+            cmp::min(virt_size, raw_size)
+        } else {
+            raw_size
+        };
+        let data = slice(bytes, raw_off, size)?.to_vec();
+        return Ok(CoffSection { data, image_base, arch });
+    }
+
+    Err(SMParserError::NoStackMapSection)
+}
+
+/// Decode a section name. Names longer than 8 bytes are stored in the string table and the
+/// name field instead holds a slash followed by the decimal offset of the name in that table.
+fn section_name<'a>(bytes: &'a [u8], raw: &'a [u8], str_tab_off: usize) -> SMParserResult<&'a str> {
+    let short = c_str(raw)?;
+    if !short.starts_with('/') {
+        return Ok(short);
+    }
+    let str_off = short[1..].parse::<usize>().map_err(|_| {
+        SMParserError::Other(format!("Malformed long section name '{}'", short))
+    })?;
+    match bytes.get(str_tab_off + str_off..) {
+        Some(tail) => c_str(tail),
+        None => Err(SMParserError::Other(String::from("COFF file is truncated"))),
+    }
+}
+
+/// Interpret `bytes` as a string, terminated by the first NUL byte (if any).
+fn c_str(bytes: &[u8]) -> SMParserResult<&str> {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    str::from_utf8(&bytes[..len])
+        .map_err(|_| SMParserError::Other(String::from("Section name is not valid UTF-8")))
+}
+
+/// Bounds-checked sub-slice of `bytes`.
+fn slice(bytes: &[u8], off: usize, len: usize) -> SMParserResult<&[u8]> {
+    match off.checked_add(len) {
+        Some(end) if end <= bytes.len() => Ok(&bytes[off..end]),
+        _ => Err(SMParserError::Other(String::from("COFF file is truncated"))),
+    }
+}
+
+fn read_u16(bytes: &[u8], off: usize) -> SMParserResult<u16> {
+    slice(bytes, off, 2).map(LittleEndian::read_u16)
+}
+
+fn read_u32(bytes: &[u8], off: usize) -> SMParserResult<u32> {
+    slice(bytes, off, 4).map(LittleEndian::read_u32)
+}
+
+fn read_u64(bytes: &[u8], off: usize) -> SMParserResult<u64> {
+    slice(bytes, off, 8).map(LittleEndian::read_u64)
+}
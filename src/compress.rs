@@ -0,0 +1,121 @@
+// Copyright (c) 2018 King's College London
+// Created by the Software Development Team <http://soft-dev.org/>
+//
+// The Universal Permissive License (UPL), Version 1.0
+//
+// Subject to the condition set forth below, permission is hereby granted to any
+// person obtaining a copy of this software, associated documentation and/or
+// data (collectively the "Software"), free of charge and under any and all
+// copyright rights in the Software, and any and all patent rights owned or
+// freely licensable by each licensor hereunder covering either (i) the
+// unmodified Software as contributed to or provided by such licensor, or (ii)
+// the Larger Works (as defined below), to deal in both
+//
+// (a) the Software, and
+// (b) any piece of software and/or hardware listed in the lrgrwrks.txt file
+// if one is included with the Software (each a "Larger Work" to which the Software
+// is contributed by such licensors),
+//
+// without restriction, including without limitation the rights to copy, create
+// derivative works of, display, perform, and distribute the Software and make,
+// use, sell, offer for sale, import, export, have made, and have sold the
+// Software and the Larger Work(s), and to sublicense the foregoing rights on
+// either these or other terms.
+//
+// This license is subject to the following condition: The above copyright
+// notice and either this complete permission notice or at a minimum a reference
+// to the UPL must be included in all copies or substantial portions of the
+// Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Decompress ELF sections that have the SHF_COMPRESSED flag set.
+//
+// The comments in this file reference the "Section Compression" part of the System V ABI found
+// here:
+// https://www.sco.com/developers/gabi/latest/ch4.sheader.html#section_compression
+
+use std::io::{Cursor, Read};
+use byteorder::{NativeEndian, ReadBytesExt};
+use elf;
+use flate2::read::ZlibDecoder;
+use errors::{SMParserError, SMParserResult};
+
+const SHF_COMPRESSED: u64 = 0x800;
+
+// Compression algorithms.
+const ELFCOMPRESS_ZLIB: u32 = 1;
+const ELFCOMPRESS_ZSTD: u32 = 2;
+
+// Sizes in bytes.
+const SIZE_CHDR: u64 = 24;
+// The most that we'll decompress a section to. Real sections are far smaller than this.
+const MAX_DECOMPRESSED_SIZE: u64 = 256 << 20;
+
+/// If `shdr` says that `data` (the contents of the section) is compressed, decompress it.
+/// Otherwise `data` is returned unchanged.
+pub (crate) fn decompress_section(shdr: &elf::types::SectionHeader, data: Vec<u8>)
+                                  -> SMParserResult<Vec<u8>> {
+    if shdr.flags.0 & SHF_COMPRESSED == 0 {
+        return Ok(data);
+    }
+
+    let mut cursor = Cursor::new(&data);
+    // Elf64_Chdr {
+    //     uint32: ch_type
+    let ch_type = cursor.read_u32::<NativeEndian>()?;
+    //     uint32: ch_reserved
+    //     uint64: ch_size
+    cursor.set_position(8);
+    let ch_size = cursor.read_u64::<NativeEndian>()?;
+    //     uint64: ch_addralign
+    // }
+    let compressed = match data.get(SIZE_CHDR as usize..) {
+        Some(c) => c,
+        None => return Err(SMParserError::Other(format!("Section {} is truncated", shdr.name))),
+    };
+
+    // Don't trust `ch_size`: a small section can decompress to a huge amount of data, and it can
+    // claim to. Reading one byte more than we expect is enough to tell that the data is too long.
+    if ch_size > MAX_DECOMPRESSED_SIZE {
+        let msg = format!("Section {} decompresses to {} bytes, more than the limit of {}",
+                          shdr.name, ch_size, MAX_DECOMPRESSED_SIZE);
+        return Err(SMParserError::Other(msg));
+    }
+    let limit = ch_size + 1;
+    let mut out = Vec::new();
+    match ch_type {
+        ELFCOMPRESS_ZLIB => {
+            ZlibDecoder::new(compressed).take(limit).read_to_end(&mut out)?;
+        },
+        ELFCOMPRESS_ZSTD => decompress_zstd(compressed, limit, &mut out)?,
+        t => return Err(SMParserError::Other(format!("Unknown section compression type {}", t))),
+    }
+
+    if out.len() as u64 != ch_size {
+        let msg = format!("Expected {} bytes from decompressing section {} but got {}",
+                          ch_size, shdr.name, out.len());
+        return Err(SMParserError::Other(msg));
+    }
+    Ok(out)
+}
+
+#[cfg(feature = "zstd")]
+fn decompress_zstd(compressed: &[u8], limit: u64, out: &mut Vec<u8>) -> SMParserResult<()> {
+    let mut compressed = compressed;
+    let decoder = ruzstd::StreamingDecoder::new(&mut compressed)
+        .map_err(|e| SMParserError::Other(format!("Bad zstd data: {}", e)))?;
+    decoder.take(limit).read_to_end(out)?;
+    Ok(())
+}
+
+#[cfg(not(feature = "zstd"))]
+fn decompress_zstd(_: &[u8], _: u64, _: &mut Vec<u8>) -> SMParserResult<()> {
+    Err(SMParserError::Other(String::from("zstd-compressed sections need the `zstd` feature")))
+}
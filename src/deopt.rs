@@ -0,0 +1,131 @@
+// Copyright (c) 2018 King's College London
+// Created by the Software Development Team <http://soft-dev.org/>
+//
+// The Universal Permissive License (UPL), Version 1.0
+//
+// Subject to the condition set forth below, permission is hereby granted to any
+// person obtaining a copy of this software, associated documentation and/or
+// data (collectively the "Software"), free of charge and under any and all
+// copyright rights in the Software, and any and all patent rights owned or
+// freely licensable by each licensor hereunder covering either (i) the
+// unmodified Software as contributed to or provided by such licensor, or (ii)
+// the Larger Works (as defined below), to deal in both
+//
+// (a) the Software, and
+// (b) any piece of software and/or hardware listed in the lrgrwrks.txt file
+// if one is included with the Software (each a "Larger Work" to which the Software
+// is contributed by such licensors),
+//
+// without restriction, including without limitation the rights to copy, create
+// derivative works of, display, perform, and distribute the Software and make,
+// use, sell, offer for sale, import, export, have made, and have sold the
+// Software and the Larger Work(s), and to sublicense the foregoing rights on
+// either these or other terms.
+//
+// This license is subject to the following condition: The above copyright
+// notice and either this complete permission notice or at a minimum a reference
+// to the UPL must be included in all copies or substantial portions of the
+// Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Reconstruct the live values of a frame from the stackmap record at its program counter, so
+// that a runtime can leave compiled code (e.g. when a guard fails) and carry on elsewhere.
+
+use errors::SMParserResult;
+use eval::{eval_loc, LocValue, MemoryReader, RegisterContext};
+use index::IndexedRecord;
+use {SMFunc, SMRec, StackMapParser};
+
+/// One live value of a `DeoptFrame`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DeoptValue {
+    value: LocValue,
+    size: u16,          // Size in bytes of the value, as given by its location.
+}
+
+impl DeoptValue {
+    /// Make a value of `size` bytes, e.g. to store in a location with `materialize()`.
+    pub fn new(value: LocValue, size: u16) -> Self {
+        Self{value, size}
+    }
+
+    /// Get the value, or for a `Direct` location, its address.
+    pub fn value(&self) -> LocValue {
+        self.value
+    }
+
+    /// Get the size of the value in bytes.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+}
+
+/// The live values of a frame stopped at a stackmap record, in the order of the record's
+/// locations.
+#[derive(Debug, Eq, PartialEq)]
+pub struct DeoptFrame {
+    id: u64,            // ID of the record.
+    func: SMFunc,       // Function containing the record.
+    values: Vec<DeoptValue>,
+}
+
+impl DeoptFrame {
+    /// Find the record at `pc` (see `StackMapParser::find_record()`) and evaluate each of its
+    /// locations in the frame described by `regs` and `mem`. `regs` must hold the registers as
+    /// they are at `pc`: for a caller's frame, that's once the callee has returned. Returns
+    /// `None` if there's no record at `pc`.
+    pub fn new<R, M>(sm: &StackMapParser, pc: u64, regs: &R, mem: &M)
+                     -> SMParserResult<Option<Self>>
+                     where R: RegisterContext + ?Sized, M: MemoryReader + ?Sized {
+        let (blob, func, rec) = match sm.find_record(pc)? {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        let mut values = Vec::with_capacity(rec.locs.len());
+        for loc in &rec.locs {
+            values.push(DeoptValue{value: blob.eval_loc(loc, regs, mem)?, size: loc.size});
+        }
+        Ok(Some(Self{id: rec.id, func, values}))
+    }
+
+    /// Evaluate each of the locations of `rec`, which was found through a `RecordIndex`, in the
+    /// frame described by `regs` and `mem` (see `new()`).
+    pub fn for_record<R, M>(rec: &IndexedRecord, regs: &R, mem: &M) -> SMParserResult<Self>
+                            where R: RegisterContext + ?Sized, M: MemoryReader + ?Sized {
+        Self::evaluate(rec.func(), rec.record(), rec.constants(), regs, mem)
+    }
+
+    /// Evaluate each of the locations of `rec`, from function `func` and a blob whose constants
+    /// are `consts`, in the frame described by `regs` and `mem`.
+    pub (crate) fn evaluate<R, M>(func: &SMFunc, rec: &SMRec, consts: &[u64], regs: &R, mem: &M)
+                                  -> SMParserResult<Self>
+                                  where R: RegisterContext + ?Sized, M: MemoryReader + ?Sized {
+        let mut values = Vec::with_capacity(rec.locs.len());
+        for loc in &rec.locs {
+            values.push(DeoptValue{value: eval_loc(loc, consts, regs, mem)?, size: loc.size});
+        }
+        Ok(Self{id: rec.id, func: func.clone(), values})
+    }
+
+    /// Get the ID of the record.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Get the function that the frame belongs to.
+    pub fn func(&self) -> &SMFunc {
+        &self.func
+    }
+
+    /// Get the live values, in the order of the record's locations.
+    pub fn values(&self) -> &[DeoptValue] {
+        &self.values
+    }
+}
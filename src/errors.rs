@@ -39,6 +39,9 @@ use std::error::Error;
 use std::fmt::{self, Formatter, Display};
 use std::io;
 use elf;
+use gimli;
+#[cfg(feature = "object")]
+use object;
 
 #[derive(Debug)]
 pub enum SMParserError {
@@ -46,6 +49,10 @@ pub enum SMParserError {
     ElfParse(elf::ParseError),
     /// Generic IO error.
     IO(io::Error),
+    /// The binary has no stackmap section.
+    NoStackMapSection,
+    /// The binary has no faultmap section.
+    NoFaultMapSection,
     /// Other error.
     Other(String),
 }
@@ -55,6 +62,8 @@ impl Display for SMParserError {
         match self {
             SMParserError::ElfParse(e) => write!(f, "{:?}", e), // `e` doesn't implement `Display`.
             SMParserError::IO(e) => Display::fmt(e, f),
+            SMParserError::NoStackMapSection => write!(f, "Can't find stackmap section in binary"),
+            SMParserError::NoFaultMapSection => write!(f, "Can't find faultmap section in binary"),
             SMParserError::Other(s) => write!(f, "{}", s),
         }
     }
@@ -64,15 +73,19 @@ impl Error for SMParserError {
     fn description(&self) -> &str {
         match self {
             SMParserError::ElfParse(_) => "ELF parse error",
-            SMParserError::IO(e) => e.description(),
+            SMParserError::IO(_) => "IO error",
+            SMParserError::NoStackMapSection => "No stackmap section",
+            SMParserError::NoFaultMapSection => "No faultmap section",
             SMParserError::Other(_) => "Other ykstackmaps error",
         }
     }
 
-    fn cause(&self) -> Option<&Error> {
+    fn cause(&self) -> Option<&dyn Error> {
         match self {
             SMParserError::ElfParse(_) => None, // Doesn't implement `Error`.
             SMParserError::IO(ref e) => Some(e),
+            SMParserError::NoStackMapSection => None,
+            SMParserError::NoFaultMapSection => None,
             SMParserError::Other(_) => None,
         }
     }
@@ -91,4 +104,17 @@ impl From<elf::ParseError> for SMParserError {
     }
 }
 
+impl From<gimli::Error> for SMParserError {
+    fn from(e: gimli::Error) -> Self {
+        SMParserError::Other(format!("DWARF error: {}", e))
+    }
+}
+
+#[cfg(feature = "object")]
+impl From<object::Error> for SMParserError {
+    fn from(e: object::Error) -> Self {
+        SMParserError::Other(e.to_string())
+    }
+}
+
 pub (crate) type SMParserResult<T> = Result<T, SMParserError>;
@@ -0,0 +1,256 @@
+// Copyright (c) 2018 King's College London
+// Created by the Software Development Team <http://soft-dev.org/>
+//
+// The Universal Permissive License (UPL), Version 1.0
+//
+// Subject to the condition set forth below, permission is hereby granted to any
+// person obtaining a copy of this software, associated documentation and/or
+// data (collectively the "Software"), free of charge and under any and all
+// copyright rights in the Software, and any and all patent rights owned or
+// freely licensable by each licensor hereunder covering either (i) the
+// unmodified Software as contributed to or provided by such licensor, or (ii)
+// the Larger Works (as defined below), to deal in both
+//
+// (a) the Software, and
+// (b) any piece of software and/or hardware listed in the lrgrwrks.txt file
+// if one is included with the Software (each a "Larger Work" to which the Software
+// is contributed by such licensors),
+//
+// without restriction, including without limitation the rights to copy, create
+// derivative works of, display, perform, and distribute the Software and make,
+// use, sell, offer for sale, import, export, have made, and have sold the
+// Software and the Larger Work(s), and to sublicense the foregoing rights on
+// either these or other terms.
+//
+// This license is subject to the following condition: The above copyright
+// notice and either this complete permission notice or at a minimum a reference
+// to the UPL must be included in all copies or substantial portions of the
+// Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Work out the value of a stackmap location in a running (or stopped) frame, given the frame's
+// registers and a way to read its memory.
+
+use std::collections::HashMap;
+use std::io;
+use byteorder::{ByteOrder, NativeEndian};
+use errors::{SMParserError, SMParserResult};
+use {LocKind, LocOffset, SMLoc};
+
+// Sizes in bytes.
+const SIZE_VALUE: u16 = 8;
+
+/// Gives the values of the registers of a frame, by DWARF register number.
+pub trait RegisterContext {
+    /// Read the register numbered `dwarf_reg`, or return `None` if its value isn't known.
+    fn read_register(&self, dwarf_reg: u16) -> Option<u64>;
+}
+
+/// A register context held as a map from DWARF register number to value.
+impl RegisterContext for HashMap<u16, u64> {
+    fn read_register(&self, dwarf_reg: u16) -> Option<u64> {
+        self.get(&dwarf_reg).cloned()
+    }
+}
+
+/// A register context held as an array indexed by DWARF register number.
+impl RegisterContext for [u64] {
+    fn read_register(&self, dwarf_reg: u16) -> Option<u64> {
+        self.get(usize::from(dwarf_reg)).cloned()
+    }
+}
+
+/// Sets the values of the registers of a frame, by DWARF register number.
+pub trait RegisterContextMut: RegisterContext {
+    /// Set the register numbered `dwarf_reg` to `value`, or return false if it can't be set.
+    fn write_register(&mut self, dwarf_reg: u16, value: u64) -> bool;
+}
+
+impl RegisterContextMut for HashMap<u16, u64> {
+    fn write_register(&mut self, dwarf_reg: u16, value: u64) -> bool {
+        self.insert(dwarf_reg, value);
+        true
+    }
+}
+
+impl RegisterContextMut for [u64] {
+    fn write_register(&mut self, dwarf_reg: u16, value: u64) -> bool {
+        match self.get_mut(usize::from(dwarf_reg)) {
+            Some(r) => {
+                *r = value;
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+/// Reads the memory that a frame's `Indirect` locations point into.
+pub trait MemoryReader {
+    /// Fill `buf` with the bytes starting at address `addr`.
+    fn read_memory(&self, addr: u64, buf: &mut [u8]) -> io::Result<()>;
+}
+
+/// Writes to the memory of a frame, e.g. to update the values that its locations point to.
+pub trait MemoryWriter {
+    /// Write the bytes of `buf` to memory starting at address `addr`.
+    fn write_memory(&mut self, addr: u64, buf: &[u8]) -> io::Result<()>;
+}
+
+/// The outcome of evaluating a location.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LocValue {
+    /// The value itself, zero-extended to 64 bits.
+    Value(u64),
+    /// The address of the value. `Direct` locations (e.g. the address of an `alloca`) give this.
+    Address(u64),
+}
+
+/// Evaluate `loc` in the frame described by `regs` and `mem`. `ConstIndex` locations index into
+/// `consts`, which must be the constants table of the blob that `loc` came from (see
+/// `SMBlob::iter_constants()`).
+///
+/// `StackMapParser::iter_stackmaps()` merges the records of every blob, losing track of which
+/// constants table each one indexes. To evaluate `ConstIndex` locations of a section with more
+/// than one blob, take the records from `SMBlob::iter_stackmaps()` and evaluate them with
+/// `SMBlob::eval_loc()`, or look them up in a `RecordIndex`, which keeps each record's constants.
+///
+/// Values wider than 64 bits (e.g. vector registers) can't be evaluated, and give an error.
+pub fn eval_loc<R, M>(loc: &SMLoc, consts: &[u64], regs: &R, mem: &M) -> SMParserResult<LocValue>
+                      where R: RegisterContext + ?Sized, M: MemoryReader + ?Sized {
+    eval_loc_with(loc, |idx| consts.get(idx as usize).cloned(), regs, mem)
+}
+
+/// Like `eval_loc()`, but looks up the entries of the constants table with `const_at`.
+pub (crate) fn eval_loc_with<C, R, M>(loc: &SMLoc, const_at: C, regs: &R, mem: &M)
+                                      -> SMParserResult<LocValue>
+                                      where C: Fn(u32) -> Option<u64>,
+                                            R: RegisterContext + ?Sized,
+                                            M: MemoryReader + ?Sized {
+    match loc.kind {
+        LocKind::Register => {
+            check_size(loc)?;
+            Ok(LocValue::Value(truncate(read_register(regs, loc.dwarf_reg)?, loc.size)))
+        },
+        LocKind::Direct => {
+            Ok(LocValue::Address(reg_plus_offset(regs, loc)?))
+        },
+        LocKind::Indirect => {
+            check_size(loc)?;
+            let addr = reg_plus_offset(regs, loc)?;
+            let mut buf = [0; SIZE_VALUE as usize];
+            let buf = &mut buf[..loc.size as usize];
+            mem.read_memory(addr, buf)?;
+            Ok(LocValue::Value(NativeEndian::read_uint(buf, buf.len())))
+        },
+        LocKind::Constant => {
+            // LLVM always writes a sign-extended i32, so however the quirk mode had the offset
+            // decoded, it's sign-extended here (see `QuirkMode::SignedConstants`).
+            let v = match loc.offset {
+                LocOffset::I32(v) => v as u64,
+                LocOffset::U32(v) => v as i32 as u64,
+            };
+            Ok(LocValue::Value(v))
+        },
+        LocKind::ConstIndex => {
+            let idx = match loc.offset {
+                LocOffset::I32(v) if v >= 0 => v as u32,
+                LocOffset::U32(v) => v,
+                LocOffset::I32(v) => return Err(SMParserError::Other(
+                    format!("Negative constant index {}", v))),
+            };
+            match const_at(idx) {
+                Some(v) => Ok(LocValue::Value(v)),
+                None => Err(SMParserError::Other(
+                    format!("Constant index {} is out of range", idx))),
+            }
+        },
+    }
+}
+
+/// Store `value` in `loc`, in the frame described by `regs` and `mem`. A `Register` location is
+/// set to the value zero-extended to 64 bits, and an `Indirect` location has its `size` bytes
+/// written.
+///
+/// The value must fit in the location. `Direct` locations (whose value is an address in the
+/// frame) and constants can't be stored to.
+pub fn store_loc<R, M>(loc: &SMLoc, value: u64, regs: &mut R, mem: &mut M) -> SMParserResult<()>
+                       where R: RegisterContextMut + ?Sized, M: MemoryWriter + ?Sized {
+    check_storable(loc, value)?;
+    match loc.kind {
+        LocKind::Register => {
+            if regs.write_register(loc.dwarf_reg, value) {
+                Ok(())
+            } else {
+                Err(SMParserError::Other(format!("Can't set DWARF register {}", loc.dwarf_reg)))
+            }
+        },
+        LocKind::Indirect => {
+            let addr = reg_plus_offset(regs, loc)?;
+            let mut buf = [0; SIZE_VALUE as usize];
+            let buf = &mut buf[..loc.size as usize];
+            NativeEndian::write_uint(buf, value, buf.len());
+            Ok(mem.write_memory(addr, buf)?)
+        },
+        _ => unreachable!(),
+    }
+}
+
+/// Check that `value` can be stored in `loc` (see `store_loc()`).
+pub (crate) fn check_storable(loc: &SMLoc, value: u64) -> SMParserResult<()> {
+    match loc.kind {
+        LocKind::Register | LocKind::Indirect => (),
+        LocKind::Direct => return Err(SMParserError::Other(
+            String::from("Can't store to a Direct location, as its value is an address"))),
+        LocKind::Constant | LocKind::ConstIndex => return Err(SMParserError::Other(
+            format!("Can't store to a {:?} location", loc.kind))),
+    }
+    check_size(loc)?;
+    if truncate(value, loc.size) != value {
+        return Err(SMParserError::Other(
+            format!("Value {:#x} doesn't fit in a location of {} bytes", value, loc.size)));
+    }
+    Ok(())
+}
+
+/// Check that the value of `loc` fits in a `u64`.
+fn check_size(loc: &SMLoc) -> SMParserResult<()> {
+    if loc.size == 0 || loc.size > SIZE_VALUE {
+        return Err(SMParserError::Other(
+            format!("Can't evaluate a {:?} location of {} bytes", loc.kind, loc.size)));
+    }
+    Ok(())
+}
+
+/// Keep only the low `size` bytes of `v`.
+fn truncate(v: u64, size: u16) -> u64 {
+    if size >= SIZE_VALUE {
+        v
+    } else {
+        v & ((1 << (u32::from(size) * 8)) - 1)
+    }
+}
+
+fn read_register<R: RegisterContext + ?Sized>(regs: &R, dwarf_reg: u16) -> SMParserResult<u64> {
+    match regs.read_register(dwarf_reg) {
+        Some(v) => Ok(v),
+        None => Err(SMParserError::Other(format!("Unknown value for DWARF register {}", dwarf_reg))),
+    }
+}
+
+/// The address that a `Direct` or `Indirect` location refers to.
+pub (crate) fn reg_plus_offset<R>(regs: &R, loc: &SMLoc) -> SMParserResult<u64>
+                                 where R: RegisterContext + ?Sized {
+    let offset = match loc.offset {
+        LocOffset::I32(v) => i64::from(v),
+        LocOffset::U32(v) => i64::from(v),
+    };
+    Ok(read_register(regs, loc.dwarf_reg)?.wrapping_add(offset as u64))
+}
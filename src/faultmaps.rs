@@ -0,0 +1,325 @@
+// Copyright (c) 2018 King's College London
+// Created by the Software Development Team <http://soft-dev.org/>
+//
+// The Universal Permissive License (UPL), Version 1.0
+//
+// Subject to the condition set forth below, permission is hereby granted to any
+// person obtaining a copy of this software, associated documentation and/or
+// data (collectively the "Software"), free of charge and under any and all
+// copyright rights in the Software, and any and all patent rights owned or
+// freely licensable by each licensor hereunder covering either (i) the
+// unmodified Software as contributed to or provided by such licensor, or (ii)
+// the Larger Works (as defined below), to deal in both
+//
+// (a) the Software, and
+// (b) any piece of software and/or hardware listed in the lrgrwrks.txt file
+// if one is included with the Software (each a "Larger Work" to which the Software
+// is contributed by such licensors),
+//
+// without restriction, including without limitation the rights to copy, create
+// derivative works of, display, perform, and distribute the Software and make,
+// use, sell, offer for sale, import, export, have made, and have sold the
+// Software and the Larger Work(s), and to sublicense the foregoing rights on
+// either these or other terms.
+//
+// This license is subject to the following condition: The above copyright
+// notice and either this complete permission notice or at a minimum a reference
+// to the UPL must be included in all copies or substantial portions of the
+// Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Parse the faultmap section that LLVM emits for implicit null checks.
+//
+// The comments in this file reference the "FaultMaps" section of the LLVM documentation found
+// here:
+// https://llvm.org/docs/FaultMaps.html
+
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+use byteorder::{NativeEndian, ReadBytesExt};
+use backend::{section_by_name, ElfBackend, ObjectBackend};
+use errors::{SMParserError, SMParserResult};
+use reloc::SMReloc;
+use util::{cursor_at, cursor_skip};
+
+const FAULTMAP_SECTION_NAME: &str = ".llvm_faultmaps";
+
+// We only support this version of the faultmap header for now.
+const FAULTMAP_VERSION: u8 = 1;
+
+// Sizes in bytes.
+const SIZE_FAULTING_PC_ENTRY: u64 = 12;
+
+/// The kind of memory access that may fault.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FaultKind {
+    FaultingLoad,
+    FaultingLoadStore,
+    FaultingStore,
+}
+
+impl FaultKind {
+    fn from_u32(val: u32) -> SMParserResult<FaultKind> {
+        match val {
+            1 => Ok(FaultKind::FaultingLoad),
+            2 => Ok(FaultKind::FaultingLoadStore),
+            3 => Ok(FaultKind::FaultingStore),
+            x => Err(SMParserError::Other(format!("Unknown fault kind '{}'", x))),
+        }
+    }
+}
+
+/// Represents a single faulting instruction and the handler that takes over if it faults.
+#[derive(Debug, Eq, PartialEq)]
+pub struct FMRec {
+    kind: FaultKind,
+    faulting_pc_offset: u32,    // Offset of the faulting instruction from the start of the func.
+    handler_pc_offset: u32,     // Offset of the handler from the start of the func.
+}
+
+impl FMRec {
+    /// Get the kind of memory access that may fault.
+    pub fn kind(&self) -> FaultKind {
+        self.kind
+    }
+
+    /// Get the offset of the faulting instruction from the start of the containing function.
+    pub fn faulting_pc_offset(&self) -> u32 {
+        self.faulting_pc_offset
+    }
+
+    /// Get the offset of the handler from the start of the containing function.
+    pub fn handler_pc_offset(&self) -> u32 {
+        self.handler_pc_offset
+    }
+}
+
+/// Represents a single function entry and its faulting instructions.
+#[derive(Debug, Eq, PartialEq)]
+pub struct FMFunc {
+    addr: u64,
+    records: Vec<FMRec>,
+}
+
+impl FMFunc {
+    /// Get the function address.
+    pub fn addr(&self) -> u64 {
+        self.addr
+    }
+
+    /// Get the faulting instructions of the function.
+    pub fn records(&self) -> &[FMRec] {
+        &self.records
+    }
+
+    /// Get the address of the faulting instruction described by `rec`, which must be one of this
+    /// function's records.
+    pub fn faulting_pc(&self, rec: &FMRec) -> u64 {
+        self.addr.wrapping_add(u64::from(rec.faulting_pc_offset))
+    }
+
+    /// Get the address of the handler for the instruction described by `rec`, which must be one
+    /// of this function's records.
+    pub fn handler_pc(&self, rec: &FMRec) -> u64 {
+        self.addr.wrapping_add(u64::from(rec.handler_pc_offset))
+    }
+}
+
+/// Iterator over the function entries in the faultmap section. Linking several objects
+/// concatenates their faultmaps, so the iterator moves on to the next header when it runs out of
+/// functions.
+pub struct FMFuncIterator<'a> {
+    cursor: Cursor<&'a [u8]>,
+    load_bias: u64,
+    num_funcs: u32,         // Number of functions left in the current faultmap.
+}
+
+impl<'a> FMFuncIterator<'a> {
+    fn new(data: &'a [u8], load_bias: u64) -> Self {
+        FMFuncIterator{cursor: cursor_at(data, 0), load_bias, num_funcs: 0}
+    }
+}
+
+impl<'a> Iterator for FMFuncIterator<'a> {
+    type Item = SMParserResult<FMFunc>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.num_funcs == 0 {
+            if !skip_padding(&mut self.cursor) {
+                return None;
+            }
+            self.num_funcs = itry!(read_header(&mut self.cursor));
+        }
+
+        // FunctionInfo {
+        //     uint64: FunctionAddress
+        let addr = itry!(self.cursor.read_u64::<NativeEndian>()).wrapping_add(self.load_bias);
+        //     uint32: NumFaultingPCs
+        let num_recs = itry!(self.cursor.read_u32::<NativeEndian>());
+        //     uint32: Reserved (expected to be 0)
+        itry!(cursor_skip(&mut self.cursor, 4));
+        //     FunctionFaultInfo[NumFaultingPCs] {
+        let mut records = Vec::new();
+        for _ in 0..num_recs {
+            //     uint32: FaultKind
+            let kind = itry!(FaultKind::from_u32(itry!(self.cursor.read_u32::<NativeEndian>())));
+            //     uint32: FaultingPCOffset
+            let faulting_pc_offset = itry!(self.cursor.read_u32::<NativeEndian>());
+            //     uint32: HandlerPCOffset
+            let handler_pc_offset = itry!(self.cursor.read_u32::<NativeEndian>());
+            records.push(FMRec{kind, faulting_pc_offset, handler_pc_offset});
+        }
+        //     }
+        // }
+
+        self.num_funcs -= 1;
+        Some(Ok(FMFunc{addr, records}))
+    }
+}
+
+/// Move `cursor` past any zero padding, returning `false` if there's nothing else left. The
+/// linker pads each object's faultmap out to the section's alignment, and a faultmap can never
+/// begin with a zero byte (that would be version 0).
+fn skip_padding(cursor: &mut Cursor<&[u8]>) -> bool {
+    let rest = cursor.get_ref().get(cursor.position() as usize..).unwrap_or(&[]);
+    match rest.iter().position(|&b| b != 0) {
+        Some(pad) => {
+            cursor.set_position(cursor.position() + pad as u64);
+            true
+        },
+        None => false,
+    }
+}
+
+/// Read and check a faultmap header, returning the number of functions that follow it.
+fn read_header(cursor: &mut Cursor<&[u8]>) -> SMParserResult<u32> {
+    // uint8: Fault Map Version
+    let version = cursor.read_u8()?;
+    if version != FAULTMAP_VERSION {
+        let msg = format!("Expected faultmap format v{} but binary is v{}", FAULTMAP_VERSION, version);
+        return Err(SMParserError::Other(msg));
+    }
+    // uint8: Reserved (expected to be 0)
+    let b1 = cursor.read_u8()?;
+    if b1 != 0 {
+        let msg = format!("Expected 0 in faultmap section byte 1, got {}", b1);
+        return Err(SMParserError::Other(msg));
+    }
+    // uint16: Reserved (expected to be 0)
+    let b2_3 = cursor.read_u16::<NativeEndian>()?;
+    if b2_3 != 0 {
+        let msg = format!("Expected 0 in faultmap section bytes 2 and 3, got {}", b2_3);
+        return Err(SMParserError::Other(msg));
+    }
+    // uint32: NumFunctions
+    Ok(cursor.read_u32::<NativeEndian>()?)
+}
+
+/// Parser for the faultmap section of an ELF binary.
+pub struct FaultMapParser {
+    data: Vec<u8>,
+    load_bias: u64,
+    relocs: Vec<SMReloc>,
+    num_funcs: u32,
+}
+
+impl FaultMapParser {
+    /// Make a parser for the faultmap section of the ELF binary at `path`.
+    pub fn new(path: &Path) -> SMParserResult<Self> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+
+    /// Make a parser for the faultmap section of the ELF binary held in `bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> SMParserResult<Self> {
+        Self::from_backend(&ElfBackend::from_bytes(bytes)?)
+    }
+
+    /// Make a parser for the faultmap section of a binary that has already been parsed by an
+    /// object file library, through that library's `ObjectBackend`.
+    pub fn from_backend<B: ObjectBackend + ?Sized>(backend: &B) -> SMParserResult<Self> {
+        match section_by_name(backend, FAULTMAP_SECTION_NAME)? {
+            Some((data, relocs)) => Self::from_section(data, relocs),
+            None => Err(SMParserError::NoFaultMapSection),
+        }
+    }
+
+    pub (crate) fn from_section(data: Vec<u8>, relocs: Vec<SMReloc>) -> SMParserResult<Self> {
+        // Walk the headers up-front, so that a malformed section is reported straight away.
+        let mut num_funcs: u32 = 0;
+        let mut cursor = cursor_at(&data, 0);
+        while skip_padding(&mut cursor) {
+            let n = read_header(&mut cursor)?;
+            for _ in 0..n {
+                // Skip the function address, then the reserved field and the faulting PCs.
+                cursor_skip(&mut cursor, 8)?;
+                let num_recs = cursor.read_u32::<NativeEndian>()?;
+                cursor_skip(&mut cursor, 4 + (u64::from(num_recs) * SIZE_FAULTING_PC_ENTRY) as i64)?;
+            }
+            num_funcs = num_funcs.checked_add(n).ok_or_else(|| SMParserError::Other(
+                String::from("Faultmap section has too many functions")))?;
+        }
+        if cursor.position() > data.len() as u64 {
+            return Err(SMParserError::Other(String::from("Faultmap section is truncated")));
+        }
+        Ok(Self{data, load_bias: 0, relocs, num_funcs})
+    }
+
+    /// Set the load bias: the difference between where the binary was loaded at runtime and the
+    /// addresses it was linked at (see `StackMapParser::set_load_bias()`).
+    pub fn set_load_bias(&mut self, load_bias: u64) {
+        self.load_bias = load_bias;
+    }
+
+    /// Returns the load bias (see `set_load_bias()`).
+    pub fn load_bias(&self) -> u64 {
+        self.load_bias
+    }
+
+    /// Returns the static relocations against the faultmap section, in the order of the fields
+    /// they apply to. Only ELF object files have these.
+    pub fn relocations(&self) -> &[SMReloc] {
+        &self.relocs
+    }
+
+    /// Returns the number of function entries in the faultmap section.
+    pub fn num_funcs(&self) -> u32 {
+        self.num_funcs
+    }
+
+    /// Make an iterator over the functions in the faultmap section.
+    ///
+    /// If the iterator returns an error, the iterator becomes invalid and reuse will lead to
+    /// undefined behaviour.
+    ///
+    /// # Example
+    /// ```
+    /// use std::path::Path;
+    /// use ykstackmaps::FaultMapParser;
+    ///
+    /// match FaultMapParser::new(&Path::new("/bin/ls")) {
+    ///     // It's unlikey /bin/ls contains faultmaps, but you get the idea.
+    ///     Err(e) => println!("error: {}", e),
+    ///     Ok(p) =>  {
+    ///         for func_res in p.iter_functions() {
+    ///             match func_res {
+    ///                 Ok(func) => println!("{:?}", func),
+    ///                 Err(e) => {
+    ///                     println!("error: {}", e);
+    ///                     break; // You must not re-use the iterator upon error.
+    ///                 }
+    ///             }
+    ///         }
+    ///     }
+    /// }
+    pub fn iter_functions(&self) -> FMFuncIterator<'_> {
+        FMFuncIterator::new(&self.data, self.load_bias)
+    }
+}
@@ -0,0 +1,201 @@
+// Copyright (c) 2018 King's College London
+// Created by the Software Development Team <http://soft-dev.org/>
+//
+// The Universal Permissive License (UPL), Version 1.0
+//
+// Subject to the condition set forth below, permission is hereby granted to any
+// person obtaining a copy of this software, associated documentation and/or
+// data (collectively the "Software"), free of charge and under any and all
+// copyright rights in the Software, and any and all patent rights owned or
+// freely licensable by each licensor hereunder covering either (i) the
+// unmodified Software as contributed to or provided by such licensor, or (ii)
+// the Larger Works (as defined below), to deal in both
+//
+// (a) the Software, and
+// (b) any piece of software and/or hardware listed in the lrgrwrks.txt file
+// if one is included with the Software (each a "Larger Work" to which the Software
+// is contributed by such licensors),
+//
+// without restriction, including without limitation the rights to copy, create
+// derivative works of, display, perform, and distribute the Software and make,
+// use, sell, offer for sale, import, export, have made, and have sold the
+// Software and the Larger Work(s), and to sublicense the foregoing rights on
+// either these or other terms.
+//
+// This license is subject to the following condition: The above copyright
+// notice and either this complete permission notice or at a minimum a reference
+// to the UPL must be included in all copies or substantial portions of the
+// Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Find the GC roots of a frame stopped at a statepoint, i.e. the places that hold the heap
+// pointers that are live across the statepoint's call, and update them after a moving collector
+// has moved the objects they point to.
+
+use std::collections::{BTreeMap, HashMap};
+use byteorder::{ByteOrder, NativeEndian};
+use errors::{SMParserError, SMParserResult};
+use eval::{reg_plus_offset, LocValue, MemoryReader, MemoryWriter, RegisterContext};
+use statepoint::Statepoint;
+use {LocKind, SMBlob, SMLoc, StackMapParser};
+
+// Sizes in bytes.
+const SIZE_POINTER: u16 = 8;
+
+/// Where a GC pointer is kept.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RootSlot {
+    /// In memory (usually a stack slot) at this address.
+    Memory(u64),
+    /// In the register with this DWARF number. Where the register is saved, if anywhere, depends
+    /// on the frames below.
+    Register(u16),
+}
+
+/// A derived pointer and the base pointer (i.e. the start of the object) that it was derived
+/// from. A pointer that isn't derived from another is its own base.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct GCRoot {
+    base: RootSlot,
+    derived: RootSlot,
+}
+
+impl GCRoot {
+    /// Get the slot holding the base pointer.
+    pub fn base(&self) -> RootSlot {
+        self.base
+    }
+
+    /// Get the slot holding the derived pointer.
+    pub fn derived(&self) -> RootSlot {
+        self.derived
+    }
+}
+
+/// Find the GC roots of the frame described by `regs`, which is stopped at `pc`: the return
+/// address of a statepoint. The roots are in the order of the statepoint's (base, derived) pairs.
+/// Pairs whose pointers are both constants (e.g. null) have nothing to find and are left out, but
+/// a pair with only one constant is an error. Returns `None` if there's no record at `pc`.
+pub fn gc_roots<R>(sm: &StackMapParser, pc: u64, regs: &R) -> SMParserResult<Option<Vec<GCRoot>>>
+                   where R: RegisterContext + ?Sized {
+    let (_, _, rec) = match sm.find_record(pc)? {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+    let sp = Statepoint::new(&rec)?;
+    let mut roots = Vec::with_capacity(sp.num_gc_pairs());
+    for (base, derived) in sp.gc_pairs() {
+        match (root_slot(base, regs)?, root_slot(derived, regs)?) {
+            (Some(base), Some(derived)) => roots.push(GCRoot{base, derived}),
+            (None, None) => (),
+            (None, Some(_)) => return Err(SMParserError::Other(format!(
+                "Statepoint {} derives a pointer in {} from a constant", rec.id, derived))),
+            (Some(_), None) => return Err(SMParserError::Other(format!(
+                "Statepoint {} derives a constant from a pointer in {}", rec.id, base))),
+        }
+    }
+    Ok(Some(roots))
+}
+
+/// Find where the pointer described by `loc` is kept, or `None` if it's a constant.
+fn root_slot<R: RegisterContext + ?Sized>(loc: &SMLoc, regs: &R) -> SMParserResult<Option<RootSlot>> {
+    match loc.kind {
+        LocKind::Register => Ok(Some(RootSlot::Register(loc.dwarf_reg))),
+        // An `Indirect` location is a spill slot, and a `Direct` one is an `alloca` that holds
+        // the pointer; either way the pointer is in memory at `reg + offset`.
+        LocKind::Direct | LocKind::Indirect => {
+            Ok(Some(RootSlot::Memory(reg_plus_offset(regs, loc)?)))
+        },
+        LocKind::Constant | LocKind::ConstIndex => Ok(None),
+    }
+}
+
+/// Update the GC pointers of the frame described by `regs` and `mem`, which is stopped at `pc`:
+/// the return address of a statepoint. `relocate` is given the old address of each object that a
+/// base pointer refers to, and returns its new address. Each derived pointer is then rebuilt as
+/// the new base plus its original offset from the old base. Returns `false` if there's no record
+/// at `pc`.
+///
+/// `relocate` is called once per object, however many pointers refer to it. Null pointers are left
+/// alone. Every slot is read before any is written, so that slots shared between several (base,
+/// derived) pairs are only updated once. Pointers that are constants can't be updated, so finding
+/// one that isn't null is an error, as is finding one held in a register.
+pub fn relocate_roots<R, M, F>(sm: &StackMapParser, pc: u64, regs: &R, mem: &mut M, mut relocate: F)
+                               -> SMParserResult<bool>
+                               where R: RegisterContext + ?Sized,
+                                     M: MemoryReader + MemoryWriter + ?Sized,
+                                     F: FnMut(u64) -> u64 {
+    let (blob, _, rec) = match sm.find_record(pc)? {
+        Some(found) => found,
+        None => return Ok(false),
+    };
+    let sp = Statepoint::new(&rec)?;
+
+    let mut moved = HashMap::new();     // Old object address to new.
+    let mut writes = BTreeMap::new();   // Slot address to the new pointer to write there.
+    for (base, derived) in sp.gc_pairs() {
+        let (base_slot, derived_slot) = match (pointer_slot(&blob, base, regs, mem)?,
+                                               pointer_slot(&blob, derived, regs, mem)?) {
+            (Some(b), Some(d)) => (b, d),
+            (None, None) => continue,
+            _ => return Err(SMParserError::Other(format!(
+                "Statepoint {} pairs a null constant with a pointer in memory", rec.id))),
+        };
+        let old_base = read_pointer(mem, base_slot)?;
+        if old_base == 0 {
+            continue;
+        }
+        let old_derived = read_pointer(mem, derived_slot)?;
+        let new_base = *moved.entry(old_base).or_insert_with(|| relocate(old_base));
+        let new_derived = new_base.wrapping_add(old_derived.wrapping_sub(old_base));
+        for &(slot, new) in &[(base_slot, new_base), (derived_slot, new_derived)] {
+            match writes.insert(slot, new) {
+                Some(prev) if prev != new => return Err(SMParserError::Other(format!(
+                    "Statepoint {} gives the slot at {:#x} two new values", rec.id, slot))),
+                _ => (),
+            }
+        }
+    }
+
+    let mut buf = [0; SIZE_POINTER as usize];
+    for (slot, new) in writes {
+        NativeEndian::write_u64(&mut buf, new);
+        mem.write_memory(slot, &buf)?;
+    }
+    Ok(true)
+}
+
+/// Find the address of the memory holding the pointer described by `loc`, or `None` if it's a
+/// null constant.
+fn pointer_slot<R, M>(blob: &SMBlob, loc: &SMLoc, regs: &R, mem: &M) -> SMParserResult<Option<u64>>
+                      where R: RegisterContext + ?Sized, M: MemoryReader + ?Sized {
+    match loc.kind {
+        LocKind::Register => Err(SMParserError::Other(format!(
+            "Can't relocate the GC pointer in {} without knowing where the register is saved",
+            loc))),
+        LocKind::Direct | LocKind::Indirect => {
+            if loc.size != SIZE_POINTER {
+                return Err(SMParserError::Other(format!("GC pointer at {} isn't pointer-sized", loc)));
+            }
+            Ok(Some(reg_plus_offset(regs, loc)?))
+        },
+        LocKind::Constant | LocKind::ConstIndex => match blob.eval_loc(loc, regs, mem)? {
+            LocValue::Value(0) => Ok(None),
+            _ => Err(SMParserError::Other(format!("Refusing to relocate the constant GC pointer {}",
+                                                  loc))),
+        },
+    }
+}
+
+fn read_pointer<M: MemoryReader + ?Sized>(mem: &M, addr: u64) -> SMParserResult<u64> {
+    let mut buf = [0; SIZE_POINTER as usize];
+    mem.read_memory(addr, &mut buf)?;
+    Ok(NativeEndian::read_u64(&buf))
+}
@@ -0,0 +1,132 @@
+// Copyright (c) 2018 King's College London
+// Created by the Software Development Team <http://soft-dev.org/>
+//
+// The Universal Permissive License (UPL), Version 1.0
+//
+// Subject to the condition set forth below, permission is hereby granted to any
+// person obtaining a copy of this software, associated documentation and/or
+// data (collectively the "Software"), free of charge and under any and all
+// copyright rights in the Software, and any and all patent rights owned or
+// freely licensable by each licensor hereunder covering either (i) the
+// unmodified Software as contributed to or provided by such licensor, or (ii)
+// the Larger Works (as defined below), to deal in both
+//
+// (a) the Software, and
+// (b) any piece of software and/or hardware listed in the lrgrwrks.txt file
+// if one is included with the Software (each a "Larger Work" to which the Software
+// is contributed by such licensors),
+//
+// without restriction, including without limitation the rights to copy, create
+// derivative works of, display, perform, and distribute the Software and make,
+// use, sell, offer for sale, import, export, have made, and have sold the
+// Software and the Larger Work(s), and to sublicense the foregoing rights on
+// either these or other terms.
+//
+// This license is subject to the following condition: The above copyright
+// notice and either this complete permission notice or at a minimum a reference
+// to the UPL must be included in all copies or substantial portions of the
+// Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// An index of the records of a stackmap section by the address of their instruction, for when
+// records must be found quickly and repeatedly, e.g. for every frame of a stack walk.
+
+use errors::{SMParserError, SMParserResult};
+use {SMFunc, SMRec, StackMapParser};
+
+/// A record found through a `RecordIndex`, along with what's needed to make sense of it.
+#[derive(Clone, Copy, Debug)]
+pub struct IndexedRecord<'a> {
+    func: &'a SMFunc,
+    rec: &'a SMRec,
+    consts: &'a [u64],
+}
+
+impl<'a> IndexedRecord<'a> {
+    /// Get the function that the record belongs to.
+    pub fn func(&self) -> &'a SMFunc {
+        self.func
+    }
+
+    /// Get the record.
+    pub fn record(&self) -> &'a SMRec {
+        self.rec
+    }
+
+    /// Get the constants table of the record's blob, which its `ConstIndex` locations index into.
+    /// See `eval_loc()`.
+    pub fn constants(&self) -> &'a [u64] {
+        self.consts
+    }
+}
+
+struct Entry {
+    pc: u64,
+    func: usize,    // Index into `RecordIndex::funcs`.
+    blob: usize,    // Index into `RecordIndex::consts`.
+    rec: SMRec,
+}
+
+/// The records of a stackmap section, sorted by the address of their instruction (see
+/// `SMFunc::record_addr()`).
+///
+/// The addresses are fixed when the index is made, so set the load bias of the parser first.
+pub struct RecordIndex {
+    funcs: Vec<SMFunc>,
+    consts: Vec<Vec<u64>>,  // The constants table of each blob.
+    entries: Vec<Entry>,
+}
+
+impl RecordIndex {
+    /// Read and index all of the records of `sm`.
+    pub fn new(sm: &StackMapParser) -> SMParserResult<Self> {
+        let mut funcs = Vec::new();
+        let mut consts = Vec::new();
+        let mut entries = Vec::new();
+        for (blob_idx, blob) in sm.iter_blobs().enumerate() {
+            consts.push(blob.iter_constants().collect::<SMParserResult<Vec<_>>>()?);
+            let mut recs = blob.iter_stackmaps();
+            for func in blob.iter_functions() {
+                let func = func?;
+                for _ in 0..func.record_count() {
+                    let rec = match recs.next() {
+                        Some(rec) => rec?,
+                        None => return Err(SMParserError::Other(String::from(
+                            "Functions claim more records than the stackmap section has"))),
+                    };
+                    entries.push(Entry{pc: func.record_addr(&rec), func: funcs.len(), blob: blob_idx,
+                                       rec});
+                }
+                funcs.push(func);
+            }
+        }
+        // The sort is stable, so where records share an address, the first in the section wins
+        // (as with `StackMapParser::find_record()`).
+        entries.sort_by_key(|e| e.pc);
+        entries.dedup_by_key(|e| e.pc);
+        Ok(Self{funcs, consts, entries})
+    }
+
+    /// Returns the number of distinct record addresses in the index.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if the index has no records.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Find the record whose instruction is at `pc`.
+    pub fn lookup(&self, pc: u64) -> Option<IndexedRecord<'_>> {
+        let e = &self.entries[self.entries.binary_search_by_key(&pc, |e| e.pc).ok()?];
+        Some(IndexedRecord{func: &self.funcs[e.func], rec: &e.rec, consts: &self.consts[e.blob]})
+    }
+}
@@ -0,0 +1,274 @@
+// Copyright (c) 2018 King's College London
+// Created by the Software Development Team <http://soft-dev.org/>
+//
+// The Universal Permissive License (UPL), Version 1.0
+//
+// Subject to the condition set forth below, permission is hereby granted to any
+// person obtaining a copy of this software, associated documentation and/or
+// data (collectively the "Software"), free of charge and under any and all
+// copyright rights in the Software, and any and all patent rights owned or
+// freely licensable by each licensor hereunder covering either (i) the
+// unmodified Software as contributed to or provided by such licensor, or (ii)
+// the Larger Works (as defined below), to deal in both
+//
+// (a) the Software, and
+// (b) any piece of software and/or hardware listed in the lrgrwrks.txt file
+// if one is included with the Software (each a "Larger Work" to which the Software
+// is contributed by such licensors),
+//
+// without restriction, including without limitation the rights to copy, create
+// derivative works of, display, perform, and distribute the Software and make,
+// use, sell, offer for sale, import, export, have made, and have sold the
+// Software and the Larger Work(s), and to sublicense the foregoing rights on
+// either these or other terms.
+//
+// This license is subject to the following condition: The above copyright
+// notice and either this complete permission notice or at a minimum a reference
+// to the UPL must be included in all copies or substantial portions of the
+// Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Lazy deoptimisation: rather than stopping a frame further down the stack straight away, its
+// return address is redirected to a trampoline, so that the frame deoptimises when control
+// returns to it. The return address must be that of a stackmap record, whose locations the
+// trampoline evaluates before handing them to a user handler. Only x86_64 Linux is supported.
+//
+// When the trampoline is entered, the callee has returned, so the stack pointer and callee-saved
+// registers are as the frame expects at the record, and the callee's return value is in RAX/RDX
+// or XMM0/XMM1. The trampoline saves all of these, calls `lazy_deopt_entry()` and then restores
+// them before jumping to wherever the handler says.
+
+use std::collections::BTreeMap;
+use std::process;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use deopt::DeoptFrame;
+use errors::{SMParserError, SMParserResult};
+use index::IndexedRecord;
+use trap::LocalMemory;
+use unwind::RegisterSet;
+use {SMFunc, SMRec};
+
+// The number of general-purpose registers, which are saved in DWARF register number order.
+const NUM_GPRS: usize = 16;
+
+// DWARF register numbers.
+const DWARF_RSP: usize = 7;
+const DWARF_XMM0: u16 = 17;
+const DWARF_XMM1: u16 = 18;
+
+// The registers saved by the trampoline, and where it should jump to once it has restored them.
+// The offsets of the fields are hard-coded into the trampoline.
+#[repr(C)]
+struct SavedRegs {
+    gprs: [u64; NUM_GPRS],  // rax, rdx, rcx, rbx, rsi, rdi, rbp, rsp, r8-r15.
+    xmm: [u64; 4],          // xmm0 and xmm1.
+    _pad: u64,              // Keeps the stack 16-byte aligned.
+    resume: u64,
+}
+
+std::arch::global_asm!(
+    ".text",
+    ".p2align 4",
+    ".globl ykstackmaps_lazy_deopt_trampoline",
+    ".hidden ykstackmaps_lazy_deopt_trampoline",
+    ".type ykstackmaps_lazy_deopt_trampoline, @function",
+    "ykstackmaps_lazy_deopt_trampoline:",
+    // We arrive by a `ret`, so the stack is 16-byte aligned, as it must be at the `call`.
+    "sub rsp, 176",
+    "mov [rsp + 0], rax",
+    "mov [rsp + 8], rdx",
+    "mov [rsp + 16], rcx",
+    "mov [rsp + 24], rbx",
+    "mov [rsp + 32], rsi",
+    "mov [rsp + 40], rdi",
+    "mov [rsp + 48], rbp",
+    "lea rax, [rsp + 176]",
+    "mov [rsp + 56], rax",
+    "mov [rsp + 64], r8",
+    "mov [rsp + 72], r9",
+    "mov [rsp + 80], r10",
+    "mov [rsp + 88], r11",
+    "mov [rsp + 96], r12",
+    "mov [rsp + 104], r13",
+    "mov [rsp + 112], r14",
+    "mov [rsp + 120], r15",
+    "movdqu [rsp + 128], xmm0",
+    "movdqu [rsp + 144], xmm1",
+    "mov rdi, rsp",
+    "call {entry}",
+    "mov [rsp + 168], rax",
+    "mov rax, [rsp + 0]",
+    "mov rdx, [rsp + 8]",
+    "mov rcx, [rsp + 16]",
+    "mov rbx, [rsp + 24]",
+    "mov rsi, [rsp + 32]",
+    "mov rdi, [rsp + 40]",
+    "mov rbp, [rsp + 48]",
+    "mov r8, [rsp + 64]",
+    "mov r9, [rsp + 72]",
+    "mov r10, [rsp + 80]",
+    "mov r11, [rsp + 88]",
+    "mov r12, [rsp + 96]",
+    "mov r13, [rsp + 104]",
+    "mov r14, [rsp + 112]",
+    "mov r15, [rsp + 120]",
+    "movdqu xmm0, [rsp + 128]",
+    "movdqu xmm1, [rsp + 144]",
+    // Pop everything but the resume address, and then "return" to it.
+    "add rsp, 168",
+    "ret",
+    ".size ykstackmaps_lazy_deopt_trampoline, . - ykstackmaps_lazy_deopt_trampoline",
+    entry = sym lazy_deopt_entry,
+);
+
+extern "C" {
+    fn ykstackmaps_lazy_deopt_trampoline();
+}
+
+type Handler = dyn Fn(&DeoptFrame, &RegisterSet) -> u64 + Send + Sync;
+
+// A frame whose return address has been redirected.
+struct Patch {
+    ret_addr: u64,
+    func: SMFunc,
+    rec: SMRec,
+    consts: Vec<u64>,
+    handler: Arc<Handler>,
+    restored: bool,     // Whether the return address was put back when the handler was dropped.
+}
+
+// Every patched frame, keyed by the address of its return address slot. A thread may already be
+// on its way into the trampoline when the handler that patched its frame is dropped, so each
+// patch keeps its handler alive, and outlives the `LazyDeoptHandler` itself. The patches that
+// were restored then stay here until their slot is patched again.
+static PATCHES: Mutex<BTreeMap<u64, Patch>> = Mutex::new(BTreeMap::new());
+
+// Whether a handler is installed. There's only one trampoline, so there can only be one handler.
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// Redirects the return addresses of frames, so that they deoptimise when control returns to them.
+///
+/// The handler is called with the values of the record's locations and the registers of the
+/// frame, whose `pc` is the original return address. It's run on the thread that returned into
+/// the frame, and returns the address to carry on at: `RegisterSet::pc()` to carry on as if the
+/// frame hadn't been patched. The registers are restored before jumping there, and the handler
+/// mustn't panic.
+///
+/// Dropping this puts back the return addresses of any frames that are still patched, so those
+/// frames must still be live (see `unpatch()`).
+pub struct LazyDeoptHandler {
+    handler: Arc<Handler>,
+}
+
+impl LazyDeoptHandler {
+    /// Install `handler`. Only one handler can be installed at a time.
+    pub fn install<F>(handler: F) -> SMParserResult<Self>
+                      where F: Fn(&DeoptFrame, &RegisterSet) -> u64 + Send + Sync + 'static {
+        if INSTALLED.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+            return Err(SMParserError::Other(
+                String::from("A lazy deoptimisation handler is already installed")));
+        }
+        Ok(Self{handler: Arc::new(handler)})
+    }
+
+    /// Redirect the return address held in `slot` to the trampoline. `rec` is the record at the
+    /// return address, found through a `RecordIndex` whose addresses are runtime addresses (see
+    /// `StackMapParser::set_load_bias()`).
+    ///
+    /// # Safety
+    ///
+    /// `slot` must be the return address slot of a live frame, i.e. the word just below its
+    /// stack pointer at the record.
+    pub unsafe fn patch(&self, slot: *mut u64, rec: &IndexedRecord) -> SMParserResult<()> {
+        let mut patches = patches();
+        if patches.get(&(slot as u64)).is_some_and(|p| !p.restored) {
+            return Err(SMParserError::Other(
+                format!("The return address at {:#x} is already patched", slot as u64)));
+        }
+        let ret_addr = ptr::read(slot);
+        let rec_addr = rec.func().record_addr(rec.record());
+        if ret_addr != rec_addr {
+            return Err(SMParserError::Other(
+                format!("The return address {:#x} at {:#x} isn't the record's address {:#x}",
+                        ret_addr, slot as u64, rec_addr)));
+        }
+        patches.insert(slot as u64, Patch{ret_addr, func: rec.func().clone(),
+                                          rec: rec.record().clone(),
+                                          consts: rec.constants().to_vec(),
+                                          handler: Arc::clone(&self.handler), restored: false});
+        ptr::write(slot, ykstackmaps_lazy_deopt_trampoline as *const () as u64);
+        Ok(())
+    }
+
+    /// Put back the original return address in `slot`, which must have been patched and not yet
+    /// returned to.
+    ///
+    /// # Safety
+    ///
+    /// The frame whose return address is in `slot` must still be live.
+    pub unsafe fn unpatch(&self, slot: *mut u64) -> SMParserResult<()> {
+        let mut patches = patches();
+        match patches.get(&(slot as u64)) {
+            Some(patch) if !patch.restored => {
+                ptr::write(slot, patch.ret_addr);
+                patches.remove(&(slot as u64));
+                Ok(())
+            },
+            _ => Err(SMParserError::Other(
+                format!("The return address at {:#x} isn't patched", slot as u64))),
+        }
+    }
+}
+
+impl Drop for LazyDeoptHandler {
+    fn drop(&mut self) {
+        // A thread that has already returned to the trampoline still finds its patch, and so
+        // deoptimises with this handler.
+        for (&slot, patch) in patches().iter_mut().filter(|(_, p)| !p.restored) {
+            unsafe { ptr::write(slot as *mut u64, patch.ret_addr) };
+            patch.restored = true;
+        }
+        INSTALLED.store(false, Ordering::SeqCst);
+    }
+}
+
+fn patches() -> MutexGuard<'static, BTreeMap<u64, Patch>> {
+    PATCHES.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Called by the trampoline with the registers it saved. Returns the address to resume at.
+unsafe extern "C" fn lazy_deopt_entry(saved: *const SavedRegs) -> u64 {
+    let saved = &*saved;
+    // The return address was popped from just below the stack pointer.
+    let slot = saved.gprs[DWARF_RSP] - 8;
+    let patch = match patches().remove(&slot) {
+        Some(patch) => patch,
+        None => fatal("returned to a frame that wasn't patched"),
+    };
+
+    let mut regs = RegisterSet::new(patch.ret_addr);
+    for (reg, &val) in saved.gprs.iter().enumerate() {
+        regs.set_register(reg as u16, val);
+    }
+    regs.set_register(DWARF_XMM0, saved.xmm[0]);
+    regs.set_register(DWARF_XMM1, saved.xmm[2]);
+    match DeoptFrame::evaluate(&patch.func, &patch.rec, &patch.consts, &regs, &LocalMemory) {
+        Ok(frame) => (patch.handler)(&frame, &regs),
+        Err(e) => fatal(&format!("can't evaluate the record's locations: {}", e)),
+    }
+}
+
+/// There's no way to carry on from the trampoline without the handler, so give up.
+fn fatal(msg: &str) -> ! {
+    eprintln!("Lazy deoptimisation failed: {}", msg);
+    process::abort();
+}
@@ -35,7 +35,7 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
-// Parse the stackmap section of an ELF binary containing stackmap records.
+// Parse the stackmap section of an ELF or COFF/PE binary containing stackmap records.
 //
 // The comments in this file reference the "Stack Map Format" section of the LLVM documentation
 // found here:
@@ -43,16 +43,82 @@
 
 extern crate elf;
 extern crate byteorder;
-
-mod errors;
+extern crate flate2;
+extern crate gimli;
+#[cfg(feature = "zstd")]
+extern crate ruzstd;
+#[cfg(feature = "object")]
+extern crate object;
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+extern crate libc;
+
+// Must come first, so that its macros are visible to the other modules.
 #[macro_use]
 mod util;
-
+mod arch;
+mod archive;
+mod backend;
+mod bbaddrmap;
+mod coff;
+mod compress;
+mod deopt;
+mod errors;
+mod eval;
+mod faultmaps;
+mod gcroots;
+mod index;
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+mod lazy;
+#[cfg(feature = "object")]
+mod objfile;
+mod osr;
+mod patchpoint;
+mod producer;
+mod reloc;
+mod segments;
+mod stacksizes;
+mod statepoint;
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+mod trap;
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+mod ucontext;
+mod unwind;
+mod walk;
+
+use std::fmt::{self, Display, Formatter};
+use std::fs;
 use std::path::Path;
 use std::io::Cursor;
+use std::slice;
 use byteorder::{NativeEndian, ReadBytesExt};
 use errors::{SMParserError, SMParserResult};
-use util::{cursor_skip, cursor_align8, cursor_from_elf};
+use segments::stackmap_from_segments;
+use backend::section_by_name;
+use util::{cursor_skip, cursor_align8, cursor_at, STACKMAP_SECTION_NAME};
+
+pub use arch::Arch;
+pub use archive::StackMapArchive;
+pub use backend::{BackendReloc, BackendSymbol, ElfBackend, ObjectBackend};
+pub use bbaddrmap::{BBAddrMapParser, BBEntry, BBFunc, BBRange, RecordBlock};
+pub use deopt::{DeoptFrame, DeoptValue};
+pub use eval::{eval_loc, store_loc, LocValue, MemoryReader, MemoryWriter, RegisterContext,
+               RegisterContextMut};
+pub use faultmaps::{FaultKind, FaultMapParser, FMFunc, FMFuncIterator, FMRec};
+pub use gcroots::{gc_roots, relocate_roots, GCRoot, RootSlot};
+pub use index::{IndexedRecord, RecordIndex};
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+pub use lazy::LazyDeoptHandler;
+pub use osr::{materialize, FrameBuilder, NativeFrame};
+pub use patchpoint::Patchpoint;
+pub use reloc::SMReloc;
+pub use stacksizes::{StackSizeEntry, StackSizeMismatch, StackSizesParser};
+pub use statepoint::{GCPairIterator, Statepoint};
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+pub use trap::{Trap, TrapAction, TrapHandler};
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+pub use ucontext::{ucontext_registers, UCONTEXT_NUM_REGS};
+pub use unwind::{CfiStackWalker, CfiUnwinder, RegisterSet};
+pub use walk::{Frame, StackWalker};
 
 // We only support this version of the stackmap header for now.
 const STACKMAP_VERSION: u8 = 3;
@@ -61,21 +127,21 @@ const STACKMAP_VERSION: u8 = 3;
 const SIZE_STACK_SIZE_ENTRY: u8 = 24;
 const SIZE_CONSTANT_ENTRY: u8 = 8;
 const SIZE_LOC_ENTRY: u8 = 12;
-const SIZE_LIVEOUT_ENTRY: u8 = 4;
 
 /// Offsets into the stackmap section.
 const OFFS_STACK_SIZE_ENTRIES: u64 = 16;
 
 /// Represents a single stackmap record entry.
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct SMRec {
     pub id: u64,            // Stackmap ID.
     pub offset: u32,        // Stackmap offset from start of containing func.
     pub num_locs: u16,
     pub locs: Vec<SMLoc>,
+    pub liveouts: Vec<SMLiveOut>,
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct SMLoc {
     pub kind: LocKind,
     pub size: u16,
@@ -83,28 +149,133 @@ pub struct SMLoc {
     pub offset: LocOffset,
 }
 
+impl SMLoc {
+    /// Make something that displays the location, using the register names of `arch`. Without an
+    /// architecture, registers are shown by number (e.g. `R#7`), as llvm-readobj does.
+    pub fn display(&self, arch: Option<Arch>) -> LocDisplay<'_> {
+        LocDisplay{loc: self, arch}
+    }
+}
+
+impl Display for SMLoc {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        self.display(None).fmt(f)
+    }
+}
+
+/// Displays an `SMLoc`. See `SMLoc::display()`.
+pub struct LocDisplay<'a> {
+    loc: &'a SMLoc,
+    arch: Option<Arch>,
+}
+
+impl<'a> Display for LocDisplay<'a> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let loc = self.loc;
+        let reg = RegDisplay{dwarf_reg: loc.dwarf_reg, arch: self.arch};
+        match (&loc.kind, &loc.offset) {
+            (LocKind::Register, _) => write!(f, "Register {}", reg)?,
+            (LocKind::Direct, off) => write!(f, "Direct {} + {}", reg, off)?,
+            (LocKind::Indirect, off) => write!(f, "Indirect [{} + {}]", reg, off)?,
+            (LocKind::Constant, off) => write!(f, "Constant {}", off)?,
+            (LocKind::ConstIndex, off) => write!(f, "ConstantIndex #{}", off)?,
+        }
+        write!(f, ", size: {}", loc.size)
+    }
+}
+
+/// A register that is live after the instruction a record describes, e.g. one holding the
+/// result of a patchpoint.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SMLiveOut {
+    pub dwarf_reg: u16,
+    pub size: u8,       // Size in bytes of the live part of the register.
+}
+
+impl SMLiveOut {
+    /// Make something that displays the live-out, using the register names of `arch`. See
+    /// `SMLoc::display()`.
+    pub fn display(&self, arch: Option<Arch>) -> LiveOutDisplay<'_> {
+        LiveOutDisplay{liveout: self, arch}
+    }
+}
+
+impl Display for SMLiveOut {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        self.display(None).fmt(f)
+    }
+}
+
+/// Displays an `SMLiveOut`. See `SMLiveOut::display()`.
+pub struct LiveOutDisplay<'a> {
+    liveout: &'a SMLiveOut,
+    arch: Option<Arch>,
+}
+
+impl<'a> Display for LiveOutDisplay<'a> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let reg = RegDisplay{dwarf_reg: self.liveout.dwarf_reg, arch: self.arch};
+        write!(f, "{} ({}-bytes)", reg, self.liveout.size)
+    }
+}
+
+/// Displays a register by name if we know it, and otherwise by number.
+struct RegDisplay {
+    dwarf_reg: u16,
+    arch: Option<Arch>,
+}
+
+impl Display for RegDisplay {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self.arch.and_then(|a| a.reg_name(self.dwarf_reg)) {
+            Some(name) => write!(f, "{}", name),
+            None => write!(f, "R#{}", self.dwarf_reg),
+        }
+    }
+}
+
 /// Unfortunately, due to a discrepancy between the llvm stackmap documentation
 /// [0] and the implementation of their own stackmap parser [1], we need this
 /// enum to interpret the integer type differently depending on its `LocKind`.
-/// The offset is interpreted as a u32 *iff* the `LocKind` is a Constant. In all
+/// The offset of a Constant is a u32 in `QuirkMode::UnsignedConstants` (as
+/// llvm-readobj and its test suite expect) and an i32 in
+/// `QuirkMode::SignedConstants` (as LLVM's code generator writes it). In all
 /// other cases, this is an i32. There are examples [2] in the LLVM test suite
 /// where the offset value contains an integer which won't fit inside an i32.
-/// For now, we interpret this in the same way that llvm-readobj and its test
-/// suite expects.
 ///
 /// [0] https://llvm.org/docs/StackMaps.html#id10
 /// [1] https://github.com/llvm/llvm-project/blob/57b38a8593bd7d63b9db09676087365d8d3d0d8a/llvm/include/llvm/Object/StackMapParser.h#L123
 /// [2] https://github.com/llvm/llvm-project/blob/master/llvm/test/CodeGen/X86/stackmap-large-location-size.ll
-///
-// #XXX: Update this when we get clarification on what the correct behaviour is
-// from the LLVM devs.
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum LocOffset {
     I32(i32),
     U32(u32)
 }
 
-#[derive(Debug, Eq, PartialEq)]
+impl Display for LocOffset {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            LocOffset::I32(v) => write!(f, "{}", v),
+            LocOffset::U32(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+/// How to decode the parts of the stackmap format that are ambiguous. The right choice depends on
+/// the compiler that produced the binary, which `StackMapParser` works out from the producer
+/// strings in the binary.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum QuirkMode {
+    /// Read the offset of a `Constant` location as a `u32`, as llvm-readobj does. This is used when
+    /// the producer is unknown.
+    UnsignedConstants,
+    /// Read the offset of a `Constant` location as an `i32`. Since LLVM 3.5, constants that fit in
+    /// an `i32` are sign-extended into the offset field (e.g. -1 is stored as `0xffffffff`), and
+    /// others are put in the constants table.
+    SignedConstants,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum LocKind {
     Register,
     Direct,
@@ -139,7 +310,7 @@ impl SMRec {
 }
 
 /// Represents a single function entry.
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct SMFunc {
     addr: u64,          // Function address.
     stack_size: u64,    // Function's stack size.
@@ -152,6 +323,12 @@ impl SMFunc {
         self.addr
     }
 
+    /// Get the address of the instruction described by `rec`, which must be one of this
+    /// function's records.
+    pub fn record_addr(&self, rec: &SMRec) -> u64 {
+        self.addr.wrapping_add(u64::from(rec.offset))
+    }
+
     /// Get the size of the stack of the function.
     pub fn stack_size(&self) -> u64 {
         self.stack_size
@@ -162,27 +339,97 @@ impl SMFunc {
     }
 }
 
+/// The position and table sizes of one stackmap blob within the stackmap section.
+#[derive(Debug)]
+struct BlobHeader {
+    offset: u64,
+    num_funcs: u32,
+    num_consts: u32,
+    num_stackmaps: u32,
+}
+
+impl BlobHeader {
+    /// Read and check the header of the blob starting at `offset`.
+    fn read(data: &[u8], offset: u64) -> SMParserResult<Self> {
+        let mut cursor = cursor_at(data, offset);
+        Self::check_header(&mut cursor)?;
+
+        // Read in table sizes.
+        // uint32: NumFunctions
+        let num_funcs = cursor.read_u32::<NativeEndian>()?;
+        // uint32: NumConstants
+        let num_consts = cursor.read_u32::<NativeEndian>()?;
+        // uint32: NumRecords
+        let num_stackmaps = cursor.read_u32::<NativeEndian>()?;
+
+        Ok(Self{offset, num_funcs, num_consts, num_stackmaps})
+    }
+
+    /// Check the stackmap header looks sane.
+    fn check_header(cursor: &mut Cursor<&[u8]>) -> SMParserResult<()> {
+        // uint8: Stack Map Version
+        let version = cursor.read_u8()?;
+        if version != STACKMAP_VERSION {
+            let msg = format!("Expected stackmap format v{} but binary is v{}", STACKMAP_VERSION, version);
+            return Err(SMParserError::Other(msg));
+        }
+        // uint8: Reserved (expected to be 0)
+        let b2 = cursor.read_u8()?;
+        if b2 != 0 {
+            let msg = format!("Expected 0 in stackmap section byte 2, got {}", b2);
+            return Err(SMParserError::Other(msg));
+        }
+        // uint16: Reserved (expected to be 0)
+        let b2_3 = cursor.read_u16::<NativeEndian>()?;
+        if b2_3 != 0 {
+            let msg = format!("Expected 0 in stackmap section bytes 2 and 3, got {}", b2_3);
+            return Err(SMParserError::Other(msg));
+        }
+        Ok(())
+    }
+
+    /// Position of the first function entry.
+    fn funcs_pos(&self) -> u64 {
+        self.offset + OFFS_STACK_SIZE_ENTRIES
+    }
+
+    /// Position of the first constant entry.
+    fn consts_pos(&self) -> u64 {
+        self.funcs_pos() + u64::from(self.num_funcs) * u64::from(SIZE_STACK_SIZE_ENTRY)
+    }
+
+    /// Position of the first stackmap record entry.
+    fn stackmaps_pos(&self) -> u64 {
+        self.consts_pos() + u64::from(self.num_consts) * u64::from(SIZE_CONSTANT_ENTRY)
+    }
+}
+
 /// An iterator over stackmap record entries.
 pub struct SMRecIterator<'a> {
-    elf_file: &'a elf::File,
-    cursor: Option<Cursor<&'a Vec<u8>>>, // Lazily created so that creating the iterator doesn't
-                                         // need to return a `Result`.
-    start_pos: u64,                      // Start position of the cursor.
-    num_stackmaps: u32,
+    data: &'a [u8],
+    blobs: &'a [BlobHeader],    // Blobs whose records are yet to be visited.
+    cursor: Cursor<&'a [u8]>,
+    num_stackmaps: u32,         // Records left in the current blob.
+    quirks: QuirkMode,
+}
+
+impl<'a> SMRecIterator<'a> {
+    fn new(data: &'a [u8], blobs: &'a [BlobHeader], quirks: QuirkMode) -> Self {
+        SMRecIterator{data, blobs, cursor: cursor_at(data, 0), num_stackmaps: 0, quirks}
+    }
 }
 
 impl<'a> Iterator for SMRecIterator<'a> {
     type Item = SMParserResult<SMRec>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.num_stackmaps == 0 {
-            return None;
-        }
-
-        if self.cursor.is_none() {
-            self.cursor = Some(itry!(cursor_from_elf(self.elf_file, self.start_pos)));
+        while self.num_stackmaps == 0 {
+            let (blob, rest) = self.blobs.split_first()?;
+            self.blobs = rest;
+            self.cursor.set_position(blob.stackmaps_pos());
+            self.num_stackmaps = blob.num_stackmaps;
         }
-        let mut cursor = self.cursor.as_mut().unwrap();
+        let cursor = &mut self.cursor;
 
         // StkMapRecord[NumRecords] {
         //     uint64: PatchPoint ID
@@ -194,51 +441,58 @@ impl<'a> Iterator for SMRecIterator<'a> {
         // of the (variable-sized) entry to find the start of the next.
 
         //     uint16: Reserved (record flags)
-        itry!(cursor_skip(&mut cursor, 2));
+        itry!(cursor_skip(cursor, 2));
 
         //     uint16: NumLocations
         let num_locs = itry!(cursor.read_u16::<NativeEndian>());
         //     Location[NumLocations] { ... }
         let loc_iter = SMLocIterator {
-            elf_file: self.elf_file,
-            cursor: None,
-            start_pos: cursor.position(),
-            num_locs: num_locs
+            cursor: cursor_at(self.data, cursor.position()),
+            num_locs,
+            quirks: self.quirks,
         };
 
         let mut locs = Vec::with_capacity(num_locs as usize);
         for loc in loc_iter {
-            locs.push(loc.expect("malformed location"))
+            locs.push(itry!(loc));
         }
 
         let skip_locs_sz = u64::from(u32::from(num_locs) * u32::from(SIZE_LOC_ENTRY));
-        itry!(cursor_skip(&mut cursor, skip_locs_sz as i64));
+        itry!(cursor_skip(cursor, skip_locs_sz as i64));
 
         //     uint32: Padding (only if required to align to 8 byte)
         //     uint16: Padding
-        itry!(cursor_align8(&mut cursor));
-        itry!(cursor_skip(&mut cursor, 2));
+        itry!(cursor_align8(cursor));
+        itry!(cursor_skip(cursor, 2));
 
         //     uint16: NumLiveOuts
         let num_liveouts = itry!(cursor.read_u16::<NativeEndian>());
-        //     LiveOuts[NumLiveOuts] { ... }
-        let skip_liveouts_len = i64::from(num_liveouts) * i64::from(SIZE_LIVEOUT_ENTRY);
-        itry!(cursor_skip(&mut cursor, skip_liveouts_len));
+        let mut liveouts = Vec::with_capacity(num_liveouts as usize);
+        for _ in 0..num_liveouts {
+            // LiveOuts[NumLiveOuts] {
+            //     uint16: Dwarf RegNum
+            let dwarf_reg = itry!(cursor.read_u16::<NativeEndian>());
+            //     uint8: Reserved
+            itry!(cursor_skip(cursor, 1));
+            //     uint8: Size in Bytes
+            let size = itry!(cursor.read_u8());
+            // }
+            liveouts.push(SMLiveOut{dwarf_reg, size});
+        }
 
         //     uint32: Padding (only if required to align to 8 byte)
-        itry!(cursor_align8(&mut cursor));
+        itry!(cursor_align8(cursor));
         // } -- End of this stackmap record.
 
         self.num_stackmaps -= 1;
-        Some(Ok(SMRec { id, offset, num_locs, locs }))
+        Some(Ok(SMRec { id, offset, num_locs, locs, liveouts }))
     }
 }
 
 struct SMLocIterator<'a> {
-    elf_file: &'a elf::File,
-    cursor: Option<Cursor<&'a Vec<u8>>>,
-    start_pos: u64,
-    num_locs: u16
+    cursor: Cursor<&'a [u8]>,
+    num_locs: u16,
+    quirks: QuirkMode,
 }
 
 impl<'a> Iterator for SMLocIterator<'a> {
@@ -248,27 +502,30 @@ impl<'a> Iterator for SMLocIterator<'a> {
         if self.num_locs == 0 {
             return None;
         }
-
-        if self.cursor.is_none() {
-            self.cursor = Some(itry!(cursor_from_elf(self.elf_file, self.start_pos)));
-        }
-
-        let cursor = self.cursor.as_mut().unwrap();
+        let cursor = &mut self.cursor;
         // Location[NumRecords] {
         //     uint8: Register | Direct | Indirect | Constant | ConstIndex
         let kind = itry!(cursor.read_u8());
         let kind = itry!(LocKind::from_hex(kind));
         //     uint8: Reserved (expected to be 0)
-        assert_eq!(itry!(cursor.read_u8()), 0);
+        let b1 = itry!(cursor.read_u8());
+        if b1 != 0 {
+            let msg = format!("Expected 0 in location byte 1, got {}", b1);
+            return Some(Err(SMParserError::Other(msg)));
+        }
         //     uint16: Location Size
         let size = itry!(cursor.read_u16::<NativeEndian>());
         //     uint16: Dwarf RegNum
         let dwarf_reg = itry!(cursor.read_u16::<NativeEndian>());
         //     uint16: Reserved (expected to be 0)
-        assert_eq!(itry!(cursor.read_u16::<NativeEndian>()), 0);
+        let b6_7 = itry!(cursor.read_u16::<NativeEndian>());
+        if b6_7 != 0 {
+            let msg = format!("Expected 0 in location bytes 6 and 7, got {}", b6_7);
+            return Some(Err(SMParserError::Other(msg)));
+        }
         //     int32 | uint32 : Offset
         let offset = match kind {
-            LocKind::Constant => {
+            LocKind::Constant if self.quirks == QuirkMode::UnsignedConstants => {
                 let v = itry!(cursor.read_u32::<NativeEndian>());
                 LocOffset::U32(v)
             },
@@ -284,29 +541,33 @@ impl<'a> Iterator for SMLocIterator<'a> {
 
 /// An iterator over function entries.
 pub struct SMFuncIterator<'a> {
-    elf_file: &'a elf::File,
-    cursor: Option<Cursor<&'a Vec<u8>>>, // Lazily created so that creating the iterator doesn't
-                                         // need to return a `Result`.
-    start_pos: u64,                      // Start position of the cursor.
-    num_funcs: u32,
+    blobs: &'a [BlobHeader],    // Blobs whose functions are yet to be visited.
+    cursor: Cursor<&'a [u8]>,
+    addr_bias: u64,             // Added (with wrapping) to each function address.
+    num_funcs: u32,             // Functions left in the current blob.
+}
+
+impl<'a> SMFuncIterator<'a> {
+    fn new(data: &'a [u8], blobs: &'a [BlobHeader], addr_bias: u64) -> Self {
+        SMFuncIterator{blobs, cursor: cursor_at(data, 0), addr_bias, num_funcs: 0}
+    }
 }
 
 impl<'a> Iterator for SMFuncIterator<'a> {
     type Item = SMParserResult<SMFunc>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.num_funcs == 0 {
-            return None;
-        }
-
-        if self.cursor.is_none() {
-            self.cursor = Some(itry!(cursor_from_elf(self.elf_file, self.start_pos)));
+        while self.num_funcs == 0 {
+            let (blob, rest) = self.blobs.split_first()?;
+            self.blobs = rest;
+            self.cursor.set_position(blob.funcs_pos());
+            self.num_funcs = blob.num_funcs;
         }
-        let cursor = self.cursor.as_mut().unwrap();
+        let cursor = &mut self.cursor;
 
         // StkSizeRecord[NumFunctions] {
         //     uint64: Function Address
-        let addr = itry!(cursor.read_u64::<NativeEndian>());
+        let addr = itry!(cursor.read_u64::<NativeEndian>()).wrapping_add(self.addr_bias);
         //     uint64: Stack Size
         let stack_size = itry!(cursor.read_u64::<NativeEndian>());
         //     uint64: Record Count
@@ -318,67 +579,390 @@ impl<'a> Iterator for SMFuncIterator<'a> {
     }
 }
 
+/// An iterator over the entries of a constants table.
+pub struct SMConstIterator<'a> {
+    cursor: Cursor<&'a [u8]>,
+    num_consts: u32,
+}
+
+impl<'a> Iterator for SMConstIterator<'a> {
+    type Item = SMParserResult<u64>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.num_consts == 0 {
+            return None;
+        }
+
+        // Constants[NumConstants] {
+        //     uint64: LargeConstant
+        let c = itry!(self.cursor.read_u64::<NativeEndian>());
+        // } -- End of this constant entry.
+
+        self.num_consts -= 1;
+        Some(Ok(c))
+    }
+}
+
+/// A single stackmap blob: one header followed by its own function, constant and record tables.
+///
+/// LLVM emits one blob per module, so a stackmap section made by linking several (non-LTO)
+/// objects contains one blob per object, one after another.
+pub struct SMBlob<'a> {
+    data: &'a [u8],
+    header: &'a BlobHeader,
+    addr_bias: u64,
+    quirks: QuirkMode,
+}
+
+impl<'a> SMBlob<'a> {
+    /// Returns the offset of the blob from the start of the stackmap section.
+    pub fn offset(&self) -> u64 {
+        self.header.offset
+    }
+
+    /// Returns the number of stackmap record entries in the blob.
+    pub fn num_stackmaps(&self) -> u32 {
+        self.header.num_stackmaps
+    }
+
+    /// Returns the number of function entries in the blob.
+    pub fn num_funcs(&self) -> u32 {
+        self.header.num_funcs
+    }
+
+    /// Returns the number of entries in the blob's constants table.
+    pub fn num_consts(&self) -> u32 {
+        self.header.num_consts
+    }
+
+    /// Make an iterator over the stackmap record entries in the blob.
+    ///
+    /// If the iterator returns an error, the iterator becomes invalid and reuse will lead to
+    /// undefined behaviour.
+    pub fn iter_stackmaps(&self) -> SMRecIterator<'a> {
+        SMRecIterator::new(self.data, slice::from_ref(self.header), self.quirks)
+    }
+
+    /// Make an iterator over functions defined in the blob.
+    ///
+    /// If the iterator returns an error, the iterator becomes invalid and reuse will lead to
+    /// undefined behaviour.
+    pub fn iter_functions(&self) -> SMFuncIterator<'a> {
+        SMFuncIterator::new(self.data, slice::from_ref(self.header), self.addr_bias)
+    }
+
+    /// Make an iterator over the blob's constants table. `ConstIndex` locations in this blob's
+    /// records index into this table.
+    ///
+    /// If the iterator returns an error, the iterator becomes invalid and reuse will lead to
+    /// undefined behaviour.
+    pub fn iter_constants(&self) -> SMConstIterator<'a> {
+        SMConstIterator{
+            cursor: cursor_at(self.data, self.header.consts_pos()),
+            num_consts: self.header.num_consts,
+        }
+    }
+
+    /// Get entry `idx` of the blob's constants table, if there is one.
+    pub fn constant(&self, idx: u32) -> Option<u64> {
+        if idx >= self.header.num_consts {
+            return None;
+        }
+        let pos = self.header.consts_pos() + u64::from(idx) * u64::from(SIZE_CONSTANT_ENTRY);
+        cursor_at(self.data, pos).read_u64::<NativeEndian>().ok()
+    }
+
+    /// Evaluate `loc`, which must come from one of this blob's records, in the frame described by
+    /// `regs` and `mem`. See `eval_loc()`.
+    pub fn eval_loc<R, M>(&self, loc: &SMLoc, regs: &R, mem: &M) -> SMParserResult<LocValue>
+                          where R: RegisterContext + ?Sized, M: MemoryReader + ?Sized {
+        eval::eval_loc_with(loc, |idx| self.constant(idx), regs, mem)
+    }
+}
+
+/// An iterator over the blobs in the stackmap section.
+pub struct SMBlobIterator<'a> {
+    data: &'a [u8],
+    headers: slice::Iter<'a, BlobHeader>,
+    addr_bias: u64,
+    quirks: QuirkMode,
+}
+
+impl<'a> Iterator for SMBlobIterator<'a> {
+    type Item = SMBlob<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let header = self.headers.next()?;
+        Some(SMBlob{data: self.data, header, addr_bias: self.addr_bias, quirks: self.quirks})
+    }
+}
+
 /// Top-level struct through which the user interfaces with the stackmap section.
+///
+/// The stackmap section may contain several blobs (see `SMBlob`). Unless stated otherwise, the
+/// methods here treat the blobs as one, e.g. `iter_functions()` visits the functions of every
+/// blob in the order they appear in the section.
 pub struct StackMapParser {
-    elf_file: elf::File,
-    num_funcs: u32,
-    num_consts: u32,
-    num_stackmaps: u32,
+    data: Vec<u8>,              // The contents of the stackmap section.
+    image_base: u64,            // Preferred load address of a PE image, otherwise 0.
+    load_bias: u64,             // Difference between the runtime and link-time addresses.
+    relocs: Vec<SMReloc>,       // Static relocations against the section, sorted by offset.
+    blobs: Vec<BlobHeader>,
+    producers: Vec<String>,     // What made the binary, according to the binary.
+    quirks: QuirkMode,
+    arch: Option<Arch>,
 }
 
 impl StackMapParser {
+    /// Make a parser for the stackmap section of the ELF or COFF/PE binary at `path`.
     pub fn new(path: &Path) -> SMParserResult<Self> {
-        let elf_file = elf::File::open_path(path)?;
-        let num_funcs;
-        let num_consts;
-        let num_stackmaps;
-        {
-            let mut cursor = cursor_from_elf(&elf_file, 0)?;
-            Self::check_header(&mut cursor)?;
+        Self::from_bytes(&fs::read(path)?)
+    }
+
+    /// Make a parser for the stackmap section of the ELF or COFF/PE binary held in `bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> SMParserResult<Self> {
+        if coff::is_coff(bytes) {
+            let sec = coff::stackmap_section(bytes)?;
+            // PE images record their producers in CodeView debug info, which we don't read, so the
+            // quirk mode is always the default one.
+            let mut p = Self::from_section(sec.data, Vec::new(), sec.image_base)?;
+            p.arch = sec.arch;
+            p.quirks = producer::quirk_mode(&p.producers);
+            Ok(p)
+        } else {
+            let backend = ElfBackend::from_bytes(bytes)?;
+            if let Some((data, relocs)) = section_by_name(&backend, STACKMAP_SECTION_NAME)? {
+                Self::from_section(data, relocs, 0)?.with_backend(&backend)
+            } else {
+                // The section headers may have been stripped, but the stackmap data could still
+                // be found through the segments. The segment holds more than just the stackmaps
+                // though, so only its first blob is used. Without section headers, no producers
+                // can be found, so this gets the default quirk mode.
+                let mut data = stackmap_from_segments(backend.elf_file(), bytes)?;
+                let (_, end) = Self::read_blob(&data, 0)?;
+                data.truncate(end as usize);
+                Self::from_section(data, Vec::new(), 0)?.with_backend(&backend)
+            }
+        }
+    }
+
+    /// Make a parser for the stackmap section of a binary that has already been parsed by an
+    /// object file library, through that library's `ObjectBackend`.
+    ///
+    /// # Example
+    /// ```
+    /// use ykstackmaps::{ElfBackend, StackMapParser};
+    ///
+    /// let bytes = std::fs::read("/bin/ls").unwrap();
+    /// let backend = ElfBackend::from_bytes(&bytes).unwrap();
+    /// match StackMapParser::from_backend(&backend) {
+    ///     // It's unlikey /bin/ls contains stackmaps, but you get the idea.
+    ///     Err(e) => println!("error: {}", e),
+    ///     Ok(p) => println!("{} records", p.num_stackmaps()),
+    /// }
+    pub fn from_backend<B: ObjectBackend + ?Sized>(backend: &B) -> SMParserResult<Self> {
+        match section_by_name(backend, STACKMAP_SECTION_NAME)? {
+            Some((data, relocs)) => {
+                Self::from_section(data, relocs, backend.image_base())?.with_backend(backend)
+            }
+            None => Err(SMParserError::NoStackMapSection),
+        }
+    }
+
+    /// Read the architecture and producers of the binary, and pick the quirk mode to suit the
+    /// producers.
+    fn with_backend<B: ObjectBackend + ?Sized>(mut self, backend: &B) -> SMParserResult<Self> {
+        self.arch = backend.arch();
+        self.producers = producer::producers(backend)?;
+        self.quirks = producer::quirk_mode(&self.producers);
+        Ok(self)
+    }
+
+    fn from_section(data: Vec<u8>, relocs: Vec<SMReloc>, image_base: u64)
+                    -> SMParserResult<Self> {
+        let mut blobs = Vec::new();
+        let mut offset = 0;
+        loop {
+            let (header, end) = Self::read_blob(&data, offset)?;
+            blobs.push(header);
+            offset = end;
+            // The linker may pad the section out to its alignment after the last blob, and a
+            // blob can never begin with a zero byte (that would be version 0).
+            if data.get(offset as usize..).is_none_or(|rest| rest.iter().all(|&b| b == 0)) {
+                break;
+            }
+        }
+
+        Ok(Self{data, image_base, load_bias: 0, relocs, blobs, producers: Vec::new(),
+                 quirks: QuirkMode::UnsignedConstants, arch: None})
+    }
+
+    /// Read the header of the blob at `offset` in `data`, and find the (8-byte aligned) offset
+    /// just past the end of the blob.
+    fn read_blob(data: &[u8], offset: u64) -> SMParserResult<(BlobHeader, u64)> {
+        let header = BlobHeader::read(data, offset)?;
+        // Records are variable-sized, so the only way to find where the blob ends (and thus
+        // where the next begins) is to walk all of them.
+        let end = {
+            let mut recs = SMRecIterator::new(data, slice::from_ref(&header),
+                                              QuirkMode::UnsignedConstants);
+            if let Some(Err(e)) = recs.by_ref().find(|r| r.is_err()) {
+                return Err(e);
+            }
+            if header.num_stackmaps == 0 {
+                header.stackmaps_pos()
+            } else {
+                recs.cursor.position()
+            }
+        };
+        Ok((header, end + (8 - end % 8) % 8))
+    }
+
+    /// Returns the preferred load address of a PE image, or 0 for any other kind of binary.
+    ///
+    /// Function addresses in a PE image are reported relative to the image base, so that they can
+    /// be treated in the same way as those in a position independent ELF binary.
+    pub fn image_base(&self) -> u64 {
+        self.image_base
+    }
+
+    /// Returns the architecture of the binary, if it's one that we know the registers of. The
+    /// `dwarf_reg` of each location refers to a register of this architecture.
+    pub fn arch(&self) -> Option<Arch> {
+        self.arch
+    }
+
+    /// Returns the producers recorded in the binary: the `DW_AT_producer` of each DWARF compilation
+    /// unit, followed by the strings in the `.comment` section. Only ELF binaries have these.
+    pub fn producers(&self) -> &[String] {
+        &self.producers
+    }
+
+    /// Returns the quirk mode used to decode the stackmaps. By default, this is chosen to suit the
+    /// LLVM version named by `producers()`. When there are no producers to go on (as is always the
+    /// case for COFF/PE binaries and ELF binaries without section headers), the default is
+    /// `QuirkMode::UnsignedConstants`, which decodes constants in the same way as `llvm-readobj`.
+    pub fn quirk_mode(&self) -> QuirkMode {
+        self.quirks
+    }
+
+    /// Override the quirk mode used to decode the stackmaps.
+    pub fn set_quirk_mode(&mut self, quirks: QuirkMode) {
+        self.quirks = quirks;
+    }
+
+    /// Set the load bias: the difference between where the binary was loaded at runtime and the
+    /// addresses it was linked at. For a PIE executable or a shared object, this is the address
+    /// the binary was loaded at (e.g. `dlpi_addr` as reported by `dl_iterate_phdr(3)`); for a PE
+    /// image, it's the address of the loaded module.
+    ///
+    /// Once set, function addresses (and thus the addresses given by `SMFunc::record_addr()`) are
+    /// runtime addresses. The bias defaults to 0, giving link-time addresses.
+    pub fn set_load_bias(&mut self, load_bias: u64) {
+        self.load_bias = load_bias;
+    }
+
+    /// Returns the load bias (see `set_load_bias()`).
+    pub fn load_bias(&self) -> u64 {
+        self.load_bias
+    }
+
+    /// Returns the static relocations against the stackmap section, in the order of the fields
+    /// they apply to. Only ELF object files have these.
+    pub fn relocations(&self) -> &[SMReloc] {
+        &self.relocs
+    }
+
+    /// Returns the relocation (if any) that applies to the address of the `idx`th function, as
+    /// ordered by `iter_functions()`. In an object file, this identifies the function, since the
+    /// function addresses themselves aren't yet known.
+    pub fn func_relocation(&self, mut idx: usize) -> Option<&SMReloc> {
+        for blob in &self.blobs {
+            if idx < blob.num_funcs as usize {
+                let offset = blob.funcs_pos() + idx as u64 * u64::from(SIZE_STACK_SIZE_ENTRY);
+                return self.relocs.binary_search_by_key(&offset, |r| r.offset()).ok()
+                    .map(|i| &self.relocs[i]);
+            }
+            idx -= blob.num_funcs as usize;
+        }
+        None
+    }
 
-            // Read in table sizes.
-            // uint32: NumFunctions
-            num_funcs = cursor.read_u32::<NativeEndian>()?;
-            // uint32: NumConstants
-            num_consts = cursor.read_u32::<NativeEndian>()?;
-            // uint32: NumRecords
-            num_stackmaps = cursor.read_u32::<NativeEndian>()?;
+    /// Returns where the `idx`th function, `func`, is at link-time as a section index and an
+    /// offset (see `SMReloc::target()`). For a linked binary, the section index is 0 and the offset
+    /// is the link-time address.
+    pub (crate) fn func_target(&self, idx: usize, func: &SMFunc) -> (usize, u64) {
+        match self.func_relocation(idx) {
+            Some(r) => r.target(),
+            None => (0, func.addr().wrapping_sub(self.addr_bias())),
         }
+    }
 
-        Ok(Self{elf_file, num_funcs, num_consts, num_stackmaps})
+    /// The amount to add to each function address in the section.
+    fn addr_bias(&self) -> u64 {
+        self.load_bias.wrapping_sub(self.image_base)
     }
 
     /// Returns the number of stackmap record entries in the stackmap section.
     pub fn num_stackmaps(&self) -> u32 {
-        self.num_stackmaps
+        self.blobs.iter().map(|b| b.num_stackmaps).sum()
     }
 
     /// Returns the number of function entries in the stackmap section.
     pub fn num_funcs(&self) -> u32 {
-        self.num_funcs
+        self.blobs.iter().map(|b| b.num_funcs).sum()
     }
 
-    /// Check the stackmap header looks sane.
-    fn check_header(cursor: &mut Cursor<&Vec<u8>>) -> SMParserResult<()> {
-        // uint8: Stack Map Version
-        let version = cursor.read_u8()?;
-        if version != STACKMAP_VERSION {
-            let msg = format!("Expected stackmap format v{} but binary is v{}", STACKMAP_VERSION, version);
-            return Err(SMParserError::Other(msg));
-        }
-        // uint8: Reserved (expected to be 0)
-        let b2 = cursor.read_u8()?;
-        if b2 != 0 {
-            let msg = format!("Expected 0 in stackmap section byte 2, got {}", b2);
-            return Err(SMParserError::Other(msg));
-        }
-        // uint16: Reserved (expected to be 0)
-        let b2_3 = cursor.read_u16::<NativeEndian>()?;
-        if b2_3 != 0 {
-            let msg = format!("Expected 0 in stackmap section bytes 2 and 3, got {}", b2_3);
-            return Err(SMParserError::Other(msg));
+    /// Returns the number of blobs in the stackmap section.
+    pub fn num_blobs(&self) -> usize {
+        self.blobs.len()
+    }
+
+    /// Make an iterator over the blobs in the stackmap section.
+    ///
+    /// # Example
+    /// ```
+    /// use std::path::Path;
+    /// use ykstackmaps::StackMapParser;
+    ///
+    /// match StackMapParser::new(&Path::new("/bin/ls")) {
+    ///     // It's unlikey /bin/ls contains stackmaps, but you get the idea.
+    ///     Err(e) => println!("error: {}", e),
+    ///     Ok(p) =>  {
+    ///         for blob in p.iter_blobs() {
+    ///             println!("blob at offset {} has {} records", blob.offset(), blob.num_stackmaps());
+    ///         }
+    ///     }
+    /// }
+    pub fn iter_blobs(&self) -> SMBlobIterator<'_> {
+        SMBlobIterator{data: &self.data, headers: self.blobs.iter(), addr_bias: self.addr_bias(),
+                       quirks: self.quirks}
+    }
+
+    /// Find the record whose instruction is at address `pc` (i.e. `SMFunc::record_addr()`), along
+    /// with the function and blob that it belongs to. For a call, such as a statepoint, this is
+    /// the return address. If several records are at `pc`, the first is returned.
+    ///
+    /// In an object file, every function's address is relative to its own section, so the
+    /// answer may not be the one you want.
+    pub fn find_record(&self, pc: u64) -> SMParserResult<Option<(SMBlob<'_>, SMFunc, SMRec)>> {
+        for blob in self.iter_blobs() {
+            let mut recs = blob.iter_stackmaps();
+            for func in blob.iter_functions() {
+                let func = func?;
+                for _ in 0..func.record_count() {
+                    let rec = match recs.next() {
+                        Some(rec) => rec?,
+                        None => return Err(SMParserError::Other(String::from(
+                            "Functions claim more records than the stackmap section has"))),
+                    };
+                    if func.record_addr(&rec) == pc {
+                        return Ok(Some((blob, func, rec)));
+                    }
+                }
+            }
         }
-        Ok(())
+        Ok(None)
     }
 
     /// Make an iterator over the stackmap record entries in the stackmap section.
@@ -386,6 +970,9 @@ impl StackMapParser {
     /// If the iterator returns an error, the iterator becomes invalid and reuse will lead to
     /// undefined behaviour.
     ///
+    /// The records of all blobs are merged, so their `ConstIndex` locations can't be told apart.
+    /// Use `SMBlob::iter_stackmaps()` or a `RecordIndex` to evaluate them (see `eval_loc()`).
+    ///
     /// # Example
     /// ```
     /// use std::path::Path;
@@ -406,16 +993,8 @@ impl StackMapParser {
     ///         }
     ///     }
     /// }
-    pub fn iter_stackmaps(&self) -> SMRecIterator {
-        let start_pos = OFFS_STACK_SIZE_ENTRIES + u64::from(self.num_funcs) *
-            u64::from(SIZE_STACK_SIZE_ENTRY) + u64::from(self.num_consts) *
-            u64::from(SIZE_CONSTANT_ENTRY);
-        SMRecIterator{
-            elf_file: &self.elf_file,
-            cursor: None,
-            start_pos,
-            num_stackmaps: self.num_stackmaps
-        }
+    pub fn iter_stackmaps(&self) -> SMRecIterator<'_> {
+        SMRecIterator::new(&self.data, &self.blobs, self.quirks)
     }
 
     /// Make an iterator over functions defined in the stackmap section.
@@ -443,30 +1022,46 @@ impl StackMapParser {
     ///         }
     ///     }
     /// }
-    pub fn iter_functions(&self) -> SMFuncIterator {
-        SMFuncIterator{
-            elf_file: &self.elf_file,
-            cursor: None,
-            start_pos: OFFS_STACK_SIZE_ENTRIES,
-            num_funcs: self.num_funcs,
-        }
+    pub fn iter_functions(&self) -> SMFuncIterator<'_> {
+        SMFuncIterator::new(&self.data, &self.blobs, self.addr_bias())
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use byteorder::{ByteOrder, NativeEndian};
+    use elf;
     use std::env;
+    use std::fs;
+    use std::io;
     use std::iter::Iterator;
     use std::path::{Path, PathBuf};
     use std::process::Command;
-    use super::{SMFunc, SMRec, SMLoc, StackMapParser, LocKind, LocOffset};
+    use super::{SMFunc, SMRec, SMLoc, SMParserError, StackMapArchive, StackMapParser, LocKind,
+                LocOffset, QuirkMode, FaultKind, FaultMapParser, BBAddrMapParser, StackSizesParser,
+                StackSizeMismatch, LocValue, MemoryReader, eval_loc, Arch, SMLiveOut,
+                DeoptFrame, Statepoint, Patchpoint, gc_roots, RootSlot, relocate_roots,
+                MemoryWriter, RecordIndex, Frame, StackWalker, RegisterContext, CfiUnwinder,
+                RegisterSet, DeoptValue, materialize, FrameBuilder};
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    use super::{LazyDeoptHandler, TrapAction, TrapHandler, ucontext_registers, UCONTEXT_NUM_REGS};
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    use libc;
+    use bbaddrmap::read_section;
+    use compress::decompress_section;
+    use producer::quirk_mode;
+    #[cfg(feature = "object")]
+    use object;
+    #[cfg(feature = "object")]
+    use super::{ElfBackend, ObjectBackend};
+    use std::collections::HashMap;
 
     #[cfg(target_os="linux")]
     const MAKE: &str = "make";
     const LLVM_READOBJ_PATH: &str = "LLVM_READOBJ_PATH";
 
     // Invokes GNU make to build a test input.
-    fn build_test_inputs(path: &PathBuf) {
+    fn build_test_inputs(path: &Path) {
         // Change into the `test_inputs` source directory.
         let md = env::var("CARGO_MANIFEST_DIR").unwrap();
         let mut dir = PathBuf::from(md);
@@ -498,11 +1093,21 @@ mod tests {
         pb
     }
 
-    // Construct an SMFunc struct from the parsing the output of a Function line
-    // in llvm-readobj. Example format:
-    //    Function address: 0, stack size: 8, callsite record count: 1
+    // Get the absolute path to a test input that is checked in to the repository.
+    fn checked_in_path(dir: &str, file: &str) -> PathBuf {
+        let md = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let mut pb = PathBuf::from(md);
+        pb.push("test_inputs");
+        pb.push(dir);
+        pb.push(file);
+        pb
+    }
+
+    // Construct an SMFunc struct from the parsing the output of a Function line
+    // in llvm-readobj. Example format:
+    //    Function address: 0, stack size: 8, callsite record count: 1
     fn parse_fn(line: &str) -> SMFunc {
-        let elems: Vec<&str> = line.split(|c| c == ',' || c == ':').collect();
+        let elems: Vec<&str> = line.split([',', ':']).collect();
         // Gives ["Function address", "0", "stack size", "8",  .... ]
 
         let addr = elems[1].trim().parse::<u64>().unwrap();
@@ -513,11 +1118,11 @@ mod tests {
     }
 
     fn parse_loc(line: &str) -> SMLoc {
-        let elems = line.split(|c| c == ',' || c == ':').collect::<Vec<_>>();
+        let elems = line.split([',', ':']).collect::<Vec<_>>();
         // Gives ["#N " LocKind +Data", "0", "size", " 8"]
 
         let size = elems[3].trim().parse::<u16>().unwrap();
-        let mut loc = elems[1].trim().split_whitespace();
+        let mut loc = elems[1].split_whitespace();
         let kind = loc.next().unwrap();
         let rest = loc.collect::<Vec<_>>();
 
@@ -541,7 +1146,7 @@ mod tests {
                 let kind = LocKind::Indirect;
                 let dwarf_reg = rest[0].trim_start_matches("[R#").parse::<u16>().unwrap();
                 let offset = {
-                    let n = rest[2].trim_end_matches("]").parse::<i32>().unwrap();
+                    let n = rest[2].trim_end_matches(']').parse::<i32>().unwrap();
                     LocOffset::I32(n)
                 };
                 SMLoc { kind, size, dwarf_reg, offset }
@@ -559,7 +1164,7 @@ mod tests {
                 let kind = LocKind::ConstIndex;
                 let dwarf_reg = 0;
                 let offset = {
-                    let c = rest[0].trim_start_matches("#").parse::<i32>().unwrap();
+                    let c = rest[0].trim_start_matches('#').parse::<i32>().unwrap();
                     LocOffset::I32(c)
                 };
                 SMLoc { kind, size, dwarf_reg, offset }
@@ -576,7 +1181,7 @@ mod tests {
     {
         let line = lines.next().unwrap();
         // Record ID: n, instruction offset: m
-        let elems: Vec<&str> = line.split(|c| c == ',' || c == ':').collect();
+        let elems: Vec<&str> = line.split([',', ':']).collect();
         // e.g ["Record ID:", " 1", " instruction offset", " 4"]
 
         let id = elems[1].trim().parse::<u64>().unwrap();
@@ -593,12 +1198,23 @@ mod tests {
         // Individual location line, e.g:
         //  "#1: Register #R0, size: 8"
         // This cast to usize is safe, as num_locs is a u16
-        let locs = lines.take(num_locs as usize).map(|x| parse_loc(x)).collect();
+        let locs = lines.take(num_locs as usize).map(parse_loc).collect();
 
-        // #TODO Live outs line
-        lines.next();
+        // Live outs line, e.g:
+        //  "2 live-outs: [ R#0 (8-bytes) R#7 (8-bytes) ]"
+        let liveouts = {
+            let line = lines.next().unwrap();
+            let elems = line.split(['[', ']']).collect::<Vec<_>>();
+            let regs = elems[1].split_whitespace().collect::<Vec<_>>();
+            regs.chunks(2).map(|r| {
+                let dwarf_reg = r[0].trim_start_matches("R#").parse::<u16>().unwrap();
+                let size = r[1].trim_start_matches('(').trim_end_matches("-bytes)")
+                               .parse::<u8>().unwrap();
+                SMLiveOut { dwarf_reg, size }
+            }).collect()
+        };
 
-        SMRec { id, offset, num_locs, locs }
+        SMRec { id, offset, num_locs, locs, liveouts }
     }
 
     // Parse the output of llvm-readelf to get expected outcomes.
@@ -606,7 +1222,7 @@ mod tests {
         let readelf = env::var(LLVM_READOBJ_PATH)
             .expect("Testing requires the LLVM_READOBJ_PATH environment variable to be set");         
         let out = Command::new(readelf)
-                          .arg("-stackmap")
+                          .arg("--stackmap")
                           .arg(path.to_str().unwrap())
                           .output()
                           .expect("failed to run llvm-readelf command");
@@ -620,7 +1236,7 @@ mod tests {
         while let Some(line) = lines.next() {
             if line.starts_with("Num Functions:") {
                 let fns = {
-                    let n = line.split(':').last().unwrap().trim();
+                    let n = line.split(':').next_back().unwrap().trim();
                     n.parse::<u32>().unwrap()
                 };
                 for _ in 0..fns {
@@ -631,7 +1247,7 @@ mod tests {
 
             if line.starts_with("Num Records:") {
                 let rcs = {
-                    let n = line.split(':').last().unwrap().trim();
+                    let n = line.split(':').next_back().unwrap().trim();
                     n.parse::<u32>().unwrap()
                 };
                 for _ in 0..rcs {
@@ -644,8 +1260,26 @@ mod tests {
 
     fn check_expected_stackmaps(path: PathBuf) {
         build_test_inputs(&path);
-        let (expect_funcs, expect_stkmaps) = get_expected(&path);
-        let p = StackMapParser::new(&path).unwrap();
+        check_against_readobj(&path);
+    }
+
+    /// llvm-readobj always shows the offset of a constant location as unsigned. Convert the
+    /// records it gives to how the parser reads them in `quirks` mode.
+    fn with_quirks(mut recs: Vec<SMRec>, quirks: QuirkMode) -> Vec<SMRec> {
+        if quirks == QuirkMode::SignedConstants {
+            for loc in recs.iter_mut().flat_map(|r| r.locs.iter_mut()) {
+                if let LocOffset::U32(c) = loc.offset {
+                    loc.offset = LocOffset::I32(c as i32);
+                }
+            }
+        }
+        recs
+    }
+
+    fn check_against_readobj(path: &Path) {
+        let p = StackMapParser::new(path).unwrap();
+        let (expect_funcs, expect_stkmaps) = get_expected(path);
+        let expect_stkmaps = with_quirks(expect_stkmaps, p.quirk_mode());
 
         assert_eq!(expect_funcs.len(), p.num_funcs() as usize);
         assert_eq!(expect_stkmaps.len(), p.num_stackmaps() as usize);
@@ -677,4 +1311,1508 @@ mod tests {
     fn test_fannkuch_redux() {
         check_expected_stackmaps(test_bin_path("fannkuch_redux", "fannkuch_redux"));
     }
+
+    #[test]
+    fn test_multiple_blobs() {
+        let path = test_bin_path("multi_blob", "multi_blob");
+        build_test_inputs(&path);
+        let p = StackMapParser::new(&path).unwrap();
+
+        // llvm-readobj only looks at the first blob, so each blob is checked against the object
+        // that it came from.
+        let parts = [test_bin_path("hello_world", "hello_world1"),
+                     test_bin_path("large_v3_stackmap", "stackmap")];
+        assert_eq!(p.num_blobs(), parts.len());
+        let mut expect_all_funcs = Vec::new();
+        let mut expect_all_stkmaps = Vec::new();
+        for (blob, part) in p.iter_blobs().zip(parts.iter()) {
+            let (expect_funcs, expect_stkmaps) = get_expected(part);
+            let expect_stkmaps = with_quirks(expect_stkmaps, p.quirk_mode());
+            assert_eq!(expect_funcs.len(), blob.num_funcs() as usize);
+            assert_eq!(expect_stkmaps.len(), blob.num_stackmaps() as usize);
+            for (got, expect) in blob.iter_functions().zip(&expect_funcs) {
+                assert_eq!(&got.unwrap(), expect);
+            }
+            for (got, expect) in blob.iter_stackmaps().zip(&expect_stkmaps) {
+                assert_eq!(&got.unwrap(), expect);
+            }
+
+            let part_p = StackMapParser::new(part).unwrap();
+            let part_blob = part_p.iter_blobs().next().unwrap();
+            assert_eq!(blob.num_consts(), part_blob.num_consts());
+            for (got, expect) in blob.iter_constants().zip(part_blob.iter_constants()) {
+                assert_eq!(got.unwrap(), expect.unwrap());
+            }
+
+            expect_all_funcs.extend(expect_funcs);
+            expect_all_stkmaps.extend(expect_stkmaps);
+        }
+
+        // The section-wide views see the blobs as one.
+        assert_eq!(expect_all_funcs.len(), p.num_funcs() as usize);
+        assert_eq!(expect_all_stkmaps.len(), p.num_stackmaps() as usize);
+        for (got, expect) in p.iter_functions().zip(expect_all_funcs) {
+            assert_eq!(got.unwrap(), expect);
+        }
+        for (got, expect) in p.iter_stackmaps().zip(expect_all_stkmaps) {
+            assert_eq!(got.unwrap(), expect);
+        }
+    }
+
+    #[test]
+    fn test_trailing_padding() {
+        let path = test_bin_path("hello_world", "hello_world1");
+        build_test_inputs(&path);
+        let elf_file = elf::File::open_path(&path).unwrap();
+        let mut data = elf_file.get_section(".llvm_stackmaps").unwrap().data.clone();
+        let (expect_funcs, expect_stkmaps) = get_expected(&path);
+
+        // Zero padding after the last blob is not mistaken for another blob.
+        data.extend([0; 16]);
+        let p = StackMapParser::from_section(data, Vec::new(), 0).unwrap();
+        assert_eq!(p.num_blobs(), 1);
+        assert_eq!(expect_funcs.len(), p.num_funcs() as usize);
+        assert_eq!(expect_stkmaps.len(), p.num_stackmaps() as usize);
+    }
+
+    #[test]
+    fn test_bad_location_reserved() {
+        // The first record of this binary has a location.
+        let path = test_bin_path("unwind", "unwind");
+        build_test_inputs(&path);
+        let elf_file = elf::File::open_path(&path).unwrap();
+        let data = elf_file.get_section(".llvm_stackmaps").unwrap().data.clone();
+        let p = StackMapParser::from_section(data.clone(), Vec::new(), 0).unwrap();
+        let num_consts = p.iter_blobs().next().unwrap().iter_constants().count();
+        // The header, then the function entries, the constants and the first record's header.
+        let loc_off = 16 + 24 * p.num_funcs() as usize + 8 * num_consts + 16;
+
+        // A non-zero reserved byte in a location is an error rather than a panic.
+        for reserved_off in [1, 6] {
+            let mut bad = data.clone();
+            bad[loc_off + reserved_off] = 1;
+            assert!(StackMapParser::from_section(bad, Vec::new(), 0).is_err());
+        }
+    }
+
+    #[test]
+    fn test_pie_load_bias() {
+        const LOAD_BIAS: u64 = 0x5555_5555_0000;
+
+        let path = test_bin_path("pie", "hello_world");
+        build_test_inputs(&path);
+        let elf_file = elf::File::open_path(&path).unwrap();
+        let symtab = elf_file.get_section(".symtab").unwrap();
+        let main_addr = elf_file.get_symbols(symtab).unwrap().iter()
+            .find(|s| s.name == "main").unwrap().value;
+
+        // The linker also stores the relocated function address in the stackmap section. Make a
+        // copy of the binary without it, to check that we apply the dynamic relocation.
+        let sec = elf_file.get_section(".llvm_stackmaps").unwrap();
+        let addr_off = sec.shdr.offset as usize + 16;
+        let mut bytes = fs::read(&path).unwrap();
+        for b in &mut bytes[addr_off..addr_off + 8] {
+            *b = 0;
+        }
+        let zeroed_path = path.with_file_name("hello_world_zeroed");
+        fs::write(&zeroed_path, bytes).unwrap();
+
+        for path in &[path, zeroed_path] {
+            let mut p = StackMapParser::new(path).unwrap();
+            assert_eq!(p.iter_functions().next().unwrap().unwrap().addr(), main_addr);
+
+            p.set_load_bias(LOAD_BIAS);
+            let func = p.iter_functions().next().unwrap().unwrap();
+            let rec = p.iter_stackmaps().next().unwrap().unwrap();
+            assert_eq!(func.addr(), main_addr + LOAD_BIAS);
+            assert_eq!(func.record_addr(&rec), main_addr + LOAD_BIAS + u64::from(rec.offset()));
+        }
+    }
+
+    fn check_archive(path: PathBuf) {
+        build_test_inputs(&path);
+        let ar = StackMapArchive::new(&path).unwrap();
+
+        // The C source file in the archive is skipped.
+        let names = ar.iter().map(|(n, _)| n.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["hello_world1", "a_member_with_a_long_name.o"]);
+
+        let (expect_funcs, expect_stkmaps) = get_expected(&test_bin_path("hello_world", "hello_world1"));
+        let p = ar.get("hello_world1").unwrap();
+        let expect_stkmaps = with_quirks(expect_stkmaps, p.quirk_mode());
+        for (got, expect) in p.iter_functions().zip(expect_funcs) {
+            assert_eq!(got.unwrap(), expect);
+        }
+        for (got, expect) in p.iter_stackmaps().zip(expect_stkmaps) {
+            assert_eq!(got.unwrap(), expect);
+        }
+        assert_eq!(p.relocations().len(), 1);
+        let reloc = p.func_relocation(0).unwrap();
+        assert_eq!((reloc.symbol(), reloc.addend()), ("main", 0));
+        assert!(p.func_relocation(1).is_none());
+
+        // Every function in the other member has a relocation naming it.
+        let p = ar.get("a_member_with_a_long_name.o").unwrap();
+        assert_eq!(p.relocations().len(), p.num_funcs() as usize);
+        assert_eq!(p.func_relocation(0).unwrap().symbol(), "constantargs");
+    }
+
+    #[test]
+    fn test_gnu_archive() {
+        check_archive(test_bin_path("archive", "libgnu.a"));
+    }
+
+    #[test]
+    fn test_bsd_archive() {
+        check_archive(test_bin_path("archive", "libbsd.a"));
+    }
+
+    fn check_compressed(path: PathBuf) {
+        build_test_inputs(&path);
+        let (expect_funcs, expect_stkmaps) = get_expected(&test_bin_path("fannkuch_redux", "fannkuch_redux"));
+        let p = StackMapParser::new(&path).unwrap();
+        let expect_stkmaps = with_quirks(expect_stkmaps, p.quirk_mode());
+
+        assert_eq!(expect_funcs.len(), p.num_funcs() as usize);
+        assert_eq!(expect_stkmaps.len(), p.num_stackmaps() as usize);
+        for (got, expect) in p.iter_functions().zip(expect_funcs) {
+            assert_eq!(got.unwrap(), expect);
+        }
+        for (got, expect) in p.iter_stackmaps().zip(expect_stkmaps) {
+            assert_eq!(got.unwrap(), expect);
+        }
+    }
+
+    #[test]
+    fn test_zlib_compressed() {
+        check_compressed(test_bin_path("compressed", "zlib"));
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_zstd_compressed() {
+        check_compressed(test_bin_path("compressed", "zstd"));
+    }
+
+    #[test]
+    fn test_compressed_too_long() {
+        use flate2::write::ZlibEncoder;
+        use std::io::Write;
+
+        // A zlib-compressed section whose header claims fewer bytes than the data really holds.
+        let mut enc = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        enc.write_all(&[0; 4096]).unwrap();
+        let mut data = Vec::new();
+        data.extend(1u32.to_ne_bytes());
+        data.extend(0u32.to_ne_bytes());
+        data.extend(16u64.to_ne_bytes());
+        data.extend(1u64.to_ne_bytes());
+        data.extend(enc.finish().unwrap());
+        let shdr = elf::types::SectionHeader{
+            name: String::from(".llvm_stackmaps"), shtype: elf::types::SHT_PROGBITS,
+            flags: elf::types::SectionFlag(0x800), addr: 0, offset: 0, size: data.len() as u64,
+            link: 0, info: 0, addralign: 1, entsize: 0};
+        match decompress_section(&shdr, data.clone()) {
+            Err(SMParserError::Other(msg)) => {
+                assert_eq!(msg, "Expected 16 bytes from decompressing section .llvm_stackmaps \
+                                 but got 17");
+            },
+            r => panic!("unexpected result {:?}", r.map(|d| d.len())),
+        }
+
+        // A header can't make us decompress without limit by claiming a huge size.
+        data[8..16].copy_from_slice(&u64::MAX.to_ne_bytes());
+        match decompress_section(&shdr, data) {
+            Err(SMParserError::Other(msg)) => assert!(msg.contains("more than the limit")),
+            r => panic!("unexpected result {:?}", r.map(|d| d.len())),
+        }
+    }
+
+    /// Make a copy of the ELF binary at `path` with its section header table removed, as `sstrip`
+    /// would.
+    fn strip_section_headers(path: &Path) -> PathBuf {
+        let mut bytes = fs::read(path).unwrap();
+        // Zero e_shoff, e_shnum and e_shstrndx.
+        for b in &mut bytes[0x28..0x30] {
+            *b = 0;
+        }
+        for b in &mut bytes[0x3c..0x40] {
+            *b = 0;
+        }
+        let stripped_path = path.with_file_name(format!(
+            "{}_sstripped", path.file_name().unwrap().to_str().unwrap()));
+        fs::write(&stripped_path, bytes).unwrap();
+        stripped_path
+    }
+
+    #[test]
+    fn test_stripped_section_headers() {
+        let path = test_bin_path("stripped", "hello_world");
+        build_test_inputs(&path);
+        let stripped_path = strip_section_headers(&path);
+        assert!(elf::File::open_path(&stripped_path).unwrap().sections.is_empty());
+
+        let expect = StackMapParser::new(&path).unwrap();
+        let got = StackMapParser::new(&stripped_path).unwrap();
+        assert_eq!(got.num_blobs(), 1);
+        // The producers are lost with the section headers.
+        assert!(got.producers().is_empty());
+        assert_eq!(got.quirk_mode(), QuirkMode::UnsignedConstants);
+        assert_eq!(got.num_funcs(), expect.num_funcs());
+        assert_eq!(got.num_stackmaps(), expect.num_stackmaps());
+        for (got, expect) in got.iter_functions().zip(expect.iter_functions()) {
+            assert_eq!(got.unwrap(), expect.unwrap());
+        }
+        for (got, expect) in got.iter_stackmaps().zip(expect.iter_stackmaps()) {
+            assert_eq!(got.unwrap(), expect.unwrap());
+        }
+
+        // A loadable segment whose end overflows is an error.
+        let mut bytes = fs::read(&stripped_path).unwrap();
+        let phoff = NativeEndian::read_u64(&bytes[0x20..]) as usize;
+        let phentsize = usize::from(NativeEndian::read_u16(&bytes[0x36..]));
+        let phnum = usize::from(NativeEndian::read_u16(&bytes[0x38..]));
+        for ph in (0..phnum).map(|i| phoff + i * phentsize) {
+            // Give every PT_LOAD segment not at address 0 the largest possible p_filesz.
+            if NativeEndian::read_u32(&bytes[ph..]) == 1
+               && NativeEndian::read_u64(&bytes[ph + 0x10..]) != 0 {
+                NativeEndian::write_u64(&mut bytes[ph + 0x20..], u64::MAX);
+            }
+        }
+        match StackMapParser::from_bytes(&bytes) {
+            Err(SMParserError::Other(msg)) => assert_eq!(msg, "ELF file has an out of range address"),
+            r => panic!("unexpected result {:?}", r.map(|p| p.num_funcs())),
+        }
+
+        // Without the exported symbol, there's no way to find the stackmaps.
+        let path = test_bin_path("pie", "hello_world");
+        build_test_inputs(&path);
+        match StackMapParser::new(&strip_section_headers(&path)) {
+            Err(SMParserError::NoStackMapSection) => (),
+            _ => panic!("expected NoStackMapSection"),
+        }
+    }
+
+    #[test]
+    fn test_coff_object() {
+        let path = checked_in_path("coff", "stackmap.obj");
+        check_against_readobj(&path);
+        let p = StackMapParser::new(&path).unwrap();
+        assert_eq!(p.image_base(), 0);
+        assert_eq!(p.quirk_mode(), QuirkMode::UnsignedConstants);
+    }
+
+    #[test]
+    fn test_pe_image() {
+        // llvm-readobj can't find the (truncated) stackmap section in the image, so we compare
+        // against the object that the image was linked from.
+        let (_, expect_stkmaps) = get_expected(&checked_in_path("coff", "stackmap.obj"));
+        let p = StackMapParser::new(&checked_in_path("coff", "stackmap.exe")).unwrap();
+
+        assert_eq!(p.image_base(), 0x140000000);
+        let addrs = p.iter_functions().map(|f| f.unwrap().addr()).collect::<Vec<_>>();
+        assert_eq!(addrs, vec![0x1000, 0x1010]);
+        assert_eq!(expect_stkmaps.len(), p.num_stackmaps() as usize);
+        for (got, expect) in p.iter_stackmaps().zip(expect_stkmaps) {
+            assert_eq!(got.unwrap(), expect);
+        }
+    }
+
+    #[test]
+    fn test_faultmaps() {
+        let kinds = [FaultKind::FaultingLoad, FaultKind::FaultingStore];
+
+        // In the object file, the function addresses are only known through the relocations.
+        let path = test_bin_path("faultmaps", "faultmaps");
+        build_test_inputs(&path);
+        let p = FaultMapParser::new(&path).unwrap();
+        assert_eq!(p.num_funcs(), 2);
+        let relocs = p.relocations().iter().map(|r| (r.offset(), r.symbol())).collect::<Vec<_>>();
+        assert_eq!(relocs, vec![(8, "load"), (36, "store")]);
+
+        let path = test_bin_path("faultmaps", "faultmaps_pie");
+        build_test_inputs(&path);
+        let elf_file = elf::File::open_path(&path).unwrap();
+        let symtab = elf_file.get_section(".symtab").unwrap();
+        let syms = elf_file.get_symbols(symtab).unwrap();
+        let mut p = FaultMapParser::new(&path).unwrap();
+        p.set_load_bias(0x1000_0000);
+        assert_eq!(p.num_funcs(), 2);
+        for ((func, name), kind) in p.iter_functions().zip(&["load", "store"]).zip(&kinds) {
+            let func = func.unwrap();
+            let sym = syms.iter().find(|s| s.name == *name).unwrap();
+            assert_eq!(func.addr(), sym.value + 0x1000_0000);
+            assert_eq!(func.records().len(), 1);
+            let rec = &func.records()[0];
+            assert_eq!(rec.kind(), *kind);
+            // The handler is the null case, which comes after the faulting access.
+            assert!(rec.faulting_pc_offset() < rec.handler_pc_offset());
+            assert!(func.handler_pc(rec) < func.addr() + sym.size);
+        }
+
+        // Zero padding between and after the faultmaps of each object is skipped.
+        let faultmap = elf_file.get_section(".llvm_faultmaps").unwrap().data.clone();
+        let mut data = faultmap.clone();
+        data.extend([0; 4]);
+        data.extend(&faultmap);
+        data.extend([0; 8]);
+        let padded = FaultMapParser::from_section(data, Vec::new()).unwrap();
+        assert_eq!(padded.num_funcs(), 4);
+        let funcs = padded.iter_functions().map(Result::unwrap).collect::<Vec<_>>();
+        assert_eq!(funcs.len(), 4);
+        assert_eq!(funcs[..2], funcs[2..]);
+
+        // A binary without implicit null checks has no faultmap section.
+        match FaultMapParser::new(&test_bin_path("hello_world", "hello_world1")) {
+            Err(SMParserError::NoFaultMapSection) => (),
+            _ => panic!("expected NoFaultMapSection"),
+        }
+    }
+
+    /// Get the function addresses and the (offset, size) of their basic blocks from the basic block
+    /// address map of the binary at `path`, as reported by llvm-readobj.
+    fn get_expected_bb_addr_map(path: &Path) -> Vec<(u64, Vec<(u64, u64)>)> {
+        let readelf = env::var(LLVM_READOBJ_PATH)
+            .expect("Testing requires the LLVM_READOBJ_PATH environment variable to be set");
+        let out = Command::new(readelf)
+                          .arg("--bb-addr-map")
+                          .arg(path.to_str().unwrap())
+                          .output()
+                          .expect("failed to run llvm-readelf command");
+        assert!(out.status.success());
+        let stdout = String::from_utf8(out.stdout).unwrap();
+
+        let hex = |line: &str| u64::from_str_radix(line.split("0x").last().unwrap(), 16).unwrap();
+        let mut funcs: Vec<(u64, Vec<(u64, u64)>)> = Vec::new();
+        let mut lines = stdout.lines().map(str::trim);
+        while let Some(line) = lines.next() {
+            if line.starts_with("At:") {
+                funcs.push((hex(line), Vec::new()));
+            } else if line.starts_with("Offset:") {
+                let size = hex(lines.next().unwrap());
+                funcs.last_mut().unwrap().1.push((hex(line), size));
+            }
+        }
+        funcs
+    }
+
+    #[test]
+    fn test_bb_addr_map() {
+        let path = test_bin_path("bb_addr_map", "fannkuch_redux");
+        build_test_inputs(&path);
+        let bbs = BBAddrMapParser::new(&path).unwrap();
+        let expect = get_expected_bb_addr_map(&path);
+        assert_eq!(bbs.num_funcs(), expect.len());
+        for (got, (addr, blocks)) in bbs.iter_functions().zip(expect) {
+            assert_eq!(got.addr(), addr);
+            let got_blocks = got.blocks().map(|b| (b.offset(), b.size())).collect::<Vec<_>>();
+            assert_eq!(got_blocks, blocks);
+            assert!(got.blocks().last().unwrap().has_return());
+        }
+
+        // Every record lies within the block it's reported to be in.
+        let mut sm = StackMapParser::new(&path).unwrap();
+        sm.set_load_bias(0x5555_0000);
+        let located = bbs.locate_records(&sm).unwrap();
+        assert_eq!(located.len(), sm.num_stackmaps() as usize);
+        let mut recs = sm.iter_stackmaps();
+        let mut ids = Vec::new();
+        for func in sm.iter_functions() {
+            let func = func.unwrap();
+            for _ in 0..func.record_count() {
+                let rec = recs.next().unwrap().unwrap();
+                let loc = located[ids.len()].as_ref().unwrap();
+                let addr = func.record_addr(&rec) - sm.load_bias();
+                assert_eq!(loc.func.addr(), func.addr() - sm.load_bias());
+                let start = loc.func.addr() + loc.block.offset();
+                assert!(addr >= start && addr <= start + loc.block.size());
+                ids.push((loc.func.addr(), loc.block.id()));
+            }
+        }
+
+        // The object file gives the same blocks, found through the relocations instead.
+        let path = test_bin_path("bb_addr_map", "fannkuch_redux.o");
+        build_test_inputs(&path);
+        let bbs = BBAddrMapParser::new(&path).unwrap();
+        let sm = StackMapParser::new(&path).unwrap();
+        let got_ids = bbs.locate_records(&sm).unwrap().iter()
+            .map(|l| l.as_ref().unwrap().block.id()).collect::<Vec<_>>();
+        assert_eq!(got_ids, ids.iter().map(|&(_, id)| id).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_bb_addr_map_v0() {
+        // Version 0 has a header, but gives absolute block offsets like the unversioned format.
+        let data = [
+            0, 0,                                   // Version, Features
+            0x00, 0x10, 0, 0, 0, 0, 0, 0,           // BaseAddress
+            2,                                      // NumBlocks
+            0, 4, 8,                                // Offset, Size, Metadata
+            6, 2, 1,                                // Offset, Size, Metadata
+        ];
+        let mut funcs = Vec::new();
+        read_section(&data, false, &HashMap::new(), &mut funcs).unwrap();
+        assert_eq!(funcs.len(), 1);
+        assert_eq!(funcs[0].addr(), 0x1000);
+        let blocks = funcs[0].blocks().map(|b| (b.id(), b.offset(), b.size())).collect::<Vec<_>>();
+        assert_eq!(blocks, vec![(0, 0, 4), (1, 6, 2)]);
+
+        // Version 0 has no features.
+        assert!(read_section(&[0, 1], false, &HashMap::new(), &mut funcs).is_err());
+    }
+
+    #[test]
+    fn test_bb_addr_map_v2() {
+        // A version 2 map of one function split into two ranges, with all of the profile data.
+        let data = [
+            2, 0xf,                                 // Version, Features
+            2,                                      // NumBBRanges
+            0x00, 0x10, 0, 0, 0, 0, 0, 0,           // BaseAddress
+            2,                                      // NumBlocks
+            0, 0, 4, 8,                             // ID, Offset, Size, Metadata
+            3, 2, 0x80, 1, 1,                       // ID, Offset, Size (128), Metadata
+            0x00, 0x20, 0, 0, 0, 0, 0, 0,           // BaseAddress
+            1,                                      // NumBlocks
+            1, 0, 6, 1,                             // ID, Offset, Size, Metadata
+            100,                                    // FuncEntryCount
+            7, 1, 3, 5,                             // Freq, NumSuccs, (ID, Prob)
+            7, 0,                                   // Freq, NumSuccs
+            7, 0,                                   // Freq, NumSuccs
+        ];
+        let mut funcs = Vec::new();
+        read_section(&data, false, &HashMap::new(), &mut funcs).unwrap();
+        assert_eq!(funcs.len(), 1);
+        let ranges = funcs[0].ranges();
+        assert_eq!((ranges[0].addr(), ranges[1].addr()), (0x1000, 0x2000));
+        let blocks = funcs[0].blocks().map(|b| (b.id(), b.offset(), b.size())).collect::<Vec<_>>();
+        // Offsets are relative to the end of the previous block in the same range.
+        assert_eq!(blocks, vec![(0, 0, 4), (3, 6, 128), (1, 0, 6)]);
+        assert!(funcs[0].blocks().next().unwrap().can_fall_through());
+
+        // Unknown versions are rejected.
+        assert!(read_section(&[3, 0], false, &HashMap::new(), &mut funcs).is_err());
+
+        // Offsets and sizes that add up to more than the address space are rejected.
+        let max = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01];
+        for (offset, size) in [(&[4][..], &max[..]), (&max[..], &[0][..])] {
+            let mut data = vec![
+                1, 0,                               // Version, Features
+                0x00, 0x10, 0, 0, 0, 0, 0, 0,       // BaseAddress
+                2,                                  // NumBlocks
+                0, 4, 0,                            // Offset, Size, Metadata
+            ];
+            data.extend(offset);
+            data.extend(size);
+            data.push(0);
+            assert!(read_section(&data, false, &HashMap::new(), &mut funcs).is_err());
+        }
+    }
+
+    #[test]
+    fn test_stack_sizes() {
+        for bin in &["fannkuch_redux", "fannkuch_redux_pie"] {
+            let path = test_bin_path("stack_sizes", bin);
+            build_test_inputs(&path);
+            let ss = StackSizesParser::new(&path).unwrap();
+            let sm = StackMapParser::new(&path).unwrap();
+            assert_eq!(ss.num_funcs(), sm.num_funcs() as usize);
+            assert_eq!(ss.check(&sm).unwrap(), vec![]);
+        }
+
+        let path = test_bin_path("stack_sizes", "fannkuch_redux_pie");
+        let (expect_funcs, _) = get_expected(&path);
+        let ss = StackSizesParser::new(&path).unwrap();
+        for (got, expect) in ss.iter_functions().zip(&expect_funcs) {
+            assert_eq!((got.addr(), got.stack_size()), (expect.addr(), expect.stack_size()));
+        }
+
+        // Make a copy with the size of the first function changed, and the address of the second
+        // function pointing elsewhere. The sizes are all small enough to take a single byte.
+        let elf_file = elf::File::open_path(&path).unwrap();
+        let off = elf_file.get_section(".stack_sizes").unwrap().shdr.offset as usize;
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[off + 8] += 1;
+        bytes[off + 9..off + 17].copy_from_slice(&0xdead_u64.to_ne_bytes());
+        let ss = StackSizesParser::from_bytes(&bytes).unwrap();
+        let sm = StackMapParser::from_bytes(&bytes).unwrap();
+        let (f0, f1) = (&expect_funcs[0], &expect_funcs[1]);
+        assert_eq!(ss.check(&sm).unwrap(), vec![
+            StackSizeMismatch::Differs{addr: f0.addr(), stackmap: f0.stack_size(),
+                                       stack_sizes: f0.stack_size() + 1},
+            StackSizeMismatch::NotInStackSizes{addr: f1.addr()},
+            StackSizeMismatch::NotInStackMaps{addr: 0xdead},
+        ]);
+    }
+
+    #[cfg(feature = "object")]
+    #[test]
+    fn test_object_backend() {
+        let paths = [
+            test_bin_path("hello_world", "hello_world1"),
+            test_bin_path("pie", "hello_world"),
+            test_bin_path("compressed", "zlib"),
+            checked_in_path("coff", "stackmap.obj"),
+        ];
+        for path in &paths {
+            build_test_inputs(path);
+            let bytes = fs::read(path).unwrap();
+            let file = object::File::parse(&*bytes).unwrap();
+            let got = StackMapParser::from_backend(&file).unwrap();
+            let expect = StackMapParser::from_bytes(&bytes).unwrap();
+            assert_eq!(got.num_funcs(), expect.num_funcs());
+            assert_eq!(got.num_stackmaps(), expect.num_stackmaps());
+            for (got, expect) in got.iter_functions().zip(expect.iter_functions()) {
+                assert_eq!(got.unwrap(), expect.unwrap());
+            }
+            for (got, expect) in got.iter_stackmaps().zip(expect.iter_stackmaps()) {
+                assert_eq!(got.unwrap(), expect.unwrap());
+            }
+            // We don't read the relocations of COFF objects ourselves.
+            if !path.ends_with("stackmap.obj") {
+                assert_eq!(got.relocations(), expect.relocations());
+                // Both backends give an error for a section that doesn't exist.
+                let elf = ElfBackend::from_bytes(&bytes).unwrap();
+                assert!(elf.section_data(usize::MAX).is_err());
+                assert!(file.section_data(usize::MAX).is_err());
+            }
+        }
+
+        let path = test_bin_path("faultmaps", "faultmaps");
+        build_test_inputs(&path);
+        let bytes = fs::read(&path).unwrap();
+        let file = object::File::parse(&*bytes).unwrap();
+        let got = FaultMapParser::from_backend(&file).unwrap();
+        let expect = FaultMapParser::from_bytes(&bytes).unwrap();
+        assert_eq!(got.relocations(), expect.relocations());
+        for (got, expect) in got.iter_functions().zip(expect.iter_functions()) {
+            assert_eq!(got.unwrap(), expect.unwrap());
+        }
+    }
+
+    #[test]
+    fn test_quirk_mode() {
+        // The producer of the IR is recorded in the `.comment` section.
+        let path = test_bin_path("hello_world", "hello_world1");
+        build_test_inputs(&path);
+        let p = StackMapParser::new(&path).unwrap();
+        assert!(p.producers().iter().any(|s| s.starts_with("clang version 3.8.1")));
+        assert_eq!(p.quirk_mode(), QuirkMode::SignedConstants);
+
+        // Without a producer, we read the section as llvm-readobj does.
+        let path = test_bin_path("large_v3_stackmap", "stackmap");
+        build_test_inputs(&path);
+        let mut p = StackMapParser::new(&path).unwrap();
+        assert!(p.producers().is_empty());
+        assert_eq!(p.quirk_mode(), QuirkMode::UnsignedConstants);
+        let unsigned = p.iter_stackmaps().map(Result::unwrap).collect::<Vec<_>>();
+        assert!(unsigned.iter().flat_map(|r| &r.locs).any(|l| l.offset == LocOffset::U32(0xffff_ffff)));
+        p.set_quirk_mode(QuirkMode::SignedConstants);
+        let signed = p.iter_stackmaps().map(Result::unwrap).collect::<Vec<_>>();
+        assert_eq!(signed, with_quirks(unsigned, QuirkMode::SignedConstants));
+    }
+
+    #[test]
+    fn test_quirk_mode_from_producers() {
+        let mode = |ps: &[&str]| quirk_mode(&ps.iter().map(|p| p.to_string()).collect::<Vec<_>>());
+        assert_eq!(mode(&[]), QuirkMode::UnsignedConstants);
+        assert_eq!(mode(&["GCC: (Debian 12.2.0-14) 12.2.0"]), QuirkMode::UnsignedConstants);
+        assert_eq!(mode(&["GCC: (Debian 12.2.0-14) 12.2.0", "Ubuntu clang version 14.0.0-1ubuntu1"]),
+                   QuirkMode::SignedConstants);
+        assert_eq!(mode(&["clang version 3.4 (tags/RELEASE_34/final)"]), QuirkMode::UnsignedConstants);
+        assert_eq!(mode(&["rustc version 1.70.0 (90c541806 2023-05-31)"]), QuirkMode::SignedConstants);
+
+        // DWARF producers come before those in the comment section.
+        let path = test_bin_path("producer", "hello_world");
+        build_test_inputs(&path);
+        let p = StackMapParser::new(&path).unwrap();
+        let pos = |prefix| p.producers().iter().position(|s: &String| s.starts_with(prefix));
+        assert!(pos("GNU C").unwrap() < pos("clang version 3.8.1").unwrap());
+        assert_eq!(p.quirk_mode(), QuirkMode::SignedConstants);
+    }
+
+    /// A fake frame: register `n` holds `0x1000 * (n + 1)`, and each word of memory holds its own
+    /// address, except that reads below `0x100` fail.
+    struct FakeMemory;
+
+    impl MemoryReader for FakeMemory {
+        fn read_memory(&self, addr: u64, buf: &mut [u8]) -> io::Result<()> {
+            if addr < 0x100 {
+                return Err(io::Error::other("unmapped"));
+            }
+            let word = addr.to_ne_bytes();
+            buf.copy_from_slice(&word[..buf.len()]);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_eval_loc() {
+        let path = test_bin_path("large_v3_stackmap", "stackmap");
+        build_test_inputs(&path);
+        let mut p = StackMapParser::new(&path).unwrap();
+        p.set_quirk_mode(QuirkMode::SignedConstants);
+        let regs = (0..32).map(|n| 0x1000 * (n + 1)).collect::<Vec<u64>>();
+        let blob = p.iter_blobs().next().unwrap();
+        let recs = blob.iter_stackmaps().map(Result::unwrap).collect::<Vec<_>>();
+        let eval = |loc: &SMLoc| blob.eval_loc(loc, regs.as_slice(), &FakeMemory);
+
+        // Small constants are sign-extended, and large ones come from the constants table.
+        let got = recs[0].locs.iter().map(|l| eval(l).unwrap()).collect::<Vec<_>>();
+        let expect = [u64::MAX, u64::MAX, 65536, 2000000000, 2147483647, u64::MAX, u64::MAX, 0,
+                      2147483648, 4294967295, 4294967296, u64::MAX];
+        assert_eq!(got, expect.iter().map(|&v| LocValue::Value(v)).collect::<Vec<_>>());
+        let consts = blob.iter_constants().map(Result::unwrap).collect::<Vec<_>>();
+        for loc in &recs[0].locs {
+            assert_eq!(eval_loc(loc, &consts, regs.as_slice(), &FakeMemory).unwrap(), eval(loc).unwrap());
+        }
+
+        // Every other kind of location is relative to a register.
+        let base = |l: &SMLoc| regs[l.dwarf_reg as usize];
+        let offset = |l: &SMLoc| match l.offset {
+            LocOffset::I32(v) => v as u64,
+            LocOffset::U32(_) => unreachable!(),
+        };
+        let mut kinds = Vec::new();
+        for loc in recs.iter().flat_map(|r| &r.locs).filter(|l| l.size <= 8) {
+            let expect = match loc.kind {
+                LocKind::Register => LocValue::Value(base(loc) & (u64::MAX >> (64 - 8 * loc.size))),
+                LocKind::Direct => LocValue::Address(base(loc).wrapping_add(offset(loc))),
+                LocKind::Indirect => {
+                    let addr = base(loc).wrapping_add(offset(loc));
+                    LocValue::Value(addr & (u64::MAX >> (64 - 8 * loc.size)))
+                },
+                LocKind::Constant | LocKind::ConstIndex => continue,
+            };
+            assert_eq!(eval(loc).unwrap(), expect);
+            kinds.push(format!("{:?}", loc.kind));
+        }
+        for kind in &["Register", "Direct", "Indirect"] {
+            assert!(kinds.iter().any(|k| k == kind));
+        }
+
+        // Failures are errors.
+        let indirect = recs.iter().flat_map(|r| &r.locs).find(|l| l.kind == LocKind::Indirect).unwrap();
+        assert!(blob.eval_loc(indirect, &regs[..1], &FakeMemory).is_err());
+        match blob.eval_loc(indirect, vec![0; 32].as_slice(), &FakeMemory) {
+            Err(SMParserError::IO(_)) => (),
+            r => panic!("expected an IO error, got {:?}", r),
+        }
+        let mut out_of_range = SMLoc{kind: LocKind::ConstIndex, size: 8, dwarf_reg: 0,
+                                     offset: LocOffset::I32(3)};
+        assert!(eval(&out_of_range).is_err());
+        out_of_range.offset = LocOffset::I32(-1);
+        assert!(eval(&out_of_range).is_err());
+        let wide = SMLoc{kind: LocKind::Register, size: 16, dwarf_reg: 17, offset: LocOffset::I32(0)};
+        assert!(eval(&wide).is_err());
+    }
+
+    #[test]
+    fn test_eval_constants_any_quirks() {
+        // Constants are sign-extended in whatever quirk mode they were decoded.
+        let path = test_bin_path("large_v3_stackmap", "stackmap");
+        build_test_inputs(&path);
+        let p = StackMapParser::new(&path).unwrap();
+        let blob = p.iter_blobs().next().unwrap();
+        let rec = blob.iter_stackmaps().next().unwrap().unwrap();
+        let got = rec.locs.iter().map(|l| blob.eval_loc(l, &[0u64][..], &FakeMemory).unwrap())
+            .collect::<Vec<_>>();
+        let expect = [u64::MAX, u64::MAX, 65536, 2000000000, 2147483647, u64::MAX, u64::MAX, 0,
+                      2147483648, 4294967295, 4294967296, u64::MAX];
+        assert_eq!(got, expect.iter().map(|&v| LocValue::Value(v)).collect::<Vec<_>>());
+
+        let unsigned = SMLoc{kind: LocKind::Constant, size: 8, dwarf_reg: 0,
+                             offset: LocOffset::U32(0xffff_fffe)};
+        assert_eq!(eval_loc(&unsigned, &[], &[0u64][..], &FakeMemory).unwrap(),
+                   LocValue::Value(u64::MAX - 1));
+    }
+
+    #[test]
+    fn test_arch() {
+        assert_eq!(Arch::from_e_machine(62), Some(Arch::X86_64));
+        assert_eq!(Arch::from_e_machine(3), None);
+        for &arch in &[Arch::X86_64, Arch::AArch64, Arch::RiscV64, Arch::PPC64LE] {
+            assert!(arch.reg_name(arch.stack_pointer()).is_some());
+            assert!(arch.reg_name(arch.frame_pointer()).is_some());
+            assert!(arch.callee_saved().contains(&arch.frame_pointer()));
+            assert!(arch.callee_saved().contains(&arch.stack_pointer()));
+            assert!(arch.callee_saved().iter().all(|&r| arch.reg_name(r).is_some()));
+            assert_eq!(arch.pointer_width(), 8);
+        }
+        assert_eq!(Arch::X86_64.reg_name(6), Some("rbp"));
+        assert_eq!(Arch::AArch64.reg_name(31), Some("sp"));
+        assert_eq!(Arch::RiscV64.reg_name(8), Some("s0"));
+        assert_eq!(Arch::PPC64LE.reg_name(108), Some("v31"));
+        assert_eq!(Arch::X86_64.reg_name(1000), None);
+
+        // The architecture comes from the ELF header, and names the registers of locations.
+        let path = test_bin_path("large_v3_stackmap", "stackmap");
+        build_test_inputs(&path);
+        let p = StackMapParser::new(&path).unwrap();
+        assert_eq!(p.arch(), Some(Arch::X86_64));
+        let rec = p.iter_stackmaps().map(Result::unwrap).find(|r| r.id == 5).unwrap();
+        assert_eq!(rec.locs[0].to_string(), "Register R#0, size: 8");
+        assert_eq!(rec.locs[0].display(p.arch()).to_string(), "Register rax, size: 8");
+        assert_eq!(rec.liveouts[1].to_string(), "R#7 (8-bytes)");
+        assert_eq!(rec.liveouts[1].display(p.arch()).to_string(), "rsp (8-bytes)");
+        let direct = p.iter_stackmaps().map(Result::unwrap).flat_map(|r| r.locs)
+                      .find(|l| l.kind == LocKind::Direct).unwrap();
+        assert!(direct.display(p.arch()).to_string().starts_with("Direct rbp + -"));
+
+        let p = StackMapParser::new(&checked_in_path("coff", "stackmap.obj")).unwrap();
+        assert_eq!(p.arch(), Some(Arch::X86_64));
+    }
+
+    #[test]
+    fn test_deopt_frame() {
+        let path = test_bin_path("stack_sizes", "fannkuch_redux_pie");
+        build_test_inputs(&path);
+        let mut p = StackMapParser::new(&path).unwrap();
+        let regs = (0..32).map(|n| 0x1000 * (n + 1)).collect::<Vec<u64>>();
+        let funcs = p.iter_functions().map(Result::unwrap).collect::<Vec<_>>();
+        let mut recs = p.iter_stackmaps().map(Result::unwrap);
+        let blob = p.iter_blobs().next().unwrap();
+        let mut seen = 0;
+        for func in &funcs {
+            for rec in recs.by_ref().take(func.record_count() as usize) {
+                let pc = func.record_addr(&rec);
+                let frame = DeoptFrame::new(&p, pc, regs.as_slice(), &FakeMemory).unwrap().unwrap();
+                assert_eq!(frame.id(), rec.id);
+                assert_eq!(frame.func(), func);
+                assert_eq!(frame.values().len(), rec.locs.len());
+                for (v, loc) in frame.values().iter().zip(&rec.locs) {
+                    assert_eq!(v.value(), blob.eval_loc(loc, regs.as_slice(), &FakeMemory).unwrap());
+                    assert_eq!(v.size(), loc.size);
+                }
+                seen += 1;
+            }
+        }
+        assert_eq!(seen, p.num_stackmaps());
+
+        // Only the exact address of a record will do.
+        // Records are in function order, so the first belongs to the first function with any.
+        let func = funcs.iter().find(|f| f.record_count() > 0).unwrap();
+        let first = p.iter_stackmaps().next().unwrap().unwrap();
+        let pc = func.record_addr(&first);
+        assert!(DeoptFrame::new(&p, pc - 1, regs.as_slice(), &FakeMemory).unwrap().is_none());
+
+        // Addresses are runtime addresses.
+        p.set_load_bias(0x100000);
+        assert!(p.find_record(pc).unwrap().is_none());
+        let (_, found_func, found) = p.find_record(pc + 0x100000).unwrap().unwrap();
+        assert_eq!(found, first);
+        assert_eq!(found_func.addr(), func.addr() + 0x100000);
+    }
+
+    #[test]
+    fn test_statepoint() {
+        let path = test_bin_path("statepoint", "statepoint");
+        check_expected_stackmaps(path.clone());
+        let p = StackMapParser::new(&path).unwrap();
+        let rec = p.iter_stackmaps().next().unwrap().unwrap();
+        let sp = Statepoint::new(&rec).unwrap();
+        assert_eq!(sp.id(), 42);
+        assert_eq!(sp.calling_conv(), 0);
+        assert_eq!(sp.flags(), 1);
+
+        // `i64 %x, i32 7, i64 5000000000`
+        let deopt = sp.deopt_args();
+        assert_eq!(deopt.len(), 3);
+        assert_eq!(deopt[0].kind, LocKind::Indirect);
+        assert_eq!(deopt[1].kind, LocKind::Constant);
+        assert_eq!(deopt[2].kind, LocKind::ConstIndex);
+
+        // `(%obj, %derived)` and `(%obj, %obj)`
+        assert_eq!(sp.num_gc_pairs(), 2);
+        let pairs = sp.gc_pairs().collect::<Vec<_>>();
+        assert_ne!(pairs[0].0, pairs[0].1);
+        assert_eq!(pairs[0].0, pairs[1].0);
+        assert_eq!(pairs[1].0, pairs[1].1);
+
+        // Records laid out any other way are rejected.
+        let constant = |c| SMLoc{kind: LocKind::Constant, size: 8, dwarf_reg: 0, offset: LocOffset::I32(c)};
+        let bad_layouts = vec![
+            vec![constant(0), constant(0)],
+            vec![constant(0), constant(0), constant(1)],
+            vec![constant(0), constant(0), constant(0), constant(0)],
+            vec![constant(0), constant(0), constant(-1)],
+            vec![constant(0), rec.locs[3].clone(), constant(0)],
+        ];
+        for locs in bad_layouts {
+            let rec = SMRec{id: 1, offset: 0, num_locs: locs.len() as u16, locs, liveouts: Vec::new()};
+            assert!(Statepoint::new(&rec).is_err());
+        }
+    }
+
+    #[test]
+    fn test_patchpoint() {
+        let path = test_bin_path("large_v3_stackmap", "stackmap");
+        build_test_inputs(&path);
+        let p = StackMapParser::new(&path).unwrap();
+        let recs = p.iter_stackmaps().map(Result::unwrap).collect::<Vec<_>>();
+        let rec = |id| recs.iter().find(|r| r.id == id).unwrap();
+
+        // propertyRead: `anyregcc i64 ... i32 1, i64* %obj`
+        let pp = Patchpoint::anyreg(rec(5), true, 1).unwrap();
+        assert_eq!(pp.id(), 5);
+        let ret = pp.ret().unwrap();
+        assert_eq!(ret, &rec(5).locs[0]);
+        assert_eq!(pp.args(), &rec(5).locs[1..]);
+        assert!(pp.live_values().is_empty());
+        assert_eq!(pp.ret_liveout().unwrap().dwarf_reg, ret.dwarf_reg);
+        assert_eq!(pp.liveouts(), rec(5).liveouts.as_slice());
+
+        // propertyWrite: `anyregcc void ... i32 2, i64* %obj, i64 %a`
+        let pp = Patchpoint::anyreg(rec(6), false, 2).unwrap();
+        assert!(pp.ret().is_none());
+        assert!(pp.ret_liveout().is_none());
+        assert_eq!(pp.args().len(), 2);
+        assert!(pp.live_values().is_empty());
+        assert!(Patchpoint::anyreg(rec(6), true, 2).is_err());
+
+        // jsVoidCall: `void ... i32 2, i64* %obj, i64 %arg, i64 %l1, i64 %l2`
+        let pp = Patchpoint::new(rec(7));
+        assert!(pp.ret().is_none());
+        assert!(pp.args().is_empty());
+        assert_eq!(pp.live_values(), rec(7).locs.as_slice());
+
+        // constantargs: the return value must be in a register.
+        assert!(Patchpoint::anyreg(rec(1), true, 0).is_err());
+    }
+
+    #[test]
+    fn test_gc_roots() {
+        let path = test_bin_path("statepoint", "statepoint");
+        build_test_inputs(&path);
+        let p = StackMapParser::new(&path).unwrap();
+        let func = p.iter_functions().next().unwrap().unwrap();
+        let rec = p.iter_stackmaps().next().unwrap().unwrap();
+        let regs = (0..32).map(|n| 0x1000 * (n + 1)).collect::<Vec<u64>>();
+
+        // Both pairs have the same base, which is a spill slot, as is the derived pointer.
+        let roots = gc_roots(&p, func.record_addr(&rec), regs.as_slice()).unwrap().unwrap();
+        let slot = |loc: &SMLoc| match loc.offset {
+            LocOffset::I32(off) => RootSlot::Memory(regs[loc.dwarf_reg as usize] + off as u64),
+            LocOffset::U32(_) => unreachable!(),
+        };
+        let sp = Statepoint::new(&rec).unwrap();
+        let pairs = sp.gc_pairs().collect::<Vec<_>>();
+        assert_eq!(roots.len(), 2);
+        for (root, (base, derived)) in roots.iter().zip(pairs) {
+            assert_eq!(root.base(), slot(base));
+            assert_eq!(root.derived(), slot(derived));
+        }
+        assert_eq!(roots[0].base(), roots[1].base());
+        assert_ne!(roots[0].base(), roots[0].derived());
+
+        assert!(gc_roots(&p, func.record_addr(&rec) + 1, regs.as_slice()).unwrap().is_none());
+        assert!(gc_roots(&p, func.record_addr(&rec), &regs[..1]).is_err());
+    }
+
+    /// Memory made of words at the given addresses. Reading anything else fails.
+    struct WordMemory(HashMap<u64, u64>);
+
+    impl MemoryReader for WordMemory {
+        fn read_memory(&self, addr: u64, buf: &mut [u8]) -> io::Result<()> {
+            let word = self.0.get(&addr).ok_or_else(|| io::Error::other("unmapped"))?;
+            buf.copy_from_slice(&word.to_ne_bytes()[..buf.len()]);
+            Ok(())
+        }
+    }
+
+    impl MemoryWriter for WordMemory {
+        fn write_memory(&mut self, addr: u64, buf: &[u8]) -> io::Result<()> {
+            let word = self.0.get_mut(&addr).ok_or_else(|| io::Error::other("unmapped"))?;
+            let mut bytes = word.to_ne_bytes();
+            bytes[..buf.len()].copy_from_slice(buf);
+            *word = u64::from_ne_bytes(bytes);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_relocate_roots() {
+        let path = test_bin_path("statepoint", "statepoint");
+        build_test_inputs(&path);
+        let p = StackMapParser::new(&path).unwrap();
+        let funcs = p.iter_functions().map(Result::unwrap).collect::<Vec<_>>();
+        let recs = p.iter_stackmaps().map(Result::unwrap).collect::<Vec<_>>();
+        let regs = (0..32).map(|n| 0x1000 * (n + 1)).collect::<Vec<u64>>();
+
+        // The object is at 0x5000, and the derived pointer 0x10 bytes into it.
+        let pc = funcs[0].record_addr(&recs[0]);
+        let roots = gc_roots(&p, pc, regs.as_slice()).unwrap().unwrap();
+        let (base, derived) = match (roots[0].base(), roots[0].derived()) {
+            (RootSlot::Memory(b), RootSlot::Memory(d)) => (b, d),
+            _ => panic!("expected the roots to be in memory"),
+        };
+        let mut mem = WordMemory(vec![(base, 0x5000), (derived, 0x5010)].into_iter().collect());
+        let mut calls = Vec::new();
+        let found = relocate_roots(&p, pc, regs.as_slice(), &mut mem, |old| {
+            calls.push(old);
+            old + 0x100000
+        }).unwrap();
+        assert!(found);
+        assert_eq!(calls, vec![0x5000]);
+        assert_eq!(mem.0[&base], 0x105000);
+        assert_eq!(mem.0[&derived], 0x105010);
+
+        // Null pointers are left alone.
+        let mut mem = WordMemory(vec![(base, 0), (derived, 0x10)].into_iter().collect());
+        relocate_roots(&p, pc, regs.as_slice(), &mut mem, |_| panic!("relocated null")).unwrap();
+        assert_eq!(mem.0[&derived], 0x10);
+
+        // Non-null constants can't be relocated.
+        let pc = funcs[1].record_addr(&recs[1]);
+        assert_eq!(recs[1].id, 43);
+        let mut mem = WordMemory(HashMap::new());
+        assert!(relocate_roots(&p, pc, regs.as_slice(), &mut mem, |old| old).is_err());
+
+        assert!(!relocate_roots(&p, 0x7777, regs.as_slice(), &mut mem, |old| old).unwrap());
+    }
+
+    #[test]
+    fn test_materialize() {
+        let loc = |kind, size, dwarf_reg, offset| SMLoc{kind, size, dwarf_reg,
+                                                         offset: LocOffset::I32(offset)};
+        let rec = SMRec{id: 7, offset: 0, num_locs: 3, liveouts: Vec::new(), locs: vec![
+            loc(LocKind::Register, 8, 3, 0),
+            loc(LocKind::Indirect, 4, 7, 16),
+            loc(LocKind::Indirect, 8, 6, -8),
+        ]};
+        let mut regs = vec![(7, 0x1000), (6, 0x1100)].into_iter().collect::<HashMap<_, _>>();
+        let mut mem = WordMemory(vec![(0x1010, u64::MAX), (0x10f8, 0)].into_iter().collect());
+        let values = [DeoptValue::new(LocValue::Value(0xabc), 8),
+                      DeoptValue::new(LocValue::Value(0x1234_5678), 4),
+                      DeoptValue::new(LocValue::Value(0x99), 8)];
+        materialize(&rec, &values, &mut regs, &mut mem).unwrap();
+        assert_eq!(regs[&3], 0xabc);
+        // Only the 4 bytes of the location are written.
+        assert_eq!(mem.0[&0x1010], 0xffff_ffff_1234_5678);
+        assert_eq!(mem.0[&0x10f8], 0x99);
+        for (loc, val) in rec.locs.iter().zip(&values) {
+            assert_eq!(eval_loc(loc, &[], &regs, &mem).unwrap(), val.value());
+        }
+
+        // Anything wrong with any of the values leaves the frame unchanged.
+        let (regs_before, mem_before) = (regs.clone(), mem.0.clone());
+        let mut check_err = |values: &[DeoptValue], rec: &SMRec| {
+            assert!(materialize(rec, values, &mut regs, &mut mem).is_err());
+            assert_eq!(regs, regs_before);
+            assert_eq!(mem.0, mem_before);
+        };
+        let new = |i: usize, val| {
+            let mut v = values.to_vec();
+            v[i] = val;
+            v
+        };
+        check_err(&values[..2], &rec);
+        check_err(&new(2, DeoptValue::new(LocValue::Value(1), 4)), &rec);
+        check_err(&new(1, DeoptValue::new(LocValue::Value(0x1_0000_0000), 4)), &rec);
+        check_err(&new(2, DeoptValue::new(LocValue::Address(0x2000), 8)), &rec);
+        for kind in &[LocKind::Direct, LocKind::Constant, LocKind::ConstIndex] {
+            let mut rec = rec.clone();
+            rec.locs[2].kind = *kind;
+            check_err(&values, &rec);
+        }
+
+        // Registers can also be held in an array, which must have room for them.
+        let mut regs = [0; 8];
+        let rec = SMRec{locs: rec.locs[..1].to_vec(), num_locs: 1, ..rec};
+        materialize(&rec, &values[..1], &mut regs[..], &mut mem).unwrap();
+        assert_eq!(regs[3], 0xabc);
+        assert!(materialize(&rec, &values[..1], &mut regs[..3], &mut mem).is_err());
+    }
+
+    #[test]
+    fn test_frame_builder() {
+        let path = test_bin_path("trap", "trap.so");
+        build_test_inputs(&path);
+        let p = StackMapParser::new(&path).unwrap();
+        let (func, rec) = p.iter_functions().map(Result::unwrap)
+            .zip(p.iter_stackmaps().map(Result::unwrap))
+            .find(|(_, r)| r.id == 11).unwrap();
+
+        // `@guard_ud2` keeps its argument in RDI, and a copy in a stack slot below the saved RBP.
+        let builder = FrameBuilder::new(Arch::X86_64, func.stack_size()).unwrap();
+        let values = [DeoptValue::new(LocValue::Value(5), 8), DeoptValue::new(LocValue::Value(5), 8)];
+        let frame = builder.build(&rec, &values).unwrap();
+        assert_eq!(frame.bytes().len(), 24);
+        assert_eq!(frame.fp_offset(), 16);
+        let mut expected = [0; 24];
+        expected[8..16].copy_from_slice(&5u64.to_ne_bytes());
+        assert_eq!(frame.bytes(), &expected[..]);
+        assert_eq!(frame.registers(0x7000), vec![(7, 0x7000), (6, 0x7010), (5, 5)]);
+
+        // Once in place, the frame gives the values back.
+        let sp = 0x7000;
+        let mem = WordMemory(frame.bytes().chunks(8).enumerate().map(|(i, w)| {
+            let mut word = [0; 8];
+            word.copy_from_slice(w);
+            (sp + 8 * i as u64, u64::from_ne_bytes(word))
+        }).collect());
+        let regs = frame.registers(sp).into_iter().collect::<HashMap<_, _>>();
+        assert_eq!(eval_loc(&rec.locs[0], &[], &regs, &mem).unwrap(), LocValue::Value(5));
+        assert_eq!(eval_loc(&rec.locs[1], &[], &regs, &mem).unwrap(), LocValue::Address(0x7008));
+        assert_eq!(mem.0[&0x7008], 5);
+
+        // Locations must be in the frame, relative to the stack or frame pointer, and agree with
+        // each other. Constants need no value.
+        let loc = |kind, dwarf_reg, offset| SMLoc{kind, size: 8, dwarf_reg,
+                                                  offset: LocOffset::I32(offset)};
+        let val = |v| DeoptValue::new(LocValue::Value(v), 8);
+        let build = |locs: Vec<SMLoc>, values: &[DeoptValue]| {
+            let rec = SMRec{id: 1, offset: 0, num_locs: locs.len() as u16, locs,
+                            liveouts: Vec::new()};
+            builder.build(&rec, values)
+        };
+        let frame = build(vec![loc(LocKind::Indirect, 7, 0), loc(LocKind::Indirect, 6, -16),
+                               loc(LocKind::Constant, 0, 3)],
+                          &[val(1), val(1), val(4)]).unwrap();
+        assert_eq!(&frame.bytes()[..8], &1u64.to_ne_bytes());
+        assert!(build(vec![loc(LocKind::Indirect, 7, 0), loc(LocKind::Indirect, 6, -16)],
+                      &[val(1), val(2)]).is_err());
+        assert!(build(vec![loc(LocKind::Register, 3, 0), loc(LocKind::Register, 3, 0)],
+                      &[val(1), val(2)]).is_err());
+        assert!(build(vec![loc(LocKind::Register, 7, 0)], &[val(1)]).is_err());
+        assert!(build(vec![loc(LocKind::Indirect, 3, 0)], &[val(1)]).is_err());
+        assert!(build(vec![loc(LocKind::Indirect, 6, 8)], &[val(1)]).is_err());
+        assert!(build(vec![loc(LocKind::Indirect, 7, -8)], &[val(1)]).is_err());
+        assert!(build(vec![loc(LocKind::Indirect, 7, 0)], &[]).is_err());
+        assert!(build(vec![loc(LocKind::Indirect, 7, 0)],
+                      &[DeoptValue::new(LocValue::Value(1), 4)]).is_err());
+        assert!(build(vec![loc(LocKind::Direct, 7, 0)],
+                      &[DeoptValue::new(LocValue::Address(0x7000), 8)]).is_err());
+
+        // The frame must have room for the saved frame pointer, and not be too large to build.
+        assert!(FrameBuilder::new(Arch::X86_64, 0).is_err());
+        assert!(FrameBuilder::new(Arch::X86_64, u64::MAX).is_err());
+        assert!(FrameBuilder::new(Arch::X86_64, u64::MAX - 4).is_err());
+        assert!(FrameBuilder::new(Arch::X86_64, 1 << 40).is_err());
+        // Only x86_64 frames have a fixed layout.
+        assert!(FrameBuilder::new(Arch::AArch64, 32).is_err());
+        assert!(FrameBuilder::new(Arch::RiscV64, 32).is_err());
+    }
+
+    #[test]
+    fn test_record_index() {
+        let path = test_bin_path("stack_sizes", "fannkuch_redux_pie");
+        build_test_inputs(&path);
+        let p = StackMapParser::new(&path).unwrap();
+        let index = RecordIndex::new(&p).unwrap();
+        assert!(!index.is_empty());
+        let funcs = p.iter_functions().map(Result::unwrap).collect::<Vec<_>>();
+        let mut recs = p.iter_stackmaps().map(Result::unwrap);
+        let blob = p.iter_blobs().next().unwrap();
+        let mut pcs = Vec::new();
+        for func in &funcs {
+            for rec in recs.by_ref().take(func.record_count() as usize) {
+                let pc = func.record_addr(&rec);
+                let found = index.lookup(pc).unwrap();
+                let (_, _, first) = p.find_record(pc).unwrap().unwrap();
+                assert_eq!(found.record(), &first);
+                assert_eq!(found.func(), func);
+                assert_eq!(found.constants().len(), blob.num_consts() as usize);
+                pcs.push(pc);
+            }
+        }
+        pcs.sort();
+        pcs.dedup();
+        assert_eq!(index.len(), pcs.len());
+        assert!(index.lookup(0).is_none());
+    }
+
+    #[test]
+    fn test_stack_walker() {
+        let path = test_bin_path("stack_sizes", "fannkuch_redux_pie");
+        build_test_inputs(&path);
+        let p = StackMapParser::new(&path).unwrap();
+        let index = RecordIndex::new(&p).unwrap();
+        let func = p.iter_functions().map(Result::unwrap).find(|f| f.record_count() > 0).unwrap();
+        let rec = p.iter_stackmaps().next().unwrap().unwrap();
+        let ret_addr = func.record_addr(&rec);
+
+        // Three frames: the first stopped anywhere, the second returning to a record, and the
+        // third returning elsewhere, and then the end of the chain.
+        let stack = 0x7000_0000..0x7000_1000;
+        let mem = WordMemory(vec![
+            (0x7000_0100, 0x7000_0200), (0x7000_0108, ret_addr),
+            (0x7000_0200, 0x7000_0300), (0x7000_0208, ret_addr + 1),
+            (0x7000_0300, 0), (0x7000_0308, 0),
+        ].into_iter().collect());
+        let start = Frame::new(Arch::X86_64, 0x1234, 0x7000_00f0, 0x7000_0100);
+        let frames = StackWalker::new(&index, &mem, start, stack.clone()).unwrap()
+                         .map(Result::unwrap).collect::<Vec<_>>();
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0].0, start);
+        assert!(frames[0].1.is_none());
+        assert_eq!(frames[1].0, Frame::new(Arch::X86_64, ret_addr, 0x7000_0110, 0x7000_0200));
+        assert_eq!(frames[1].1.unwrap().record(), &rec);
+        assert_eq!(frames[2].0.pc(), ret_addr + 1);
+        assert!(frames[2].1.is_none());
+
+        // Locations of the record can be evaluated in its frame.
+        let sp = Arch::X86_64.stack_pointer();
+        assert_eq!(frames[1].0.read_register(sp), Some(0x7000_0110));
+        assert_eq!(frames[1].0.read_register(0), None);
+
+        // The depth limit is enforced.
+        let walk = StackWalker::new(&index, &mem, start, stack.clone()).unwrap().with_max_depth(2);
+        let results = walk.collect::<Vec<_>>();
+        assert_eq!(results.len(), 3);
+        assert!(results[2].is_err());
+
+        // Frame pointers must be in the stack and move towards its base.
+        let outside = Frame::new(Arch::X86_64, 0x1234, 0x7000_00f0, 0x6000_0000);
+        let mut walk = StackWalker::new(&index, &mem, outside, stack.clone()).unwrap();
+        // The frame we started from is still given before the error.
+        assert_eq!(walk.next().unwrap().unwrap().0, outside);
+        assert!(walk.next().unwrap().is_err());
+        assert!(walk.next().is_none());
+        let looping = WordMemory(vec![(0x7000_0100, 0x7000_0100), (0x7000_0108, ret_addr)]
+                                     .into_iter().collect());
+        let walk = StackWalker::new(&index, &looping, start, stack.clone()).unwrap();
+        assert!(walk.map(|r| r.map(|_| ())).collect::<Result<Vec<_>, _>>().is_err());
+        // A saved frame pointer at the top of the address space is an error, not an overflow.
+        let top = u64::MAX - 7;
+        let overflowing = WordMemory(vec![(0x7000_0100, top), (0x7000_0108, ret_addr)]
+                                         .into_iter().collect());
+        let mut walk = StackWalker::new(&index, &overflowing, start, 0x7000_0000..u64::MAX)
+                           .unwrap();
+        assert_eq!(walk.next().unwrap().unwrap().0, start);
+        assert_eq!(walk.next().unwrap().unwrap().0.fp(), top);
+        assert!(walk.next().unwrap().is_err());
+        assert!(walk.next().is_none());
+
+        // On AArch64, the frame record's place in the frame isn't fixed, so the stack pointers of
+        // the callers are unknown.
+        let start = Frame::new(Arch::AArch64, 0x1234, 0x7000_00f0, 0x7000_0100);
+        let frames = StackWalker::new(&index, &mem, start, stack.clone()).unwrap()
+                         .map(Result::unwrap).collect::<Vec<_>>();
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0].0.sp(), Some(0x7000_00f0));
+        assert_eq!((frames[1].0.sp(), frames[1].0.fp()), (None, 0x7000_0200));
+        assert_eq!(frames[1].0.read_register(Arch::AArch64.stack_pointer()), None);
+
+        assert!(StackWalker::new(&index, &mem, Frame::new(Arch::RiscV64, 0, 0, 0), stack).is_err());
+    }
+
+    #[test]
+    fn test_cfi_unwinder() {
+        let path = test_bin_path("unwind", "unwind");
+        build_test_inputs(&path);
+        let p = StackMapParser::new(&path).unwrap();
+        let index = RecordIndex::new(&p).unwrap();
+        let unwinder = CfiUnwinder::new(&path).unwrap();
+        assert_eq!(unwinder.arch(), Arch::X86_64);
+        // Each function with a stackmap has one record.
+        let addr_of = |id| {
+            let (func, rec) = p.iter_functions().map(Result::unwrap)
+                .zip(p.iter_stackmaps().map(Result::unwrap))
+                .find(|(_, r)| r.id == id).unwrap();
+            func.record_addr(&rec)
+        };
+        let (inner_pc, outer_pc) = (addr_of(1), addr_of(2));
+        // Where `@inner` returns to in `@middle`, which has no frame pointer. Its frame is 64 bytes
+        // and holds the saved RBP, R12 and RBX at the top.
+        let middle_ret = 0x1162;
+        let (rsp, rbp, rbx, r12, rip) = (7, 6, 3, 12, 16);
+
+        // `@inner` stopped at its record, called from `@middle`, which was called from `@outer`.
+        let middle_cfa = 0x7000_0400;
+        let inner_cfa = middle_cfa - 64;
+        let outer_fp = 0x7000_0500;
+        let mem = WordMemory(vec![
+            (middle_cfa - 8, outer_pc), (middle_cfa - 16, outer_fp), (middle_cfa - 24, 0x1212),
+            (middle_cfa - 32, 0xb0b0),
+            (inner_cfa - 8, middle_ret), (inner_cfa - 16, 0xdead), (inner_cfa - 24, 0x5555),
+            (outer_fp, 0), (outer_fp + 8, 0),
+        ].into_iter().collect());
+        let mut start = RegisterSet::new(inner_pc);
+        start.set_register(rsp, inner_cfa - 32);
+        start.set_register(rbp, inner_cfa - 16);
+        start.set_register(rbx, 7);
+        assert_eq!(unwinder.cfa(&start).unwrap(), inner_cfa);
+
+        let frames = unwinder.walk(&index, &mem, start.clone()).map(Result::unwrap)
+                             .collect::<Vec<_>>();
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[0].0, start);
+        let rec = frames[0].1.unwrap().record();
+        assert_eq!(rec.id, 1);
+        assert_eq!(eval_loc(&rec.locs[0], &[], &frames[0].0, &mem).unwrap(), LocValue::Value(7));
+
+        let middle = &frames[1].0;
+        assert_eq!(middle.pc(), middle_ret);
+        assert!(frames[1].1.is_none());
+        assert_eq!(middle.register(rsp), Some(inner_cfa));
+        assert_eq!(middle.register(rbp), Some(0xdead));
+        assert_eq!(middle.register(rbx), Some(0x5555));
+        assert_eq!(middle.register(0), None);
+
+        // The registers of `@outer` come from where `@middle` saved them.
+        let outer = &frames[2].0;
+        assert_eq!(outer.pc(), outer_pc);
+        assert_eq!(outer.register(rsp), Some(middle_cfa));
+        assert_eq!(outer.register(rbp), Some(outer_fp));
+        assert_eq!(outer.register(rbx), Some(0xb0b0));
+        assert_eq!(outer.register(r12), Some(0x1212));
+        let rec = frames[2].1.unwrap().record();
+        assert_eq!(rec.id, 2);
+        assert_eq!(eval_loc(&rec.locs[0], &[], outer, &mem).unwrap(), LocValue::Value(0xb0b0));
+
+        // Following the frame pointers instead gets lost in `@middle`.
+        let stack = 0x7000_0000..0x7000_1000;
+        let fp_start = Frame::new(Arch::X86_64, inner_pc, inner_cfa - 32, inner_cfa - 16);
+        let walk = StackWalker::new(&index, &mem, fp_start, stack).unwrap();
+        assert!(walk.map(|r| r.map(|_| ())).collect::<Result<Vec<_>, _>>().is_err());
+
+        // The depth limit is enforced, and addresses without CFI can't be unwound.
+        let results = unwinder.walk(&index, &mem, start.clone()).with_max_depth(2)
+                              .collect::<Vec<_>>();
+        assert_eq!(results.len(), 3);
+        assert!(results[2].is_err());
+        assert!(unwinder.unwind(&RegisterSet::new(0x10), &mem).is_err());
+
+        // A frame that can't be unwound is still given before the error.
+        let lost = WordMemory(HashMap::new());
+        let mut lost_walk = unwinder.walk(&index, &lost, start.clone());
+        assert_eq!(lost_walk.next().unwrap().unwrap().0, start);
+        assert!(lost_walk.next().unwrap().is_err());
+        assert!(lost_walk.next().is_none());
+
+        // The CFI of `_start` says that it has no return address, even if the return address
+        // register seems to have a value.
+        let elf_file = elf::File::open_path(&path).unwrap();
+        let symtab = elf_file.get_section(".symtab").unwrap();
+        let start_addr = elf_file.get_symbols(symtab).unwrap().iter()
+            .find(|s| s.name == "_start").unwrap().value;
+        let mut entry = RegisterSet::new(start_addr);
+        entry.set_register(rsp, inner_cfa);
+        entry.set_register(rip, 0x1234);
+        assert!(unwinder.unwind(&entry, &mem).unwrap().is_none());
+
+        // With a load bias, the same stack at runtime addresses unwinds in the same way.
+        let bias = 0x5555_0000_0000;
+        let mut unwinder = unwinder;
+        unwinder.set_load_bias(bias);
+        let mem = WordMemory(vec![(inner_cfa - 8, middle_ret + bias), (inner_cfa - 16, 0xdead),
+                                  (inner_cfa - 24, 0x5555)].into_iter().collect());
+        let mut start = RegisterSet::new(inner_pc + bias);
+        start.set_register(rsp, inner_cfa - 32);
+        start.set_register(rbp, inner_cfa - 16);
+        let caller = unwinder.unwind(&start, &mem).unwrap().unwrap();
+        assert_eq!(caller.pc(), middle_ret + bias);
+        assert_eq!(caller.register(rsp), Some(inner_cfa));
+    }
+
+    // Held by the tests that change the actions of signals, which are process-wide.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    static SIGNAL_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    #[test]
+    fn test_registers_from_ucontext() {
+        use std::arch::asm;
+        use std::ffi::c_void;
+        use std::sync::Mutex;
+        use std::{mem, ptr};
+
+        let _lock = SIGNAL_LOCK.lock().unwrap();
+        static CAPTURED: Mutex<Option<([u64; UCONTEXT_NUM_REGS], RegisterSet)>> = Mutex::new(None);
+        extern "C" fn on_trap(_: libc::c_int, _: *mut libc::siginfo_t, uc: *mut c_void) {
+            let captured = unsafe { (ucontext_registers(uc), RegisterSet::from_ucontext(uc)) };
+            *CAPTURED.lock().unwrap() = Some(captured);
+        }
+
+        let (sp, pc): (u64, u64);
+        unsafe {
+            let mut act: libc::sigaction = mem::zeroed();
+            act.sa_sigaction = on_trap as *const () as usize;
+            act.sa_flags = libc::SA_SIGINFO;
+            let mut old: libc::sigaction = mem::zeroed();
+            assert_eq!(libc::sigaction(libc::SIGTRAP, &act, &mut old), 0);
+            // The kernel reports the address after the `int3`.
+            asm!("mov {sp}, rsp",
+                 "lea {pc}, [rip + 2f]",
+                 "int3",
+                 "2:",
+                 sp = out(reg) sp, pc = out(reg) pc,
+                 in("r12") 0x1212u64, in("r13") 0x1313u64, in("xmm1") 1.5f64);
+            assert_eq!(libc::sigaction(libc::SIGTRAP, &old, ptr::null_mut()), 0);
+        }
+
+        let (snapshot, regs) = CAPTURED.lock().unwrap().take().unwrap();
+        // The snapshot has the instruction pointer in the return address column.
+        assert_eq!(snapshot[16], pc);
+        assert_eq!(regs.pc(), pc);
+        fn check<R: RegisterContext + ?Sized>(regs: &R, sp: u64) {
+            assert_eq!(regs.read_register(Arch::X86_64.stack_pointer()), Some(sp));
+            assert_eq!(regs.read_register(12), Some(0x1212));
+            assert_eq!(regs.read_register(13), Some(0x1313));
+            assert_eq!(regs.read_register(18), Some(1.5f64.to_bits()));
+
+            // Locations can be evaluated against the captured registers directly.
+            let loc = SMLoc{kind: LocKind::Register, size: 8, dwarf_reg: 13,
+                            offset: LocOffset::I32(0)};
+            assert_eq!(eval_loc(&loc, &[], regs, &FakeMemory).unwrap(), LocValue::Value(0x1313));
+        }
+        check(&snapshot[..], sp);
+        check(&regs, sp);
+        // Only the register set knows which registers weren't saved.
+        assert_eq!(regs.read_register(33), None);
+        assert_eq!(snapshot[..].read_register(33), Some(0));
+    }
+
+    /// A shared object from the test inputs, loaded into the test process.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    struct TestLib {
+        handle: *mut libc::c_void,
+        path: PathBuf,
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    impl TestLib {
+        fn open(dir: &str, name: &str) -> Self {
+            use std::ffi::CString;
+            use std::os::unix::ffi::OsStrExt;
+
+            let path = test_bin_path(dir, name);
+            build_test_inputs(&path);
+            let cpath = CString::new(path.as_os_str().as_bytes()).unwrap();
+            let handle = unsafe { libc::dlopen(cpath.as_ptr(), libc::RTLD_NOW) };
+            assert!(!handle.is_null());
+            TestLib{handle, path}
+        }
+
+        fn sym(&self, name: &str) -> *mut libc::c_void {
+            let name = std::ffi::CString::new(name).unwrap();
+            let addr = unsafe { libc::dlsym(self.handle, name.as_ptr()) };
+            assert!(!addr.is_null());
+            addr
+        }
+
+        /// Parse the stackmaps of the library, at the addresses it was loaded at.
+        fn parser(&self, some_sym: &str) -> StackMapParser {
+            // The library is linked at address 0, so it's loaded at its load bias.
+            let mut info: libc::Dl_info = unsafe { std::mem::zeroed() };
+            assert_ne!(unsafe { libc::dladdr(self.sym(some_sym), &mut info) }, 0);
+            let mut p = StackMapParser::new(&self.path).unwrap();
+            p.set_load_bias(info.dli_fbase as u64);
+            p
+        }
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    impl Drop for TestLib {
+        fn drop(&mut self) {
+            unsafe { libc::dlclose(self.handle) };
+        }
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    #[test]
+    fn test_trap_handler() {
+        use std::sync::{Arc, Mutex};
+        use std::mem;
+
+        let _lock = SIGNAL_LOCK.lock().unwrap();
+        let lib = TestLib::open("trap", "trap.so");
+        let (int3, ud2) = (lib.sym("guard_int3"), lib.sym("guard_ud2"));
+        let p = lib.parser("guard_int3");
+
+        // Record the ID and values of each trap, reading the memory that `Direct` locations
+        // point to.
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen_by_handler = Arc::clone(&seen);
+        let handler = TrapHandler::install(RecordIndex::new(&p).unwrap(), move |trap| {
+            let values = trap.values().map(|v| match v.unwrap().value() {
+                LocValue::Value(v) => v,
+                LocValue::Address(a) => unsafe { *(a as *const u64) },
+            }).collect::<Vec<_>>();
+            seen_by_handler.lock().unwrap().push((trap.signal(), trap.id(), values));
+            TrapAction::Resume
+        }).unwrap();
+        assert!(TrapHandler::install(RecordIndex::new(&p).unwrap(), |_| TrapAction::Resume)
+                    .is_err());
+
+        let guard_int3: extern "C" fn(u64, u64) -> u64 = unsafe { mem::transmute(int3) };
+        let guard_ud2: extern "C" fn(u64) -> u64 = unsafe { mem::transmute(ud2) };
+        assert_eq!(guard_int3(3, 4), 7);
+        assert_eq!(guard_ud2(5), 5);
+        drop(handler);
+        assert_eq!(*seen.lock().unwrap(), vec![(libc::SIGTRAP, 10, vec![3, 4, 42]),
+                                               (libc::SIGILL, 11, vec![5, 5])]);
+
+        // Once dropped, another handler can be installed.
+        drop(TrapHandler::install(RecordIndex::new(&p).unwrap(), |_| TrapAction::Resume).unwrap());
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    #[test]
+    fn test_lazy_deopt() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Mutex;
+        use std::mem;
+
+        // `@callee` passes its return address slot to `hook()`, which redirects it.
+        static PATCHING: Mutex<Option<(LazyDeoptHandler, RecordIndex)>> = Mutex::new(None);
+        static UNPATCH: AtomicBool = AtomicBool::new(false);
+        static SEEN: Mutex<Vec<(u64, Vec<LocValue>, u64)>> = Mutex::new(Vec::new());
+        extern "C" fn hook(slot: *mut u64) {
+            let guard = PATCHING.lock().unwrap();
+            let (handler, index) = guard.as_ref().unwrap();
+            let rec = index.lookup(unsafe { *slot }).unwrap();
+            unsafe {
+                handler.patch(slot, &rec).unwrap();
+                assert!(handler.patch(slot, &rec).is_err());
+                if UNPATCH.load(Ordering::SeqCst) {
+                    handler.unpatch(slot).unwrap();
+                }
+            }
+        }
+
+        let lib = TestLib::open("lazy", "lazy.so");
+        let p = lib.parser("caller");
+        let caller: extern "C" fn(u64, u64, extern "C" fn(*mut u64)) -> u64
+            = unsafe { mem::transmute(lib.sym("caller")) };
+        let handler = LazyDeoptHandler::install(|frame, regs| {
+            let values = frame.values().iter().map(|v| v.value()).collect();
+            SEEN.lock().unwrap().push((frame.id(), values, regs.pc()));
+            regs.pc()
+        }).unwrap();
+        assert!(LazyDeoptHandler::install(|_, regs| regs.pc()).is_err());
+
+        // A return address can only be patched with the record at that address.
+        let func = p.iter_functions().next().unwrap().unwrap();
+        let ret_addr = func.record_addr(&p.iter_stackmaps().next().unwrap().unwrap());
+        let index = RecordIndex::new(&p).unwrap();
+        let rec = index.lookup(ret_addr).unwrap();
+        let mut not_a_ret_addr = 0u64;
+        assert!(unsafe { handler.patch(&mut not_a_ret_addr, &rec) }.is_err());
+        assert!(unsafe { handler.unpatch(&mut not_a_ret_addr) }.is_err());
+
+        // A slot that's still patched when the handler is dropped gets its return address back.
+        let outstanding = Box::into_raw(Box::new(ret_addr));
+        unsafe {
+            handler.patch(outstanding, &rec).unwrap();
+            assert_ne!(*outstanding, ret_addr);
+        }
+
+        // The frame of `@caller` deoptimises when `@callee` returns to it, with `@callee`'s return
+        // value in RAX, and then carries on as normal.
+        *PATCHING.lock().unwrap() = Some((handler, index));
+        assert_eq!(caller(5, 7, hook), 17);
+        assert_eq!(*SEEN.lock().unwrap(),
+                   vec![(20, vec![LocValue::Value(7), LocValue::Value(10)], ret_addr)]);
+
+        // An unpatched frame returns as normal.
+        UNPATCH.store(true, Ordering::SeqCst);
+        assert_eq!(caller(1, 2, hook), 4);
+        assert_eq!(SEEN.lock().unwrap().len(), 1);
+        PATCHING.lock().unwrap().take();
+        assert_eq!(unsafe { *Box::from_raw(outstanding) }, ret_addr);
+
+        // Once dropped, another handler can be installed.
+        drop(LazyDeoptHandler::install(|_, regs| regs.pc()).unwrap());
+    }
 }
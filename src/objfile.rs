@@ -0,0 +1,156 @@
+// Copyright (c) 2018 King's College London
+// Created by the Software Development Team <http://soft-dev.org/>
+//
+// The Universal Permissive License (UPL), Version 1.0
+//
+// Subject to the condition set forth below, permission is hereby granted to any
+// person obtaining a copy of this software, associated documentation and/or
+// data (collectively the "Software"), free of charge and under any and all
+// copyright rights in the Software, and any and all patent rights owned or
+// freely licensable by each licensor hereunder covering either (i) the
+// unmodified Software as contributed to or provided by such licensor, or (ii)
+// the Larger Works (as defined below), to deal in both
+//
+// (a) the Software, and
+// (b) any piece of software and/or hardware listed in the lrgrwrks.txt file
+// if one is included with the Software (each a "Larger Work" to which the Software
+// is contributed by such licensors),
+//
+// without restriction, including without limitation the rights to copy, create
+// derivative works of, display, perform, and distribute the Software and make,
+// use, sell, offer for sale, import, export, have made, and have sold the
+// Software and the Larger Work(s), and to sublicense the foregoing rights on
+// either these or other terms.
+//
+// This license is subject to the following condition: The above copyright
+// notice and either this complete permission notice or at a minimum a reference
+// to the UPL must be included in all copies or substantial portions of the
+// Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// An `ObjectBackend` for binaries parsed with the `object` crate.
+
+use object::{self, Architecture, Object, ObjectSection, ObjectSymbol, RelocationFlags,
+             RelocationTarget, SectionFlags, SectionIndex, SymbolKind};
+use byteorder::{ByteOrder, NativeEndian};
+use elf;
+use arch::Arch;
+use backend::{BackendReloc, BackendSymbol, ObjectBackend};
+use errors::{SMParserError, SMParserResult};
+use reloc::relative_reloc_type;
+
+const SHF_ALLOC: u64 = 0x2;
+
+impl<'data> ObjectBackend for object::File<'data> {
+    fn section_by_name(&self, name: &str) -> Option<usize> {
+        Object::section_by_name(self, name).map(|s| s.index().0)
+    }
+
+    fn section_name(&self, idx: usize) -> Option<String> {
+        let sec = self.section_by_index(SectionIndex(idx)).ok()?;
+        sec.name().ok().map(String::from)
+    }
+
+    fn section_data(&self, idx: usize) -> SMParserResult<Vec<u8>> {
+        let sec = self.section_by_index(SectionIndex(idx))?;
+        let mut data = sec.uncompressed_data()?.into_owned();
+
+        // Sections that aren't loaded can't be relocated at load time.
+        let is_alloc = match sec.flags() {
+            SectionFlags::Elf{sh_flags} => sh_flags & SHF_ALLOC != 0,
+            _ => false,
+        };
+        let relative = machine(self.architecture()).and_then(relative_reloc_type);
+        if let (true, Some(relative), Some(relocs)) = (is_alloc, relative, self.dynamic_relocations()) {
+            let addr = sec.address();
+            for (r_offset, reloc) in relocs {
+                if reloc.flags() != (RelocationFlags::Elf{r_type: relative})
+                    || r_offset < addr || r_offset >= addr + data.len() as u64 {
+                    continue;
+                }
+                let off = (r_offset - addr) as usize;
+                match data.get_mut(off..off + 8) {
+                    Some(word) => NativeEndian::write_u64(word, reloc.addend() as u64),
+                    None => return Err(SMParserError::Other(
+                        String::from("Relocation crosses the end of the section"))),
+                }
+            }
+        }
+        Ok(data)
+    }
+
+    fn symbols(&self) -> SMParserResult<Vec<BackendSymbol>> {
+        // Some entries of the symbol table (e.g. the null symbol) aren't yielded, so leave gaps
+        // for them to keep the indices the same as the relocations use.
+        let mut syms = Vec::new();
+        for sym in Object::symbols(self) {
+            let idx = sym.index().0;
+            if syms.len() <= idx {
+                syms.resize(idx + 1, BackendSymbol::default());
+            }
+            syms[idx] = BackendSymbol{
+                name: sym.name().unwrap_or_default().to_owned(),
+                section: sym.section_index().map(|i| i.0),
+                value: sym.address(),
+                is_section: sym.kind() == SymbolKind::Section,
+            };
+        }
+        Ok(syms)
+    }
+
+    fn relocations(&self, idx: usize) -> SMParserResult<Vec<BackendReloc>> {
+        let sec = self.section_by_index(SectionIndex(idx))?;
+        let mut relocs = Vec::new();
+        for (offset, reloc) in sec.relocations() {
+            let symbol = match reloc.target() {
+                RelocationTarget::Symbol(i) => i.0,
+                // We can only describe relocations against symbols.
+                _ => continue,
+            };
+            let kind = match reloc.flags() {
+                RelocationFlags::Elf{r_type} => r_type,
+                RelocationFlags::Coff{typ} => u32::from(typ),
+                RelocationFlags::MachO{r_type, ..} => u32::from(r_type),
+                _ => 0,
+            };
+            relocs.push(BackendReloc{offset, kind, symbol, addend: reloc.addend(),
+                                     implicit_addend: reloc.has_implicit_addend()});
+        }
+        Ok(relocs)
+    }
+
+    fn image_base(&self) -> u64 {
+        match self.format() {
+            object::BinaryFormat::Pe => self.relative_address_base(),
+            _ => 0,
+        }
+    }
+
+    fn arch(&self) -> Option<Arch> {
+        match self.architecture() {
+            Architecture::X86_64 => Some(Arch::X86_64),
+            Architecture::Aarch64 => Some(Arch::AArch64),
+            Architecture::Riscv64 => Some(Arch::RiscV64),
+            Architecture::PowerPc64 if self.is_little_endian() => Some(Arch::PPC64LE),
+            _ => None,
+        }
+    }
+}
+
+/// The ELF machine type of `arch`, for those architectures we know the relocations of.
+fn machine(arch: Architecture) -> Option<elf::types::Machine> {
+    match arch {
+        Architecture::X86_64 => Some(elf::types::EM_X86_64),
+        Architecture::Aarch64 => Some(elf::types::EM_AARCH64),
+        Architecture::PowerPc64 => Some(elf::types::EM_PPC64),
+        Architecture::Riscv64 => Some(elf::types::EM_RISCV),
+        _ => None,
+    }
+}
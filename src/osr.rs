@@ -0,0 +1,271 @@
+// Copyright (c) 2018 King's College London
+// Created by the Software Development Team <http://soft-dev.org/>
+//
+// The Universal Permissive License (UPL), Version 1.0
+//
+// Subject to the condition set forth below, permission is hereby granted to any
+// person obtaining a copy of this software, associated documentation and/or
+// data (collectively the "Software"), free of charge and under any and all
+// copyright rights in the Software, and any and all patent rights owned or
+// freely licensable by each licensor hereunder covering either (i) the
+// unmodified Software as contributed to or provided by such licensor, or (ii)
+// the Larger Works (as defined below), to deal in both
+//
+// (a) the Software, and
+// (b) any piece of software and/or hardware listed in the lrgrwrks.txt file
+// if one is included with the Software (each a "Larger Work" to which the Software
+// is contributed by such licensors),
+//
+// without restriction, including without limitation the rights to copy, create
+// derivative works of, display, perform, and distribute the Software and make,
+// use, sell, offer for sale, import, export, have made, and have sold the
+// Software and the Larger Work(s), and to sublicense the foregoing rights on
+// either these or other terms.
+//
+// This license is subject to the following condition: The above copyright
+// notice and either this complete permission notice or at a minimum a reference
+// to the UPL must be included in all copies or substantial portions of the
+// Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Move values into the frame of a stackmap record, for on-stack replacement: a runtime that has
+// the live values of one frame (e.g. from a `DeoptFrame`) can store them where the record of
+// another piece of code expects them, and then carry on in that code. The frame can be an
+// existing one, or a fresh one built to enter a function at the record.
+//
+// A fresh frame is laid out as LLVM lays out a frame with a frame pointer, which functions with
+// stackmaps always have on x86_64. The Canonical Frame Address (CFA) is the stack pointer before
+// the call, and the call pushes the return address just below it. The function's stack size
+// covers the rest of the frame, from the stack pointer up to the return address, and the frame
+// pointer points to the saved frame pointer just above the stack pointer:
+//
+//               +------------------+  <- CFA
+//               | return address   |     (not part of the stack size)
+//               +------------------+
+//               | saved RBP        |
+//               +------------------+  <- FP = CFA - 16
+//               | ...              |
+//               +------------------+  <- SP
+//
+// Only x86_64 is supported. On AArch64, where the frame record sits in the frame varies from
+// function to function, and can only be found from the function's CFI.
+
+use byteorder::{ByteOrder, NativeEndian};
+use arch::Arch;
+use errors::{SMParserError, SMParserResult};
+use deopt::DeoptValue;
+use eval::{check_storable, store_loc, LocValue, MemoryWriter, RegisterContextMut};
+use {LocKind, LocOffset, SMRec};
+
+// The offset of the frame pointer below the CFA.
+const FP_BELOW_CFA: u64 = 16;
+
+// The largest frame that we'll build: the default stack size of a thread.
+const MAX_STACK_SIZE: u64 = 8 << 20;
+
+/// Store `values` in the locations of `rec`, in order, in the frame described by `regs` and
+/// `mem`. There must be one value per location, of the location's size (see `store_loc()`).
+///
+/// Every location is checked before anything is stored, so on error the frame is unchanged,
+/// unless a register or memory write fails.
+pub fn materialize<R, M>(rec: &SMRec, values: &[DeoptValue], regs: &mut R, mem: &mut M)
+                         -> SMParserResult<()>
+                         where R: RegisterContextMut + ?Sized, M: MemoryWriter + ?Sized {
+    if values.len() != rec.locs.len() {
+        return Err(SMParserError::Other(
+            format!("Record {} has {} locations, but there are {} values",
+                    rec.id, rec.locs.len(), values.len())));
+    }
+    let mut raw = Vec::with_capacity(values.len());
+    for (i, (loc, val)) in rec.locs.iter().zip(values).enumerate() {
+        if val.size() != loc.size {
+            return Err(SMParserError::Other(
+                format!("Value {} is {} bytes, but location {} of record {} is {} bytes",
+                        i, val.size(), i, rec.id, loc.size)));
+        }
+        let v = match val.value() {
+            LocValue::Value(v) => v,
+            LocValue::Address(_) => return Err(SMParserError::Other(
+                format!("Value {} is the address of a Direct location, which can't be moved", i))),
+        };
+        check_storable(loc, v)?;
+        raw.push(v);
+    }
+    for (loc, v) in rec.locs.iter().zip(raw) {
+        store_loc(loc, v, regs, mem)?;
+    }
+    Ok(())
+}
+
+/// A frame built by `FrameBuilder`, to be copied onto the stack so that its lowest byte is at the
+/// stack pointer.
+///
+/// The saved frame pointer slot is left as zeroes, for the runtime to fill in, as is the return
+/// address, which lies just above the frame.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct NativeFrame {
+    arch: Arch,
+    bytes: Vec<u8>,
+    fp_offset: u64,             // Offset of the frame pointer from the stack pointer.
+    regs: Vec<(u16, u64)>,      // Values of the record's `Register` locations.
+}
+
+impl NativeFrame {
+    /// Get the contents of the frame, from the stack pointer upwards. There's one byte per byte
+    /// of the function's stack size.
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Get the offset of the frame pointer from the stack pointer.
+    pub fn fp_offset(&self) -> u64 {
+        self.fp_offset
+    }
+
+    /// Get the registers that must be set on entry to the frame, as `(dwarf_reg, value)` pairs,
+    /// if the frame is copied to `sp`. These are the stack and frame pointers, followed by the
+    /// registers of the record's `Register` locations.
+    pub fn registers(&self, sp: u64) -> Vec<(u16, u64)> {
+        let mut regs = vec![(self.arch.stack_pointer(), sp),
+                            (self.arch.frame_pointer(), sp + self.fp_offset)];
+        regs.extend(&self.regs);
+        regs
+    }
+}
+
+/// Builds fresh frames for a function, holding given values in the locations of one of its
+/// records (see the comment at the top of this file for how the frame is laid out). Only x86_64
+/// is supported.
+pub struct FrameBuilder {
+    arch: Arch,
+    stack_size: u64,
+    fp_offset: u64,     // Offset of the frame pointer from the stack pointer.
+}
+
+impl FrameBuilder {
+    /// Make a builder for frames of a function of `arch` whose stack size (see
+    /// `SMFunc::stack_size()`) is `stack_size`. Functions with a dynamically-sized stack can't
+    /// have frames built for them, and nor can those with frames of more than 8 MiB.
+    pub fn new(arch: Arch, stack_size: u64) -> SMParserResult<Self> {
+        let ra_size = match arch {
+            Arch::X86_64 => arch.pointer_width() as u64,
+            arch => return Err(SMParserError::Other(
+                format!("Can't build frames for {:?}", arch))),
+        };
+        if stack_size == u64::MAX {
+            return Err(SMParserError::Other(
+                String::from("Can't build a frame for a function with a dynamically-sized stack")));
+        }
+        if stack_size > MAX_STACK_SIZE {
+            return Err(SMParserError::Other(
+                format!("A stack size of {} bytes is too large to build a frame for", stack_size)));
+        }
+        let fp_offset = match stack_size.checked_add(ra_size)
+                                        .and_then(|size| size.checked_sub(FP_BELOW_CFA)) {
+            Some(off) => off,
+            None => return Err(SMParserError::Other(
+                format!("A stack size of {} bytes is too small for a frame pointer", stack_size))),
+        };
+        Ok(Self{arch, stack_size, fp_offset})
+    }
+
+    /// Build a frame in which the locations of `rec` hold `values`, in order. There must be one
+    /// value per location, of the location's size.
+    ///
+    /// `Indirect` locations have their value stored in the frame, and `Direct` locations have
+    /// theirs stored at the start of the stack object that they point to. Both must be relative
+    /// to the stack or frame pointer, and lie within the frame. `Register` locations are returned
+    /// by `NativeFrame::registers()`. Constant locations need nothing doing, so their values are
+    /// ignored. Two locations that share a register or bytes of the frame must have the same
+    /// value.
+    pub fn build(&self, rec: &SMRec, values: &[DeoptValue]) -> SMParserResult<NativeFrame> {
+        if values.len() != rec.locs.len() {
+            return Err(SMParserError::Other(
+                format!("Record {} has {} locations, but there are {} values",
+                        rec.id, rec.locs.len(), values.len())));
+        }
+        let (sp, fp) = (self.arch.stack_pointer(), self.arch.frame_pointer());
+
+        let mut bytes = vec![0; self.stack_size as usize];
+        let mut written = vec![false; bytes.len()];
+        let mut regs: Vec<(u16, u64)> = Vec::new();
+        for (i, (loc, val)) in rec.locs.iter().zip(values).enumerate() {
+            if val.size() != loc.size {
+                return Err(SMParserError::Other(
+                    format!("Value {} is {} bytes, but location {} of record {} is {} bytes",
+                            i, val.size(), i, rec.id, loc.size)));
+            }
+            if let LocKind::Constant | LocKind::ConstIndex = loc.kind {
+                continue;
+            }
+            let v = match val.value() {
+                LocValue::Value(v) => v,
+                LocValue::Address(_) => return Err(SMParserError::Other(
+                    format!("Value {} is the address of a Direct location, which can't be moved",
+                            i))),
+            };
+
+            // Check the value as if it were going into a register or `Indirect` location.
+            let mut as_stored = loc.clone();
+            if loc.kind == LocKind::Direct {
+                as_stored.kind = LocKind::Indirect;
+            }
+            check_storable(&as_stored, v)?;
+
+            if loc.kind == LocKind::Register {
+                if loc.dwarf_reg == sp || loc.dwarf_reg == fp {
+                    return Err(SMParserError::Other(
+                        format!("Location {} is the stack or frame pointer", i)));
+                }
+                match regs.iter().find(|&&(r, _)| r == loc.dwarf_reg) {
+                    Some(&(_, old)) if old != v => return Err(SMParserError::Other(
+                        format!("Location {} gives DWARF register {} a second value",
+                                i, loc.dwarf_reg))),
+                    Some(_) => (),
+                    None => regs.push((loc.dwarf_reg, v)),
+                }
+                continue;
+            }
+
+            let base = if loc.dwarf_reg == sp {
+                0
+            } else if loc.dwarf_reg == fp {
+                self.fp_offset
+            } else {
+                return Err(SMParserError::Other(
+                    format!("Location {} is relative to DWARF register {}, not the stack or \
+                             frame pointer", i, loc.dwarf_reg)));
+            };
+            let offset = match loc.offset {
+                LocOffset::I32(o) => i64::from(o),
+                LocOffset::U32(o) => i64::from(o),
+            };
+            let start = base as i64 + offset;
+            let end = start + i64::from(loc.size);
+            if start < 0 || end > self.stack_size as i64 {
+                return Err(SMParserError::Other(
+                    format!("Location {} is outside the frame of {} bytes", i, self.stack_size)));
+            }
+            let (start, end) = (start as usize, end as usize);
+            let mut buf = [0; 8];
+            let new = &mut buf[..loc.size as usize];
+            NativeEndian::write_uint(new, v, new.len());
+            for (j, &b) in new.iter().enumerate() {
+                if written[start + j] && bytes[start + j] != b {
+                    return Err(SMParserError::Other(
+                        format!("Location {} overlaps another with a different value", i)));
+                }
+            }
+            bytes[start..end].copy_from_slice(new);
+            written[start..end].iter_mut().for_each(|w| *w = true);
+        }
+        Ok(NativeFrame{arch: self.arch, bytes, fp_offset: self.fp_offset, regs})
+    }
+}
@@ -0,0 +1,128 @@
+// Copyright (c) 2018 King's College London
+// Created by the Software Development Team <http://soft-dev.org/>
+//
+// The Universal Permissive License (UPL), Version 1.0
+//
+// Subject to the condition set forth below, permission is hereby granted to any
+// person obtaining a copy of this software, associated documentation and/or
+// data (collectively the "Software"), free of charge and under any and all
+// copyright rights in the Software, and any and all patent rights owned or
+// freely licensable by each licensor hereunder covering either (i) the
+// unmodified Software as contributed to or provided by such licensor, or (ii)
+// the Larger Works (as defined below), to deal in both
+//
+// (a) the Software, and
+// (b) any piece of software and/or hardware listed in the lrgrwrks.txt file
+// if one is included with the Software (each a "Larger Work" to which the Software
+// is contributed by such licensors),
+//
+// without restriction, including without limitation the rights to copy, create
+// derivative works of, display, perform, and distribute the Software and make,
+// use, sell, offer for sale, import, export, have made, and have sold the
+// Software and the Larger Work(s), and to sublicense the foregoing rights on
+// either these or other terms.
+//
+// This license is subject to the following condition: The above copyright
+// notice and either this complete permission notice or at a minimum a reference
+// to the UPL must be included in all copies or substantial portions of the
+// Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// A view of the records that LLVM emits for `llvm.experimental.patchpoint`, which split their
+// locations by what the patchpoint's call needs.
+//
+// The comments in this file reference the "Patchpoint" parts of the LLVM documentation found
+// here:
+// https://llvm.org/docs/StackMaps.html#llvm-experimental-patchpoint-intrinsic
+
+use errors::{SMParserError, SMParserResult};
+use {LocKind, SMLiveOut, SMLoc, SMRec};
+
+/// A view of a patchpoint record.
+///
+/// With the `anyregcc` calling convention, the record starts with the location of the return
+/// value (if there is one) and then the locations of the call's arguments, since they may be in
+/// any register. Otherwise, the arguments are where the calling convention says, so the record
+/// only describes the live values that follow them.
+#[derive(Debug)]
+pub struct Patchpoint<'a> {
+    rec: &'a SMRec,
+    ret: Option<&'a SMLoc>,
+    args: &'a [SMLoc],
+    live_values: &'a [SMLoc],
+}
+
+impl<'a> Patchpoint<'a> {
+    /// Interpret `rec` as the record of a patchpoint whose call uses the `anyregcc` calling
+    /// convention and takes `num_args` arguments (the patchpoint's `numArgs` operand).
+    /// `returns_value` says whether the call returns a value, i.e. whether the patchpoint is
+    /// `patchpoint.i64` rather than `patchpoint.void`.
+    pub fn anyreg(rec: &'a SMRec, returns_value: bool, num_args: usize) -> SMParserResult<Self> {
+        let num_ret = usize::from(returns_value);
+        if rec.locs.len() < num_ret + num_args {
+            let msg = format!("Patchpoint record {} has {} locations, too few for a return value \
+                               and {} arguments", rec.id, rec.locs.len(), num_args);
+            return Err(SMParserError::Other(msg));
+        }
+        let (ret, rest) = rec.locs.split_at(num_ret);
+        let ret = ret.first();
+        if let Some(loc) = ret {
+            if loc.kind != LocKind::Register {
+                let msg = format!("Patchpoint record {} has {} for its return value", rec.id, loc);
+                return Err(SMParserError::Other(msg));
+            }
+        }
+        let (args, live_values) = rest.split_at(num_args);
+        Ok(Self{rec, ret, args, live_values})
+    }
+
+    /// Interpret `rec` as the record of a patchpoint whose call uses any calling convention other
+    /// than `anyregcc`. All of its locations are live values.
+    pub fn new(rec: &'a SMRec) -> Self {
+        Self{rec, ret: None, args: &[], live_values: &rec.locs}
+    }
+
+    /// Get the patchpoint ID, which is the ID of the record.
+    pub fn id(&self) -> u64 {
+        self.rec.id
+    }
+
+    /// Get the record itself.
+    pub fn record(&self) -> &'a SMRec {
+        self.rec
+    }
+
+    /// Get the location of the return value. Only `anyregcc` patchpoints that return a value have
+    /// one.
+    pub fn ret(&self) -> Option<&'a SMLoc> {
+        self.ret
+    }
+
+    /// Get the locations of the call's arguments. Only `anyregcc` patchpoints have these.
+    pub fn args(&self) -> &'a [SMLoc] {
+        self.args
+    }
+
+    /// Get the locations of the live values that follow the call's arguments.
+    pub fn live_values(&self) -> &'a [SMLoc] {
+        self.live_values
+    }
+
+    /// Get the registers that are live after the patchpoint.
+    pub fn liveouts(&self) -> &'a [SMLiveOut] {
+        &self.rec.liveouts
+    }
+
+    /// Get the live-out entry of the register that holds the return value, if there is one.
+    pub fn ret_liveout(&self) -> Option<&'a SMLiveOut> {
+        let ret = self.ret?;
+        self.rec.liveouts.iter().find(|l| l.dwarf_reg == ret.dwarf_reg)
+    }
+}
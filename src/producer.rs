@@ -0,0 +1,132 @@
+// Copyright (c) 2018 King's College London
+// Created by the Software Development Team <http://soft-dev.org/>
+//
+// The Universal Permissive License (UPL), Version 1.0
+//
+// Subject to the condition set forth below, permission is hereby granted to any
+// person obtaining a copy of this software, associated documentation and/or
+// data (collectively the "Software"), free of charge and under any and all
+// copyright rights in the Software, and any and all patent rights owned or
+// freely licensable by each licensor hereunder covering either (i) the
+// unmodified Software as contributed to or provided by such licensor, or (ii)
+// the Larger Works (as defined below), to deal in both
+//
+// (a) the Software, and
+// (b) any piece of software and/or hardware listed in the lrgrwrks.txt file
+// if one is included with the Software (each a "Larger Work" to which the Software
+// is contributed by such licensors),
+//
+// without restriction, including without limitation the rights to copy, create
+// derivative works of, display, perform, and distribute the Software and make,
+// use, sell, offer for sale, import, export, have made, and have sold the
+// Software and the Larger Work(s), and to sublicense the foregoing rights on
+// either these or other terms.
+//
+// This license is subject to the following condition: The above copyright
+// notice and either this complete permission notice or at a minimum a reference
+// to the UPL must be included in all copies or substantial portions of the
+// Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Work out which compiler produced a binary, and thus how to decode the parts of the stackmap
+// format that are ambiguous.
+
+use std::borrow::Cow;
+use gimli;
+use backend::ObjectBackend;
+use errors::SMParserResult;
+use QuirkMode;
+
+const COMMENT_SECTION_NAME: &str = ".comment";
+
+// LLVM has encoded small constants as sign-extended integers since this version.
+const SIGNED_CONSTANTS_LLVM_VERSION: (u32, u32) = (3, 5);
+
+/// Read the producer strings of a binary: the `DW_AT_producer` of each DWARF compilation unit,
+/// followed by the strings in the `.comment` section.
+///
+/// In an object file, the relocations that the DWARF string references rely on haven't been
+/// applied, so only the `.comment` section is used.
+pub (crate) fn producers<B>(backend: &B) -> SMParserResult<Vec<String>>
+                            where B: ObjectBackend + ?Sized {
+    let mut producers = Vec::new();
+    let relocated_debug_info = match backend.section_by_name(".debug_info") {
+        Some(idx) => backend.relocations(idx)?.is_empty(),
+        None => false,
+    };
+    if relocated_debug_info {
+        producers.extend(dwarf_producers(backend)?);
+    }
+
+    // The comment section holds NUL-terminated strings, often starting with an empty one.
+    if let Some(idx) = backend.section_by_name(COMMENT_SECTION_NAME) {
+        let data = backend.section_data(idx)?;
+        producers.extend(data.split(|&b| b == 0).filter(|s| !s.is_empty())
+                             .map(|s| String::from_utf8_lossy(s).into_owned()));
+    }
+    Ok(producers)
+}
+
+fn dwarf_producers<B>(backend: &B) -> SMParserResult<Vec<String>>
+                      where B: ObjectBackend + ?Sized {
+    let sections = gimli::DwarfSections::load(|id| -> SMParserResult<Cow<[u8]>> {
+        match backend.section_by_name(id.name()) {
+            Some(idx) => Ok(Cow::Owned(backend.section_data(idx)?)),
+            None => Ok(Cow::Borrowed(&[])),
+        }
+    })?;
+    let dwarf = sections.borrow(|s| gimli::EndianSlice::new(s, gimli::NativeEndian));
+
+    let mut producers = Vec::new();
+    let mut headers = dwarf.units();
+    while let Some(header) = headers.next()? {
+        let unit = dwarf.unit(header)?;
+        let mut entries = unit.entries();
+        if let Some((_, cu)) = entries.next_dfs()? {
+            if let Some(attr) = cu.attr_value(gimli::DW_AT_producer)? {
+                producers.push(dwarf.attr_string(&unit, attr)?.to_string_lossy().into_owned());
+            }
+        }
+    }
+    Ok(producers)
+}
+
+/// Choose how to decode the stackmaps made by one of `producers`. The first LLVM-based producer
+/// decides: we assume that's what generated the machine code.
+pub (crate) fn quirk_mode(producers: &[String]) -> QuirkMode {
+    for p in producers {
+        match llvm_version(p) {
+            Some(Some(v)) if v < SIGNED_CONSTANTS_LLVM_VERSION => return QuirkMode::UnsignedConstants,
+            Some(_) => return QuirkMode::SignedConstants,
+            None => (),
+        }
+    }
+    // Without knowing better, decode in the same way as llvm-readobj.
+    QuirkMode::UnsignedConstants
+}
+
+/// If `producer` is LLVM-based, returns its (major, minor) LLVM version if that can be found.
+fn llvm_version(producer: &str) -> Option<Option<(u32, u32)>> {
+    // e.g. "clang version 14.0.6", "Ubuntu clang version 14.0.0-1ubuntu1" or "LLVM version 3.8".
+    for marker in &["clang version ", "LLVM version "] {
+        if let Some(i) = producer.find(marker) {
+            let mut nums = producer[i + marker.len()..].split(|c: char| !c.is_ascii_digit());
+            let major = nums.next().and_then(|n| n.parse().ok());
+            let minor = nums.next().and_then(|n| n.parse().ok());
+            return Some(major.map(|major| (major, minor.unwrap_or(0))));
+        }
+    }
+    // Other LLVM-based compilers don't say which LLVM they use, but any that emits version 3
+    // stackmaps is new enough.
+    if producer.starts_with("rustc version ") || producer.contains("flang") {
+        return Some(None);
+    }
+    None
+}
@@ -0,0 +1,243 @@
+// Copyright (c) 2018 King's College London
+// Created by the Software Development Team <http://soft-dev.org/>
+//
+// The Universal Permissive License (UPL), Version 1.0
+//
+// Subject to the condition set forth below, permission is hereby granted to any
+// person obtaining a copy of this software, associated documentation and/or
+// data (collectively the "Software"), free of charge and under any and all
+// copyright rights in the Software, and any and all patent rights owned or
+// freely licensable by each licensor hereunder covering either (i) the
+// unmodified Software as contributed to or provided by such licensor, or (ii)
+// the Larger Works (as defined below), to deal in both
+//
+// (a) the Software, and
+// (b) any piece of software and/or hardware listed in the lrgrwrks.txt file
+// if one is included with the Software (each a "Larger Work" to which the Software
+// is contributed by such licensors),
+//
+// without restriction, including without limitation the rights to copy, create
+// derivative works of, display, perform, and distribute the Software and make,
+// use, sell, offer for sale, import, export, have made, and have sold the
+// Software and the Larger Work(s), and to sublicense the foregoing rights on
+// either these or other terms.
+//
+// This license is subject to the following condition: The above copyright
+// notice and either this complete permission notice or at a minimum a reference
+// to the UPL must be included in all copies or substantial portions of the
+// Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Read and resolve the relocations that apply to the stackmap section of an ELF binary.
+
+use std::io::Cursor;
+use byteorder::{ByteOrder, NativeEndian, ReadBytesExt};
+use elf;
+use backend::{BackendReloc, ObjectBackend};
+use errors::{SMParserError, SMParserResult};
+
+// Section types and flags.
+const SHT_RELA: u32 = 4;
+const SHT_REL: u32 = 9;
+const SHF_ALLOC: u64 = 0x2;
+
+// The "relative" relocation type of each architecture we support.
+const R_X86_64_RELATIVE: u32 = 8;
+const R_PPC64_RELATIVE: u32 = 22;
+const R_AARCH64_RELATIVE: u32 = 1027;
+const R_RISCV_RELATIVE: u32 = 3;
+
+// Sizes in bytes.
+const SIZE_REL_ENTRY: u64 = 16;
+const SIZE_RELA_ENTRY: u64 = 24;
+const SIZE_ADDR: u64 = 8;
+
+/// A relocation against the stackmap section of an object file, which the linker applies when
+/// making the final binary. For example, the address of each function entry in an object file is
+/// only known once the linker has applied its relocation.
+#[derive(Debug, Eq, PartialEq)]
+pub struct SMReloc {
+    offset: u64,        // Offset of the relocated field from the start of the stackmap section.
+    kind: u32,          // Architecture-specific relocation type, e.g. `R_X86_64_64`.
+    symbol: String,     // Symbol (or for section symbols, the section) relocated against.
+    addend: i64,
+    sym_shndx: usize,   // Index of the section that the symbol is defined in.
+    sym_value: u64,     // Value of the symbol (its offset in that section, for an object file).
+}
+
+impl SMReloc {
+    /// Get the offset of the relocated field from the start of the stackmap section.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Get the (architecture-specific) relocation type, e.g. 1 for `R_X86_64_64`.
+    pub fn kind(&self) -> u32 {
+        self.kind
+    }
+
+    /// Get the name of the symbol that the relocation refers to. If the relocation refers to a
+    /// section symbol, this is the name of the section instead.
+    pub fn symbol(&self) -> &str {
+        &self.symbol
+    }
+
+    /// Get the addend, which is added to the address of the symbol.
+    pub fn addend(&self) -> i64 {
+        self.addend
+    }
+
+    /// Get the section index and the offset within that section that the relocated field will
+    /// point to. Two fields relocated against different symbols can still refer to the same
+    /// place, e.g. one against `main` and the other against the section symbol of `.text`.
+    pub (crate) fn target(&self) -> (usize, u64) {
+        (self.sym_shndx, self.sym_value.wrapping_add(self.addend as u64))
+    }
+}
+
+/// Read the static relocations that the linker will apply to section number `sec_idx` of
+/// `backend`, whose contents are `data`, and resolve the symbols they refer to. Only object files
+/// have these.
+pub (crate) fn static_relocs<B>(backend: &B, sec_idx: usize, data: &[u8])
+                                -> SMParserResult<Vec<SMReloc>>
+                                where B: ObjectBackend + ?Sized {
+    let raw = backend.relocations(sec_idx)?;
+    if raw.is_empty() {
+        return Ok(Vec::new());
+    }
+    let syms = backend.symbols()?;
+
+    let mut relocs = Vec::new();
+    for r in raw {
+        let addend = if r.implicit_addend {
+            // The addend is stored in the relocated field.
+            match data.get(r.offset as usize..r.offset as usize + SIZE_ADDR as usize) {
+                Some(word) => NativeEndian::read_i64(word),
+                None => return Err(SMParserError::Other(
+                    String::from("Relocation crosses the end of the section"))),
+            }
+        } else {
+            r.addend
+        };
+        let sym = match syms.get(r.symbol) {
+            Some(sym) => sym,
+            None => return Err(SMParserError::Other(
+                String::from("Relocation refers to a non-existent symbol"))),
+        };
+        let symbol = match sym.section {
+            Some(idx) if sym.is_section => backend.section_name(idx).unwrap_or_default(),
+            _ => sym.name.clone(),
+        };
+        relocs.push(SMReloc{offset: r.offset, kind: r.kind, symbol, addend,
+                            sym_shndx: sym.section.unwrap_or(0), sym_value: sym.value});
+    }
+    relocs.sort_by_key(|r| r.offset);
+    Ok(relocs)
+}
+
+/// Read the entries of the (non-allocated) relocation sections of `elf_file` that apply to
+/// section number `sec_idx`.
+pub (crate) fn read_elf_relocs(elf_file: &elf::File, sec_idx: usize)
+                               -> SMParserResult<Vec<BackendReloc>> {
+    let mut relocs = Vec::new();
+    let rel_secs = elf_file.sections.iter().filter(|s| {
+        (s.shdr.shtype.0 == SHT_REL || s.shdr.shtype.0 == SHT_RELA)
+            && s.shdr.flags.0 & SHF_ALLOC == 0 && s.shdr.info as usize == sec_idx
+    });
+    for rsec in rel_secs {
+        let is_rela = rsec.shdr.shtype.0 == SHT_RELA;
+        let entry_size = if is_rela { SIZE_RELA_ENTRY } else { SIZE_REL_ENTRY };
+
+        let mut cursor = Cursor::new(&rsec.data);
+        while cursor.position() + entry_size <= rsec.data.len() as u64 {
+            // Elf64_Rel(a) {
+            //     uint64: r_offset
+            let offset = cursor.read_u64::<NativeEndian>()?;
+            //     uint64: r_info
+            let r_info = cursor.read_u64::<NativeEndian>()?;
+            //     int64: r_addend (only for Elf64_Rela)
+            let addend = if is_rela { cursor.read_i64::<NativeEndian>()? } else { 0 };
+            // }
+            relocs.push(BackendReloc{offset, kind: (r_info & 0xffff_ffff) as u32,
+                                     symbol: (r_info >> 32) as usize, addend,
+                                     implicit_addend: !is_rela});
+        }
+    }
+    Ok(relocs)
+}
+
+/// Apply the dynamic relocations of `elf_file` that fall within `sec` to `data` (the contents of
+/// `sec`), so that the addresses in `data` are the link-time addresses of the things they refer
+/// to.
+///
+/// Only "relative" relocations (e.g. `R_X86_64_RELATIVE`) can be resolved without knowing where
+/// other objects are loaded, and those are the only kind that the linker emits for the function
+/// addresses in a stackmap section. Static linkers usually store the relocated value in the
+/// section as well, but they needn't do so (e.g. LLD leaves a zero there by default). Packed
+/// (RELR) relocations always keep their addend in the section, so need no work here.
+pub (crate) fn apply_dynamic_relocs(elf_file: &elf::File, sec: &elf::types::SectionHeader,
+                                    data: &mut [u8]) -> SMParserResult<()> {
+    // Sections that aren't loaded can't be relocated at load time.
+    if sec.flags.0 & SHF_ALLOC == 0 {
+        return Ok(());
+    }
+
+    // Dynamic relocation sections are allocated, unlike the static relocations of an object file.
+    let rela_secs = elf_file.sections.iter()
+        .filter(|s| s.shdr.shtype.0 == SHT_RELA && s.shdr.flags.0 & SHF_ALLOC != 0);
+    for rsec in rela_secs {
+        apply_relative_relocs(elf_file.ehdr.machine, &rsec.data, sec.addr, data)?;
+    }
+    Ok(())
+}
+
+/// Apply the "relative" relocations from the `Elf64_Rela` table `rela` that fall within `data`,
+/// which is loaded at virtual address `addr`.
+pub (crate) fn apply_relative_relocs(machine: elf::types::Machine, rela: &[u8], addr: u64,
+                                     data: &mut [u8]) -> SMParserResult<()> {
+    let relative = match relative_reloc_type(machine) {
+        Some(r) => r,
+        None => return Ok(()),
+    };
+
+    let mut cursor = Cursor::new(rela);
+    while cursor.position() + SIZE_RELA_ENTRY <= rela.len() as u64 {
+        // Elf64_Rela {
+        //     uint64: r_offset
+        let r_offset = cursor.read_u64::<NativeEndian>()?;
+        //     uint64: r_info
+        let r_info = cursor.read_u64::<NativeEndian>()?;
+        //     int64: r_addend
+        let r_addend = cursor.read_i64::<NativeEndian>()?;
+        // }
+        if r_info & 0xffff_ffff != u64::from(relative)
+            || r_offset < addr || r_offset >= addr + data.len() as u64 {
+            continue;
+        }
+        let off = (r_offset - addr) as usize;
+        match data.get_mut(off..off + SIZE_ADDR as usize) {
+            Some(word) => NativeEndian::write_u64(word, r_addend as u64),
+            None => return Err(SMParserError::Other(
+                String::from("Relocation crosses the end of the stackmap section"))),
+        }
+    }
+    Ok(())
+}
+
+/// The "relative" relocation type of `machine`, if we know it.
+pub (crate) fn relative_reloc_type(machine: elf::types::Machine) -> Option<u32> {
+    match machine {
+        elf::types::EM_X86_64 => Some(R_X86_64_RELATIVE),
+        elf::types::EM_AARCH64 => Some(R_AARCH64_RELATIVE),
+        elf::types::EM_PPC64 => Some(R_PPC64_RELATIVE),
+        elf::types::EM_RISCV => Some(R_RISCV_RELATIVE),
+        _ => None,
+    }
+}
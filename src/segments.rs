@@ -0,0 +1,244 @@
+// Copyright (c) 2018 King's College London
+// Created by the Software Development Team <http://soft-dev.org/>
+//
+// The Universal Permissive License (UPL), Version 1.0
+//
+// Subject to the condition set forth below, permission is hereby granted to any
+// person obtaining a copy of this software, associated documentation and/or
+// data (collectively the "Software"), free of charge and under any and all
+// copyright rights in the Software, and any and all patent rights owned or
+// freely licensable by each licensor hereunder covering either (i) the
+// unmodified Software as contributed to or provided by such licensor, or (ii)
+// the Larger Works (as defined below), to deal in both
+//
+// (a) the Software, and
+// (b) any piece of software and/or hardware listed in the lrgrwrks.txt file
+// if one is included with the Software (each a "Larger Work" to which the Software
+// is contributed by such licensors),
+//
+// without restriction, including without limitation the rights to copy, create
+// derivative works of, display, perform, and distribute the Software and make,
+// use, sell, offer for sale, import, export, have made, and have sold the
+// Software and the Larger Work(s), and to sublicense the foregoing rights on
+// either these or other terms.
+//
+// This license is subject to the following condition: The above copyright
+// notice and either this complete permission notice or at a minimum a reference
+// to the UPL must be included in all copies or substantial portions of the
+// Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Locate the stackmap blob of an ELF binary using only its program headers and dynamic section.
+//
+// Tools such as `sstrip` remove the section header table from a binary, but the stackmap data is
+// still loaded as part of a segment. LLVM labels the blob with the `__LLVM_StackMaps` symbol, so
+// if that symbol has been exported (e.g. with `-rdynamic`) we can still find the blob through the
+// dynamic symbol table.
+
+use std::io::Cursor;
+use byteorder::{ByteOrder, NativeEndian, ReadBytesExt};
+use elf;
+use errors::{SMParserError, SMParserResult};
+use reloc::apply_relative_relocs;
+
+const STACKMAP_SYMBOL_NAME: &[u8] = b"__LLVM_StackMaps";
+
+// Dynamic section tags.
+const DT_NULL: i64 = 0;
+const DT_HASH: i64 = 4;
+const DT_STRTAB: i64 = 5;
+const DT_SYMTAB: i64 = 6;
+const DT_RELA: i64 = 7;
+const DT_RELASZ: i64 = 8;
+const DT_STRSZ: i64 = 10;
+const DT_GNU_HASH: i64 = 0x6fff_fef5;
+
+// Sizes in bytes.
+const SIZE_DYN_ENTRY: u64 = 16;
+const SIZE_SYMBOL: usize = 24;
+
+/// The parts of the dynamic section that we need. Addresses are virtual addresses.
+#[derive(Default)]
+struct Dynamic {
+    symtab: Option<u64>,
+    strtab: Option<u64>,
+    strsz: u64,
+    hash: Option<u64>,
+    gnu_hash: Option<u64>,
+    rela: Option<u64>,
+    relasz: u64,
+}
+
+/// Copy the stackmap data out of the segment that holds it, with any "relative" dynamic
+/// relocations applied. `bytes` is the whole of the binary that `elf_file` was parsed from.
+///
+/// Without section headers there's no way to tell how big the stackmap data is, so the returned
+/// data runs from the start of the blob to the end of the segment's file image. Only the first
+/// blob found in there can be trusted.
+pub (crate) fn stackmap_from_segments(elf_file: &elf::File, bytes: &[u8])
+                                      -> SMParserResult<Vec<u8>> {
+    let dyn_ph = match elf_file.phdrs.iter().find(|p| p.progtype == elf::types::PT_DYNAMIC) {
+        Some(p) => p,
+        None => return Err(SMParserError::NoStackMapSection),
+    };
+    let dynamic = read_dynamic(slice(bytes, dyn_ph.offset, dyn_ph.filesz)?)?;
+    let (symtab, strtab) = match (dynamic.symtab, dynamic.strtab) {
+        (Some(symtab), Some(strtab)) => (symtab, strtab),
+        _ => return Err(SMParserError::NoStackMapSection),
+    };
+
+    // The dynamic section doesn't record the size of the symbol table. It has to be worked out
+    // from one of the hash tables instead.
+    let num_syms = if let Some(hash) = dynamic.hash {
+        hash_num_syms(bytes, offset_of(elf_file, hash)?)?
+    } else if let Some(gnu_hash) = dynamic.gnu_hash {
+        gnu_hash_num_syms(bytes, offset_of(elf_file, gnu_hash)?)?
+    } else {
+        return Err(SMParserError::Other(String::from("Dynamic section has no hash table")));
+    };
+
+    let syms = slice(bytes, offset_of(elf_file, symtab)?, num_syms * SIZE_SYMBOL as u64)?;
+    let strs = slice(bytes, offset_of(elf_file, strtab)?, dynamic.strsz)?;
+    let addr = match find_symbol(syms, strs, STACKMAP_SYMBOL_NAME)? {
+        Some(addr) => addr,
+        None => return Err(SMParserError::NoStackMapSection),
+    };
+
+    let load = load_segment(elf_file, addr)?;
+    let start = offset_of(elf_file, addr)?;
+    let end = checked(load.offset.checked_add(load.filesz))?;
+    let mut data = slice(bytes, start, end - start)?.to_vec();
+    if let Some(rela) = dynamic.rela {
+        let rela = slice(bytes, offset_of(elf_file, rela)?, dynamic.relasz)?;
+        apply_relative_relocs(elf_file.ehdr.machine, rela, addr, &mut data)?;
+    }
+    Ok(data)
+}
+
+/// Read the entries of the dynamic section that we care about.
+fn read_dynamic(data: &[u8]) -> SMParserResult<Dynamic> {
+    let mut dynamic = Dynamic::default();
+    let mut cursor = Cursor::new(data);
+    while cursor.position() + SIZE_DYN_ENTRY <= data.len() as u64 {
+        // Elf64_Dyn {
+        //     int64: d_tag
+        let tag = cursor.read_i64::<NativeEndian>()?;
+        //     uint64: d_val or d_ptr
+        let val = cursor.read_u64::<NativeEndian>()?;
+        // }
+        match tag {
+            DT_NULL => break,
+            DT_HASH => dynamic.hash = Some(val),
+            DT_STRTAB => dynamic.strtab = Some(val),
+            DT_SYMTAB => dynamic.symtab = Some(val),
+            DT_RELA => dynamic.rela = Some(val),
+            DT_RELASZ => dynamic.relasz = val,
+            DT_STRSZ => dynamic.strsz = val,
+            DT_GNU_HASH => dynamic.gnu_hash = Some(val),
+            _ => (),
+        }
+    }
+    Ok(dynamic)
+}
+
+/// The number of symbols in a SysV hash table is the number of entries in its chain array.
+fn hash_num_syms(bytes: &[u8], off: u64) -> SMParserResult<u64> {
+    // uint32: nbucket
+    // uint32: nchain
+    let header = slice(bytes, off, 8)?;
+    Ok(u64::from(NativeEndian::read_u32(&header[4..])))
+}
+
+/// A GNU hash table doesn't record the number of symbols, but the symbols in the last non-empty
+/// bucket have the highest indices, and the chain entry of the final symbol has its low bit set.
+fn gnu_hash_num_syms(bytes: &[u8], off: u64) -> SMParserResult<u64> {
+    let header = slice(bytes, off, 16)?;
+    // uint32: nbuckets
+    let nbuckets = u64::from(NativeEndian::read_u32(header));
+    // uint32: symoffset
+    let symoffset = u64::from(NativeEndian::read_u32(&header[4..]));
+    // uint32: bloom_size
+    let bloom_size = u64::from(NativeEndian::read_u32(&header[8..]));
+    // uint32: bloom_shift
+    // uint64[bloom_size]: bloom
+    // uint32[nbuckets]: buckets
+    let buckets_off = checked((off + 16).checked_add(bloom_size * 8))?;
+    let buckets = slice(bytes, buckets_off, nbuckets * 4)?;
+    let last = buckets.chunks(4).map(NativeEndian::read_u32).max().unwrap_or(0);
+    if u64::from(last) < symoffset {
+        // All the buckets are empty, so only the unhashed symbols exist.
+        return Ok(symoffset);
+    }
+    // uint32[]: chain, indexed from symoffset
+    let chain_off = checked(buckets_off.checked_add(nbuckets * 4))?;
+    let mut idx = u64::from(last);
+    loop {
+        let entry_off = checked(chain_off.checked_add((idx - symoffset) * 4))?;
+        let chain = NativeEndian::read_u32(slice(bytes, entry_off, 4)?);
+        if chain & 1 != 0 {
+            return Ok(idx + 1);
+        }
+        idx += 1;
+    }
+}
+
+/// Look up the value of the symbol called `name` in the symbol table `syms`.
+fn find_symbol(syms: &[u8], strs: &[u8], name: &[u8]) -> SMParserResult<Option<u64>> {
+    for sym in syms.chunks(SIZE_SYMBOL) {
+        // Elf64_Sym {
+        //     uint32: st_name
+        let st_name = NativeEndian::read_u32(sym) as usize;
+        //     uint8: st_info
+        //     uint8: st_other
+        //     uint16: st_shndx
+        //     uint64: st_value
+        let st_value = NativeEndian::read_u64(&sym[8..]);
+        //     uint64: st_size
+        // }
+        let sym_name = match strs.get(st_name..) {
+            Some(s) => &s[..s.iter().position(|&b| b == 0).unwrap_or(s.len())],
+            None => return Err(SMParserError::Other(String::from("Symbol name is out of bounds"))),
+        };
+        if sym_name == name {
+            return Ok(Some(st_value));
+        }
+    }
+    Ok(None)
+}
+
+/// Find the loadable segment containing the virtual address `addr`.
+fn load_segment(elf_file: &elf::File, addr: u64) -> SMParserResult<&elf::types::ProgramHeader> {
+    for p in elf_file.phdrs.iter().filter(|p| p.progtype == elf::types::PT_LOAD) {
+        if addr >= p.vaddr && addr < checked(p.vaddr.checked_add(p.filesz))? {
+            return Ok(p);
+        }
+    }
+    Err(SMParserError::Other(format!("Address {:#x} is not in any loadable segment", addr)))
+}
+
+/// Convert the virtual address `addr` into an offset into the file.
+fn offset_of(elf_file: &elf::File, addr: u64) -> SMParserResult<u64> {
+    let load = load_segment(elf_file, addr)?;
+    checked(load.offset.checked_add(addr - load.vaddr))
+}
+
+/// Turn the result of checked arithmetic on values read from the file into an error if it
+/// overflowed.
+fn checked(v: Option<u64>) -> SMParserResult<u64> {
+    v.ok_or_else(|| SMParserError::Other(String::from("ELF file has an out of range address")))
+}
+
+/// Bounds-checked sub-slice of `bytes`.
+fn slice(bytes: &[u8], off: u64, len: u64) -> SMParserResult<&[u8]> {
+    match off.checked_add(len) {
+        Some(end) if end <= bytes.len() as u64 => Ok(&bytes[off as usize..end as usize]),
+        _ => Err(SMParserError::Other(String::from("ELF file is truncated"))),
+    }
+}
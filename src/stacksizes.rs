@@ -0,0 +1,172 @@
+// Copyright (c) 2018 King's College London
+// Created by the Software Development Team <http://soft-dev.org/>
+//
+// The Universal Permissive License (UPL), Version 1.0
+//
+// Subject to the condition set forth below, permission is hereby granted to any
+// person obtaining a copy of this software, associated documentation and/or
+// data (collectively the "Software"), free of charge and under any and all
+// copyright rights in the Software, and any and all patent rights owned or
+// freely licensable by each licensor hereunder covering either (i) the
+// unmodified Software as contributed to or provided by such licensor, or (ii)
+// the Larger Works (as defined below), to deal in both
+//
+// (a) the Software, and
+// (b) any piece of software and/or hardware listed in the lrgrwrks.txt file
+// if one is included with the Software (each a "Larger Work" to which the Software
+// is contributed by such licensors),
+//
+// without restriction, including without limitation the rights to copy, create
+// derivative works of, display, perform, and distribute the Software and make,
+// use, sell, offer for sale, import, export, have made, and have sold the
+// Software and the Larger Work(s), and to sublicense the foregoing rights on
+// either these or other terms.
+//
+// This license is subject to the following condition: The above copyright
+// notice and either this complete permission notice or at a minimum a reference
+// to the UPL must be included in all copies or substantial portions of the
+// Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Parse the `.stack_sizes` section that LLVM emits when given `-stack-size-section` (or Clang's
+// `-fstack-size-section`), so that it can be checked against the stack sizes in the stackmaps.
+//
+// The section is a sequence of function entries, each of which is:
+//
+//   uint64: FunctionAddress
+//   ULEB128: StackSize
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+use byteorder::{NativeEndian, ReadBytesExt};
+use backend::{ElfBackend, ObjectBackend};
+use errors::SMParserResult;
+use reloc::static_relocs;
+use util::read_uleb128;
+use StackMapParser;
+
+const STACK_SIZES_SECTION_NAME: &str = ".stack_sizes";
+
+/// Represents a single function entry in the stack sizes section.
+#[derive(Debug, Eq, PartialEq)]
+pub struct StackSizeEntry {
+    addr: u64,
+    stack_size: u64,
+    key: (usize, u64),  // Where the function is, resolving any relocation (see `SMReloc::target()`).
+}
+
+impl StackSizeEntry {
+    /// Get the function address.
+    pub fn addr(&self) -> u64 {
+        self.addr
+    }
+
+    /// Get the size of the stack of the function.
+    pub fn stack_size(&self) -> u64 {
+        self.stack_size
+    }
+}
+
+/// A disagreement between the stack sizes section and the stackmap section. Addresses are
+/// link-time addresses, or in an object file, offsets into the function's section.
+#[derive(Debug, Eq, PartialEq)]
+pub enum StackSizeMismatch {
+    /// The two sections give different stack sizes for the function.
+    Differs { addr: u64, stackmap: u64, stack_sizes: u64 },
+    /// The function is in the stackmap section but not the stack sizes section.
+    NotInStackSizes { addr: u64 },
+    /// The function is in the stack sizes section but not the stackmap section.
+    NotInStackMaps { addr: u64 },
+}
+
+/// Parser for the stack sizes sections of an ELF binary.
+pub struct StackSizesParser {
+    entries: Vec<StackSizeEntry>,
+}
+
+impl StackSizesParser {
+    /// Make a parser for the stack sizes sections of the ELF binary at `path`.
+    pub fn new(path: &Path) -> SMParserResult<Self> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+
+    /// Make a parser for the stack sizes sections of the ELF binary held in `bytes`.
+    ///
+    /// An object file has one stack sizes section per text section, so all of them are read. A
+    /// binary without any has no entries.
+    pub fn from_bytes(bytes: &[u8]) -> SMParserResult<Self> {
+        let backend = ElfBackend::from_bytes(bytes)?;
+        let mut entries = Vec::new();
+        for (idx, sec) in backend.elf_file().sections.iter().enumerate() {
+            if sec.shdr.name != STACK_SIZES_SECTION_NAME {
+                continue;
+            }
+            let data = backend.section_data(idx)?;
+            let relocs = static_relocs(&backend, idx, &data)?
+                .iter().map(|r| (r.offset(), r.target())).collect::<HashMap<_, _>>();
+
+            let mut cursor = Cursor::new(data.as_slice());
+            while cursor.position() < data.len() as u64 {
+                let field_off = cursor.position();
+                // uint64: FunctionAddress
+                let addr = cursor.read_u64::<NativeEndian>()?;
+                // ULEB128: StackSize
+                let stack_size = read_uleb128(&mut cursor)?;
+                let key = relocs.get(&field_off).cloned().unwrap_or((0, addr));
+                entries.push(StackSizeEntry{addr, stack_size, key});
+            }
+        }
+        Ok(Self{entries})
+    }
+
+    /// Returns the number of function entries in the stack sizes sections.
+    pub fn num_funcs(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Make an iterator over the function entries in the stack sizes sections.
+    pub fn iter_functions(&self) -> impl Iterator<Item = &StackSizeEntry> {
+        self.entries.iter()
+    }
+
+    /// Compare the stack size of each function in `sm`, which must have been made from the same
+    /// binary, with the stack sizes section. Mismatches are reported in the order of
+    /// `StackMapParser::iter_functions()`, followed by any functions that only the stack sizes
+    /// section has.
+    ///
+    /// LLVM doesn't put functions with a dynamically-sized stack in the stack sizes section, and
+    /// gives them a stack size of `u64::MAX` in the stackmap section, so such functions aren't
+    /// reported as missing.
+    pub fn check(&self, sm: &StackMapParser) -> SMParserResult<Vec<StackSizeMismatch>> {
+        let by_key = self.entries.iter().map(|e| (e.key, e)).collect::<HashMap<_, _>>();
+        let mut seen = HashSet::new();
+        let mut mismatches = Vec::new();
+        for (idx, func) in sm.iter_functions().enumerate() {
+            let func = func?;
+            let key = sm.func_target(idx, &func);
+            seen.insert(key);
+            match by_key.get(&key) {
+                Some(e) if e.stack_size != func.stack_size() => {
+                    mismatches.push(StackSizeMismatch::Differs{
+                        addr: key.1, stackmap: func.stack_size(), stack_sizes: e.stack_size});
+                }
+                Some(_) => (),
+                None if func.stack_size() == u64::MAX => (),
+                None => mismatches.push(StackSizeMismatch::NotInStackSizes{addr: key.1}),
+            }
+        }
+        for e in self.entries.iter().filter(|e| !seen.contains(&e.key)) {
+            mismatches.push(StackSizeMismatch::NotInStackMaps{addr: e.key.1});
+        }
+        Ok(mismatches)
+    }
+}
@@ -0,0 +1,153 @@
+// Copyright (c) 2018 King's College London
+// Created by the Software Development Team <http://soft-dev.org/>
+//
+// The Universal Permissive License (UPL), Version 1.0
+//
+// Subject to the condition set forth below, permission is hereby granted to any
+// person obtaining a copy of this software, associated documentation and/or
+// data (collectively the "Software"), free of charge and under any and all
+// copyright rights in the Software, and any and all patent rights owned or
+// freely licensable by each licensor hereunder covering either (i) the
+// unmodified Software as contributed to or provided by such licensor, or (ii)
+// the Larger Works (as defined below), to deal in both
+//
+// (a) the Software, and
+// (b) any piece of software and/or hardware listed in the lrgrwrks.txt file
+// if one is included with the Software (each a "Larger Work" to which the Software
+// is contributed by such licensors),
+//
+// without restriction, including without limitation the rights to copy, create
+// derivative works of, display, perform, and distribute the Software and make,
+// use, sell, offer for sale, import, export, have made, and have sold the
+// Software and the Larger Work(s), and to sublicense the foregoing rights on
+// either these or other terms.
+//
+// This license is subject to the following condition: The above copyright
+// notice and either this complete permission notice or at a minimum a reference
+// to the UPL must be included in all copies or substantial portions of the
+// Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// A view of the records that LLVM emits for `gc.statepoint`, which split their locations into
+// fixed groups.
+//
+// The comments in this file reference the "Stack Map Format" section of the LLVM statepoint
+// documentation found here:
+// https://llvm.org/docs/Statepoints.html#stack-map-format
+
+use std::slice;
+use errors::{SMParserError, SMParserResult};
+use {LocKind, LocOffset, SMLoc, SMRec};
+
+// The constant locations that every statepoint record starts with.
+const NUM_HEADER_LOCS: usize = 3;
+
+/// A view of a statepoint record, i.e. one emitted for a call to `gc.statepoint`.
+#[derive(Debug)]
+pub struct Statepoint<'a> {
+    rec: &'a SMRec,
+    calling_conv: u64,
+    flags: u64,
+    num_deopt: usize,
+}
+
+impl<'a> Statepoint<'a> {
+    /// Interpret `rec` as a statepoint record, checking that its locations are laid out as one.
+    pub fn new(rec: &'a SMRec) -> SMParserResult<Self> {
+        // The locations are:
+        //   Constant: Calling Convention
+        //   Constant: Flags
+        //   Constant: NumDeoptArgs
+        //   Location[NumDeoptArgs]: Deopt Args
+        //   (Location, Location)[]: (Base, Derived) GC Pointers
+        if rec.locs.len() < NUM_HEADER_LOCS {
+            return Err(bad_statepoint(rec, "has too few locations"));
+        }
+        let calling_conv = header_constant(rec, 0)?;
+        let flags = header_constant(rec, 1)?;
+        let num_deopt = header_constant(rec, 2)?;
+        let rest = (rec.locs.len() - NUM_HEADER_LOCS) as u64;
+        if num_deopt > rest {
+            return Err(bad_statepoint(rec, &format!("claims {} deopt arguments but has only {} more \
+                                                     locations", num_deopt, rest)));
+        }
+        if !(rest - num_deopt).is_multiple_of(2) {
+            return Err(bad_statepoint(rec, "has an unpaired GC pointer"));
+        }
+        Ok(Self{rec, calling_conv, flags, num_deopt: num_deopt as usize})
+    }
+
+    /// Get the statepoint ID, which is the ID of the record.
+    pub fn id(&self) -> u64 {
+        self.rec.id
+    }
+
+    /// Get the record itself.
+    pub fn record(&self) -> &'a SMRec {
+        self.rec
+    }
+
+    /// Get the calling convention of the call (e.g. 0 for the C calling convention).
+    pub fn calling_conv(&self) -> u64 {
+        self.calling_conv
+    }
+
+    /// Get the flags of the statepoint (e.g. 1 for a GC transition).
+    pub fn flags(&self) -> u64 {
+        self.flags
+    }
+
+    /// Get the locations of the deopt operands, in the order that they were given.
+    pub fn deopt_args(&self) -> &'a [SMLoc] {
+        &self.rec.locs[NUM_HEADER_LOCS..NUM_HEADER_LOCS + self.num_deopt]
+    }
+
+    /// Returns the number of (base, derived) GC pointer pairs.
+    pub fn num_gc_pairs(&self) -> usize {
+        self.gc_locs().len() / 2
+    }
+
+    /// Make an iterator over the (base, derived) GC pointer pairs. A pointer that isn't derived
+    /// from another is paired with itself.
+    pub fn gc_pairs(&self) -> GCPairIterator<'a> {
+        GCPairIterator{chunks: self.gc_locs().chunks(2)}
+    }
+
+    fn gc_locs(&self) -> &'a [SMLoc] {
+        &self.rec.locs[NUM_HEADER_LOCS + self.num_deopt..]
+    }
+}
+
+/// An iterator over the (base, derived) GC pointer pairs of a statepoint.
+pub struct GCPairIterator<'a> {
+    chunks: slice::Chunks<'a, SMLoc>,
+}
+
+impl<'a> Iterator for GCPairIterator<'a> {
+    type Item = (&'a SMLoc, &'a SMLoc);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.chunks.next().map(|pair| (&pair[0], &pair[1]))
+    }
+}
+
+/// Read the value of header location `idx` of `rec`, which must be a small constant.
+fn header_constant(rec: &SMRec, idx: usize) -> SMParserResult<u64> {
+    let loc = &rec.locs[idx];
+    match (&loc.kind, &loc.offset) {
+        (LocKind::Constant, LocOffset::U32(v)) => Ok(u64::from(*v)),
+        (LocKind::Constant, LocOffset::I32(v)) if *v >= 0 => Ok(*v as u64),
+        _ => Err(bad_statepoint(rec, &format!("has {} for header location #{}", loc, idx + 1))),
+    }
+}
+
+fn bad_statepoint(rec: &SMRec, msg: &str) -> SMParserError {
+    SMParserError::Other(format!("Record {} isn't a statepoint: it {}", rec.id, msg))
+}
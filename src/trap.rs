@@ -0,0 +1,266 @@
+// Copyright (c) 2018 King's College London
+// Created by the Software Development Team <http://soft-dev.org/>
+//
+// The Universal Permissive License (UPL), Version 1.0
+//
+// Subject to the condition set forth below, permission is hereby granted to any
+// person obtaining a copy of this software, associated documentation and/or
+// data (collectively the "Software"), free of charge and under any and all
+// copyright rights in the Software, and any and all patent rights owned or
+// freely licensable by each licensor hereunder covering either (i) the
+// unmodified Software as contributed to or provided by such licensor, or (ii)
+// the Larger Works (as defined below), to deal in both
+//
+// (a) the Software, and
+// (b) any piece of software and/or hardware listed in the lrgrwrks.txt file
+// if one is included with the Software (each a "Larger Work" to which the Software
+// is contributed by such licensors),
+//
+// without restriction, including without limitation the rights to copy, create
+// derivative works of, display, perform, and distribute the Software and make,
+// use, sell, offer for sale, import, export, have made, and have sold the
+// Software and the Larger Work(s), and to sublicense the foregoing rights on
+// either these or other terms.
+//
+// This license is subject to the following condition: The above copyright
+// notice and either this complete permission notice or at a minimum a reference
+// to the UPL must be included in all copies or substantial portions of the
+// Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Deoptimise when a guard in compiled code traps. A guard is an `int3` or `ud2` instruction
+// immediately followed by the stackmap that describes the live values at that point, so the
+// record's address is the one after the trapping instruction. The handler finds the record and
+// asks a user callback how to carry on, letting it evaluate the record's locations. Only x86_64
+// Linux is supported.
+//
+// Everything done in the signal handler must be async-signal-safe, so it doesn't allocate: the
+// registers are read into an array on the stack, and the locations are only evaluated when the
+// callback asks for them.
+
+use std::ffi::c_void;
+use std::io;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::thread;
+use libc;
+use deopt::DeoptValue;
+use errors::{SMParserError, SMParserResult};
+use eval::{eval_loc, MemoryReader};
+use index::{IndexedRecord, RecordIndex};
+use ucontext::{set_ucontext_pc, ucontext_registers, UCONTEXT_NUM_REGS};
+
+// The signals raised by `int3` and `ud2` respectively.
+const SIGNALS: [libc::c_int; 2] = [libc::SIGTRAP, libc::SIGILL];
+
+// The encoding of `ud2`. The kernel reports the address of a `ud2` that raised `SIGILL`, but the
+// address after an `int3` that raised `SIGTRAP`.
+const UD2: [u8; 2] = [0x0f, 0x0b];
+
+// The index of the instruction pointer in a register snapshot.
+const DWARF_RIP: usize = 16;
+
+/// What a trap handler's callback wants to happen once it returns.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TrapAction {
+    /// Carry on after the trapping instruction, at the record's address.
+    Resume,
+    /// Carry on at the given address, with the registers unchanged.
+    ResumeAt(u64),
+    /// Pass the signal on to the handler that was installed before, or if there was none, let
+    /// it take its default action (which kills the process).
+    Unhandled,
+}
+
+/// A trap at a stackmap record, as given to a `TrapHandler`'s callback.
+#[derive(Debug)]
+pub struct Trap<'a> {
+    signal: libc::c_int,
+    regs: &'a [u64; UCONTEXT_NUM_REGS],
+    rec: IndexedRecord<'a>,
+}
+
+impl<'a> Trap<'a> {
+    /// Get the number of the signal that the trap raised: `SIGTRAP` or `SIGILL`.
+    pub fn signal(&self) -> libc::c_int {
+        self.signal
+    }
+
+    /// Get the registers of the thread when it trapped, indexed by DWARF register number (see
+    /// `ucontext_registers()`). Entry 16 is where the kernel says that the thread stopped, which
+    /// isn't always the record's address.
+    pub fn registers(&self) -> &'a [u64] {
+        self.regs
+    }
+
+    /// Get the record that the thread trapped at.
+    pub fn record(&self) -> IndexedRecord<'a> {
+        self.rec
+    }
+
+    /// Get the ID of the record.
+    pub fn id(&self) -> u64 {
+        self.rec.record().id
+    }
+
+    /// Evaluate the record's locations in the trapping frame, one at a time, giving the live
+    /// values in the order of the locations. This doesn't allocate, unless a location can't be
+    /// evaluated, which gives an error.
+    pub fn values(&self) -> impl Iterator<Item = SMParserResult<DeoptValue>> + 'a {
+        let (consts, regs) = (self.rec.constants(), &self.regs[..]);
+        self.rec.record().locs.iter().map(move |loc| {
+            Ok(DeoptValue::new(eval_loc(loc, consts, regs, &LocalMemory)?, loc.size))
+        })
+    }
+}
+
+type Callback = dyn Fn(&Trap<'_>) -> TrapAction + Send + Sync;
+
+struct State {
+    index: RecordIndex,
+    callback: Box<Callback>,
+    old: [libc::sigaction; 2],  // The actions that we replaced, in the order of `SIGNALS`.
+}
+
+// The state of the installed handler, if any. Signal handlers are per-process, so there can only
+// be one.
+static STATE: AtomicPtr<State> = AtomicPtr::new(ptr::null_mut());
+
+// The number of signal handlers using the state. It's incremented before `STATE` is read, so once
+// `STATE` has been cleared, the state can be freed when this drops to 0.
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+/// A process-wide handler for `SIGTRAP` and `SIGILL` that calls a user callback when a thread
+/// traps at a stackmap record. The previous handlers are put back when this is dropped.
+///
+/// Traps that aren't at a record are passed on as if the callback had returned
+/// `TrapAction::Unhandled`.
+pub struct TrapHandler {
+    state: *mut State,
+}
+
+impl TrapHandler {
+    /// Install the handler, looking records up in `index`, whose addresses must be runtime
+    /// addresses (see `StackMapParser::set_load_bias()`). `callback` is run in the signal handler
+    /// of whichever thread trapped, so should only do what's safe there, and mustn't panic or
+    /// jump out of the handler: dropping the `TrapHandler` waits for every callback to return.
+    ///
+    /// Only one handler can be installed at a time.
+    pub fn install<F>(index: RecordIndex, callback: F) -> SMParserResult<Self>
+                      where F: Fn(&Trap<'_>) -> TrapAction + Send + Sync + 'static {
+        // `sigaction` is plain old data, so all zeroes is a valid (default) action.
+        let old = unsafe { [std::mem::zeroed(), std::mem::zeroed()] };
+        let state = Box::into_raw(Box::new(State{index, callback: Box::new(callback), old}));
+        if STATE.compare_exchange(ptr::null_mut(), state, Ordering::SeqCst, Ordering::SeqCst)
+                .is_err() {
+            drop(unsafe { Box::from_raw(state) });
+            return Err(SMParserError::Other(String::from("A trap handler is already installed")));
+        }
+
+        let mut act: libc::sigaction = unsafe { std::mem::zeroed() };
+        act.sa_sigaction = on_signal as *const () as usize;
+        act.sa_flags = libc::SA_SIGINFO;
+        for (i, &sig) in SIGNALS.iter().enumerate() {
+            if unsafe { libc::sigaction(sig, &act, &mut (*state).old[i]) } != 0 {
+                // Only the actions that we replaced are put back.
+                let err = io::Error::last_os_error();
+                unsafe { release(state, i) };
+                return Err(err.into());
+            }
+        }
+        Ok(TrapHandler{state})
+    }
+}
+
+impl Drop for TrapHandler {
+    fn drop(&mut self) {
+        unsafe { release(self.state, SIGNALS.len()) };
+    }
+}
+
+/// Put back the actions of the first `n` signals in `SIGNALS`, and free `state` once no signal
+/// handler is using it.
+unsafe fn release(state: *mut State, n: usize) {
+    for (i, &sig) in SIGNALS.iter().enumerate().take(n) {
+        libc::sigaction(sig, &(*state).old[i], ptr::null_mut());
+    }
+    STATE.store(ptr::null_mut(), Ordering::SeqCst);
+    while ACTIVE.load(Ordering::SeqCst) != 0 {
+        thread::yield_now();
+    }
+    drop(Box::from_raw(state));
+}
+
+extern "C" fn on_signal(sig: libc::c_int, info: *mut libc::siginfo_t, uc: *mut c_void) {
+    ACTIVE.fetch_add(1, Ordering::SeqCst);
+    let unhandled = unsafe { handle(sig, uc) };
+    // The old action may never return (e.g. the default action kills the process), so the state
+    // is let go of first.
+    ACTIVE.fetch_sub(1, Ordering::SeqCst);
+    if let Some(old) = unhandled {
+        unsafe { chain(sig, info, uc, &old) };
+    }
+}
+
+/// Handle the signal `sig` that interrupted the thread with context `uc`. Returns the action to
+/// pass it on to if it wasn't handled.
+unsafe fn handle(sig: libc::c_int, uc: *mut c_void) -> Option<libc::sigaction> {
+    let state = STATE.load(Ordering::SeqCst).as_ref()?;
+    let regs = ucontext_registers(uc);
+    let mut pc = regs[DWARF_RIP];
+    if sig == libc::SIGILL && ptr::read_unaligned(pc as *const [u8; 2]) == UD2 {
+        pc += UD2.len() as u64;
+    }
+
+    let action = match state.index.lookup(pc) {
+        Some(rec) => (state.callback)(&Trap{signal: sig, regs: &regs, rec}),
+        None => TrapAction::Unhandled,
+    };
+    match action {
+        TrapAction::Resume => set_ucontext_pc(uc, pc),
+        TrapAction::ResumeAt(addr) => set_ucontext_pc(uc, addr),
+        TrapAction::Unhandled => {
+            let i = SIGNALS.iter().position(|&s| s == sig).unwrap();
+            return Some(state.old[i]);
+        },
+    }
+    None
+}
+
+/// Pass a signal on to the action `old` that our handler replaced.
+unsafe fn chain(sig: libc::c_int, info: *mut libc::siginfo_t, uc: *mut c_void,
+                old: &libc::sigaction) {
+    if old.sa_flags & libc::SA_SIGINFO != 0 {
+        let f: extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut c_void)
+            = std::mem::transmute(old.sa_sigaction);
+        f(sig, info, uc);
+    } else if old.sa_sigaction == libc::SIG_DFL {
+        // The signal is blocked while we handle it, so this is delivered, with the default
+        // action, once we return.
+        libc::signal(sig, libc::SIG_DFL);
+        libc::raise(sig);
+    } else if old.sa_sigaction != libc::SIG_IGN {
+        let f: extern "C" fn(libc::c_int) = std::mem::transmute(old.sa_sigaction);
+        f(sig);
+    }
+}
+
+/// Reads the memory of the current process.
+pub (crate) struct LocalMemory;
+
+impl MemoryReader for LocalMemory {
+    fn read_memory(&self, addr: u64, buf: &mut [u8]) -> io::Result<()> {
+        if addr == 0 {
+            return Err(io::Error::other("Read from a null address"));
+        }
+        unsafe { ptr::copy_nonoverlapping(addr as *const u8, buf.as_mut_ptr(), buf.len()) };
+        Ok(())
+    }
+}
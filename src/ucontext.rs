@@ -0,0 +1,140 @@
+// Copyright (c) 2018 King's College London
+// Created by the Software Development Team <http://soft-dev.org/>
+//
+// The Universal Permissive License (UPL), Version 1.0
+//
+// Subject to the condition set forth below, permission is hereby granted to any
+// person obtaining a copy of this software, associated documentation and/or
+// data (collectively the "Software"), free of charge and under any and all
+// copyright rights in the Software, and any and all patent rights owned or
+// freely licensable by each licensor hereunder covering either (i) the
+// unmodified Software as contributed to or provided by such licensor, or (ii)
+// the Larger Works (as defined below), to deal in both
+//
+// (a) the Software, and
+// (b) any piece of software and/or hardware listed in the lrgrwrks.txt file
+// if one is included with the Software (each a "Larger Work" to which the Software
+// is contributed by such licensors),
+//
+// without restriction, including without limitation the rights to copy, create
+// derivative works of, display, perform, and distribute the Software and make,
+// use, sell, offer for sale, import, export, have made, and have sold the
+// Software and the Larger Work(s), and to sublicense the foregoing rights on
+// either these or other terms.
+//
+// This license is subject to the following condition: The above copyright
+// notice and either this complete permission notice or at a minimum a reference
+// to the UPL must be included in all copies or substantial portions of the
+// Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Read the registers of an interrupted thread from the `ucontext_t` that the kernel passes to a
+// signal handler installed with `SA_SIGINFO`, so that stackmap locations can be evaluated inside
+// the handler, e.g. when a guard traps. Only x86_64 Linux is supported.
+
+use std::ffi::c_void;
+use std::iter;
+use libc;
+use unwind::RegisterSet;
+
+/// The number of registers in a snapshot made by `ucontext_registers()`: enough for DWARF
+/// registers 0 to 66 (the x87 status word).
+pub const UCONTEXT_NUM_REGS: usize = 67;
+
+// The DWARF register numbers of the general-purpose registers, in the order of the `REG_*`
+// indices into `mcontext_t::gregs` (`REG_R8` to `REG_RSP`).
+const GREG_DWARF_REGS: [u16; 16] = [
+    8, 9, 10, 11, 12, 13, 14, 15,   // r8-r15
+    5,                              // rdi
+    4,                              // rsi
+    6,                              // rbp
+    3,                              // rbx
+    1,                              // rdx
+    0,                              // rax
+    2,                              // rcx
+    7,                              // rsp
+];
+
+// DWARF register numbers of the rest of the state that we read.
+const DWARF_RIP: u16 = 16;  // The return address column.
+const DWARF_XMM0: u16 = 17;
+const NUM_XMM: u16 = 16;
+const DWARF_RFLAGS: u16 = 49;
+const DWARF_MXCSR: u16 = 64;
+const DWARF_FCW: u16 = 65;
+const DWARF_FSW: u16 = 66;
+
+/// Read the registers from `ucontext`, the third argument of a signal handler installed with
+/// `SA_SIGINFO`, into an array indexed by DWARF register number. The array is a
+/// `RegisterContext`, and making it doesn't allocate, so it's fit for use in the handler itself.
+///
+/// Entry 16 (the return address column) holds the interrupted instruction pointer, which for a
+/// trap such as `int3` is the address after the trapping instruction. As well as the
+/// general-purpose registers and the flags, the SSE registers (of which only the low 64 bits are
+/// kept, enough for a `double`), MXCSR and the x87 control and status words are read, if the
+/// kernel saved them. Registers that weren't saved, and numbers that aren't registers, read as 0.
+///
+/// # Safety
+///
+/// `ucontext` must point to a valid `ucontext_t`, such as the one given to the handler.
+pub unsafe fn ucontext_registers(ucontext: *const c_void) -> [u64; UCONTEXT_NUM_REGS] {
+    let mcontext = &(*(ucontext as *const libc::ucontext_t)).uc_mcontext;
+    let gregs = &mcontext.gregs;
+    let mut regs = [0; UCONTEXT_NUM_REGS];
+    regs[DWARF_RIP as usize] = gregs[libc::REG_RIP as usize] as u64;
+    for (i, &dwarf_reg) in GREG_DWARF_REGS.iter().enumerate() {
+        regs[dwarf_reg as usize] = gregs[libc::REG_R8 as usize + i] as u64;
+    }
+    regs[DWARF_RFLAGS as usize] = gregs[libc::REG_EFL as usize] as u64;
+
+    if let Some(fpregs) = mcontext.fpregs.as_ref() {
+        for (i, xmm) in fpregs._xmm.iter().enumerate() {
+            let low = u64::from(xmm.element[0]) | u64::from(xmm.element[1]) << 32;
+            regs[DWARF_XMM0 as usize + i] = low;
+        }
+        regs[DWARF_MXCSR as usize] = u64::from(fpregs.mxcsr);
+        regs[DWARF_FCW as usize] = u64::from(fpregs.cwd);
+        regs[DWARF_FSW as usize] = u64::from(fpregs.swd);
+    }
+    regs
+}
+
+impl RegisterSet {
+    /// Read the registers from `ucontext`, as `ucontext_registers()` does, into a register set
+    /// that only has the registers that the kernel saved. The register set's `pc` is the
+    /// interrupted instruction pointer.
+    ///
+    /// Unlike `ucontext_registers()`, this allocates, so isn't safe to use in a signal handler.
+    ///
+    /// # Safety
+    ///
+    /// `ucontext` must point to a valid `ucontext_t`, such as the one given to the handler.
+    pub unsafe fn from_ucontext(ucontext: *const c_void) -> Self {
+        let snapshot = ucontext_registers(ucontext);
+        let mut regs = RegisterSet::new(snapshot[DWARF_RIP as usize]);
+        let fp_saved = !(*(ucontext as *const libc::ucontext_t)).uc_mcontext.fpregs.is_null();
+        let fp_regs = (DWARF_XMM0..DWARF_XMM0 + NUM_XMM).chain([DWARF_MXCSR, DWARF_FCW, DWARF_FSW]);
+        let saved = GREG_DWARF_REGS.iter().cloned().chain(iter::once(DWARF_RFLAGS))
+            .chain(fp_regs.filter(|_| fp_saved));
+        for reg in saved {
+            regs.set_register(reg, snapshot[reg as usize]);
+        }
+        regs
+    }
+}
+
+/// Make the thread interrupted with `ucontext` resume at `pc` once the signal handler returns.
+///
+/// # Safety
+///
+/// `ucontext` must point to a valid `ucontext_t`, such as the one given to the handler.
+pub (crate) unsafe fn set_ucontext_pc(ucontext: *mut c_void, pc: u64) {
+    (*(ucontext as *mut libc::ucontext_t)).uc_mcontext.gregs[libc::REG_RIP as usize] = pc as i64;
+}
@@ -0,0 +1,439 @@
+// Copyright (c) 2018 King's College London
+// Created by the Software Development Team <http://soft-dev.org/>
+//
+// The Universal Permissive License (UPL), Version 1.0
+//
+// Subject to the condition set forth below, permission is hereby granted to any
+// person obtaining a copy of this software, associated documentation and/or
+// data (collectively the "Software"), free of charge and under any and all
+// copyright rights in the Software, and any and all patent rights owned or
+// freely licensable by each licensor hereunder covering either (i) the
+// unmodified Software as contributed to or provided by such licensor, or (ii)
+// the Larger Works (as defined below), to deal in both
+//
+// (a) the Software, and
+// (b) any piece of software and/or hardware listed in the lrgrwrks.txt file
+// if one is included with the Software (each a "Larger Work" to which the Software
+// is contributed by such licensors),
+//
+// without restriction, including without limitation the rights to copy, create
+// derivative works of, display, perform, and distribute the Software and make,
+// use, sell, offer for sale, import, export, have made, and have sold the
+// Software and the Larger Work(s), and to sublicense the foregoing rights on
+// either these or other terms.
+//
+// This license is subject to the following condition: The above copyright
+// notice and either this complete permission notice or at a minimum a reference
+// to the UPL must be included in all copies or substantial portions of the
+// Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Unwind the native stack using the call frame information (CFI) in a binary's `.eh_frame`
+// section, which, unlike following frame pointers, also works for functions compiled without a
+// frame pointer.
+//
+// For each instruction, the CFI gives a rule for computing the Canonical Frame Address (CFA),
+// which is the value of the stack pointer just before the call into the function, and rules for
+// recovering the caller's values of the registers that the function saved (and of the return
+// address) relative to the CFA.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use byteorder::{ByteOrder, NativeEndian};
+use gimli::{self, UnwindSection};
+use arch::Arch;
+use backend::{ElfBackend, ObjectBackend};
+use errors::{SMParserError, SMParserResult};
+use eval::{MemoryReader, RegisterContext, RegisterContextMut};
+use index::{IndexedRecord, RecordIndex};
+
+const EH_FRAME_SECTION_NAME: &str = ".eh_frame";
+const EH_FRAME_HDR_SECTION_NAME: &str = ".eh_frame_hdr";
+const TEXT_SECTION_NAME: &str = ".text";
+
+// The depth at which a walk gives up, unless told otherwise.
+const DEFAULT_MAX_DEPTH: usize = 1024;
+
+type EhFrame<'e> = gimli::EhFrame<gimli::EndianSlice<'e, gimli::NativeEndian>>;
+type Fde<'e> = gimli::FrameDescriptionEntry<gimli::EndianSlice<'e, gimli::NativeEndian>>;
+
+/// The registers of a frame, by DWARF register number, and the address it's executing at.
+///
+/// Unwinding a frame gives the registers of its caller, but only those that the callee must
+/// preserve (and the stack pointer) are known, as the rest may have been overwritten by the time
+/// the callee returns.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RegisterSet {
+    pc: u64,
+    regs: HashMap<u16, u64>,
+    is_caller: bool,    // True if `pc` is a return address.
+}
+
+impl RegisterSet {
+    /// Make a register set for a frame executing at `pc`, with no known registers.
+    pub fn new(pc: u64) -> Self {
+        Self{pc, regs: HashMap::new(), is_caller: false}
+    }
+
+    /// Get the address that the frame is executing at. For a frame found by unwinding, this is
+    /// the return address of its callee.
+    pub fn pc(&self) -> u64 {
+        self.pc
+    }
+
+    /// Get the value of the register numbered `dwarf_reg`, if it's known.
+    pub fn register(&self, dwarf_reg: u16) -> Option<u64> {
+        self.regs.get(&dwarf_reg).cloned()
+    }
+
+    /// Set the value of the register numbered `dwarf_reg`.
+    pub fn set_register(&mut self, dwarf_reg: u16, value: u64) {
+        self.regs.insert(dwarf_reg, value);
+    }
+
+    /// Forget the value of the register numbered `dwarf_reg`.
+    pub fn clear_register(&mut self, dwarf_reg: u16) {
+        self.regs.remove(&dwarf_reg);
+    }
+
+    /// Make an iterator over the known registers, as `(dwarf_reg, value)` pairs in no particular
+    /// order.
+    pub fn iter_registers(&self) -> impl Iterator<Item = (u16, u64)> + '_ {
+        self.regs.iter().map(|(&r, &v)| (r, v))
+    }
+}
+
+impl RegisterContext for RegisterSet {
+    fn read_register(&self, dwarf_reg: u16) -> Option<u64> {
+        self.register(dwarf_reg)
+    }
+}
+
+impl RegisterContextMut for RegisterSet {
+    fn write_register(&mut self, dwarf_reg: u16, value: u64) -> bool {
+        self.set_register(dwarf_reg, value);
+        true
+    }
+}
+
+/// Unwinds frames using the call frame information of an ELF binary.
+pub struct CfiUnwinder {
+    arch: Arch,
+    eh_frame: Vec<u8>,
+    eh_frame_hdr: Option<Vec<u8>>,
+    bases: gimli::BaseAddresses,
+    load_bias: u64,
+}
+
+impl CfiUnwinder {
+    /// Make an unwinder for the ELF binary at `path`.
+    pub fn new(path: &Path) -> SMParserResult<Self> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+
+    /// Make an unwinder for the ELF binary held in `bytes`, which must have an `.eh_frame`
+    /// section. If there's an `.eh_frame_hdr` section, its table is used to find the CFI of an
+    /// address, rather than searching `.eh_frame`.
+    pub fn from_bytes(bytes: &[u8]) -> SMParserResult<Self> {
+        let backend = ElfBackend::from_bytes(bytes)?;
+        let arch = match backend.arch() {
+            Some(arch) => arch,
+            None => return Err(SMParserError::Other(
+                String::from("Can't unwind a binary of an unknown architecture"))),
+        };
+        let section = |name| -> SMParserResult<Option<(Vec<u8>, u64)>> {
+            match backend.section_by_name(name) {
+                Some(idx) => Ok(Some((backend.section_data(idx)?,
+                                      backend.elf_file().sections[idx].shdr.addr))),
+                None => Ok(None),
+            }
+        };
+
+        let (eh_frame, eh_frame_addr) = match section(EH_FRAME_SECTION_NAME)? {
+            Some(sec) => sec,
+            None => return Err(SMParserError::Other(
+                format!("No {} section to unwind with", EH_FRAME_SECTION_NAME))),
+        };
+        let mut bases = gimli::BaseAddresses::default().set_eh_frame(eh_frame_addr);
+        let eh_frame_hdr = match section(EH_FRAME_HDR_SECTION_NAME)? {
+            Some((data, addr)) => {
+                bases = bases.set_eh_frame_hdr(addr);
+                Some(data)
+            },
+            None => None,
+        };
+        if let Some(idx) = backend.section_by_name(TEXT_SECTION_NAME) {
+            bases = bases.set_text(backend.elf_file().sections[idx].shdr.addr);
+        }
+        Ok(Self{arch, eh_frame, eh_frame_hdr, bases, load_bias: 0})
+    }
+
+    /// Get the architecture of the binary.
+    pub fn arch(&self) -> Arch {
+        self.arch
+    }
+
+    /// Set the load bias of the binary (see `StackMapParser::set_load_bias()`), so that the
+    /// addresses in register sets are runtime addresses. The bias defaults to 0.
+    pub fn set_load_bias(&mut self, load_bias: u64) {
+        self.load_bias = load_bias;
+    }
+
+    /// Returns the load bias (see `set_load_bias()`).
+    pub fn load_bias(&self) -> u64 {
+        self.load_bias
+    }
+
+    /// Work out the Canonical Frame Address of the frame with registers `regs`: the value of the
+    /// stack pointer in its caller.
+    pub fn cfa(&self, regs: &RegisterSet) -> SMParserResult<u64> {
+        let eh_frame = gimli::EhFrame::new(&self.eh_frame, gimli::NativeEndian);
+        let mut ctx = gimli::UnwindContext::new();
+        let (_, _, row) = self.unwind_row(&eh_frame, &mut ctx, regs)?;
+        self.cfa_of(row.cfa(), regs)
+    }
+
+    /// Unwind the frame with registers `regs`, whose stack can be read through `mem`, giving the
+    /// registers of its caller. Returns `None` if the frame is the outermost one, i.e. the CFI
+    /// says that it has no return address, or the return address is null.
+    ///
+    /// The caller's stack pointer is the CFA, and the registers that the frame saved are read from
+    /// its stack. Other callee-saved registers are unchanged, and the rest become unknown. CFI
+    /// that uses DWARF expressions isn't supported.
+    pub fn unwind<M>(&self, regs: &RegisterSet, mem: &M) -> SMParserResult<Option<RegisterSet>>
+                     where M: MemoryReader + ?Sized {
+        let eh_frame = gimli::EhFrame::new(&self.eh_frame, gimli::NativeEndian);
+        let mut ctx = gimli::UnwindContext::new();
+        let (fde, addr, row) = self.unwind_row(&eh_frame, &mut ctx, regs)?;
+        let ra_reg = fde.cie().return_address_register();
+        let cfa = self.cfa_of(row.cfa(), regs)?;
+
+        let recover = |reg: gimli::Register| -> SMParserResult<Option<u64>> {
+            Ok(match row.register(reg) {
+                // gimli doesn't tell an undefined register from one without a rule, and the
+                // latter is how a saved register that hasn't been touched yet is described.
+                gimli::RegisterRule::Undefined | gimli::RegisterRule::SameValue =>
+                    regs.register(reg.0),
+                gimli::RegisterRule::Offset(off) => {
+                    let mut buf = [0; 8];
+                    mem.read_memory(cfa.wrapping_add(off as u64), &mut buf)?;
+                    Some(NativeEndian::read_u64(&buf))
+                },
+                gimli::RegisterRule::ValOffset(off) => Some(cfa.wrapping_add(off as u64)),
+                gimli::RegisterRule::Register(other) => regs.register(other.0),
+                gimli::RegisterRule::Constant(val) => Some(val),
+                _ => return Err(SMParserError::Other(
+                    format!("Unsupported CFI rule for register {} at {:#x}", reg.0, regs.pc))),
+            })
+        };
+
+        // The return address register may be a pseudo-register (e.g. on x86_64), which is never
+        // in `regs`, so if it has no rule, this is the outermost frame. It may also be a real
+        // register (e.g. the link register on AArch64), which holds the return address until a
+        // function saves it, unless the CFI says outright that it's undefined (as it does for
+        // the entry point of a program).
+        if let gimli::RegisterRule::Undefined = row.register(ra_reg) {
+            if self.is_undefined(&eh_frame, &fde, addr, ra_reg)? {
+                return Ok(None);
+            }
+        }
+        let pc = match recover(ra_reg)? {
+            Some(0) | None => return Ok(None),
+            Some(pc) => pc,
+        };
+
+        let sp = self.arch.stack_pointer();
+        let mut caller = RegisterSet{pc, regs: HashMap::new(), is_caller: true};
+        for &reg in self.arch.callee_saved().iter().filter(|&&r| r != sp) {
+            if let Some(val) = recover(gimli::Register(reg))? {
+                caller.set_register(reg, val);
+            }
+        }
+        caller.set_register(sp, cfa);
+        Ok(Some(caller))
+    }
+
+    /// Walk the stack from the frame with registers `start`, looking up records in `index` and
+    /// reading the stack through `mem`.
+    pub fn walk<'a, M>(&'a self, index: &'a RecordIndex, mem: &'a M, start: RegisterSet)
+                       -> CfiStackWalker<'a, M>
+                       where M: MemoryReader + ?Sized {
+        CfiStackWalker{unwinder: self, index, mem, max_depth: DEFAULT_MAX_DEPTH, depth: 0,
+                       next: Some(Ok(start))}
+    }
+
+    /// Find the CFI row that applies to the frame with registers `regs`, along with the FDE that
+    /// it comes from and the (link-time) address that it was looked up with.
+    fn unwind_row<'e, 'c>(&'e self, eh_frame: &EhFrame<'e>,
+                          ctx: &'c mut gimli::UnwindContext<usize>, regs: &RegisterSet)
+                          -> SMParserResult<(Fde<'e>, u64, &'c gimli::UnwindTableRow<usize>)> {
+        // A return address is the instruction after the call, which may belong to the next
+        // function (if the call doesn't return), or be after the caller's CFI has changed, so we
+        // look up the call instruction itself.
+        let mut addr = regs.pc.wrapping_sub(self.load_bias);
+        if regs.is_caller {
+            addr = addr.wrapping_sub(1);
+        }
+
+        let hdr = match self.eh_frame_hdr {
+            Some(ref data) => {
+                let hdr = gimli::EhFrameHdr::new(data, gimli::NativeEndian);
+                Some(hdr.parse(&self.bases, self.arch.pointer_width() as u8)?)
+            },
+            None => None,
+        };
+        let fde = match hdr.as_ref().and_then(|h| h.table()) {
+            Some(table) =>
+                table.fde_for_address(eh_frame, &self.bases, addr, gimli::EhFrame::cie_from_offset),
+            None => eh_frame.fde_for_address(&self.bases, addr, gimli::EhFrame::cie_from_offset),
+        };
+        let fde = match fde {
+            Ok(fde) => fde,
+            Err(gimli::Error::NoUnwindInfoForAddress) => return Err(SMParserError::Other(
+                format!("No CFI for address {:#x}", regs.pc))),
+            Err(e) => return Err(e.into()),
+        };
+        let row = fde.unwind_info_for_address(eh_frame, &self.bases, ctx, addr)?;
+        Ok((fde, addr, row))
+    }
+
+    /// Does the CFI of `fde` explicitly make `reg` undefined at `addr`? gimli's rows can't tell
+    /// that apart from a register without a rule, so this replays the instructions.
+    fn is_undefined(&self, eh_frame: &EhFrame, fde: &Fde, addr: u64, reg: gimli::Register)
+                    -> SMParserResult<bool> {
+        let cie = fde.cie();
+        let mut undefined = false;
+        let mut instrs = cie.instructions(eh_frame, &self.bases);
+        while let Some(instr) = instrs.next()? {
+            if let Some(u) = undefines(&instr, reg) {
+                undefined = u;
+            }
+        }
+
+        let initial = undefined;
+        let mut remembered = Vec::new();
+        let mut loc = fde.initial_address();
+        let mut instrs = fde.instructions(eh_frame, &self.bases);
+        while let Some(instr) = instrs.next()? {
+            let next_loc = match instr {
+                gimli::CallFrameInstruction::AdvanceLoc{delta} =>
+                    loc.wrapping_add(u64::from(delta) * cie.code_alignment_factor()),
+                gimli::CallFrameInstruction::SetLoc{address} => address,
+                gimli::CallFrameInstruction::Restore{register} if register == reg => {
+                    undefined = initial;
+                    continue;
+                },
+                gimli::CallFrameInstruction::RememberState => {
+                    remembered.push(undefined);
+                    continue;
+                },
+                gimli::CallFrameInstruction::RestoreState => {
+                    undefined = remembered.pop().unwrap_or(initial);
+                    continue;
+                },
+                _ => {
+                    if let Some(u) = undefines(&instr, reg) {
+                        undefined = u;
+                    }
+                    continue;
+                },
+            };
+            if next_loc > addr {
+                break;
+            }
+            loc = next_loc;
+        }
+        Ok(undefined)
+    }
+
+    fn cfa_of(&self, rule: &gimli::CfaRule<usize>, regs: &RegisterSet) -> SMParserResult<u64> {
+        match *rule {
+            gimli::CfaRule::RegisterAndOffset{register, offset} => match regs.register(register.0) {
+                Some(val) => Ok(val.wrapping_add(offset as u64)),
+                None => Err(SMParserError::Other(
+                    format!("The CFA at {:#x} needs the unknown register {}", regs.pc, register.0))),
+            },
+            _ => Err(SMParserError::Other(
+                format!("Unsupported CFA rule at {:#x}", regs.pc))),
+        }
+    }
+}
+
+/// Returns whether `instr` makes `reg` undefined (`Some(true)`), gives it some other rule
+/// (`Some(false)`), or leaves it alone (`None`).
+fn undefines(instr: &gimli::CallFrameInstruction<usize>, reg: gimli::Register) -> Option<bool> {
+    use gimli::CallFrameInstruction::*;
+    match *instr {
+        Undefined{register} if register == reg => Some(true),
+        SameValue{register} | Offset{register, ..} | OffsetExtendedSf{register, ..}
+            | ValOffset{register, ..} | ValOffsetSf{register, ..} | Expression{register, ..}
+            | ValExpression{register, ..} if register == reg => Some(false),
+        Register{dest_register, ..} if dest_register == reg => Some(false),
+        _ => None,
+    }
+}
+
+/// An iterator over the frames of a stack, from the innermost outwards, unwinding each with a
+/// `CfiUnwinder`. Each frame comes with the record at its `pc`, if there is one.
+///
+/// The walk ends at the outermost frame (see `CfiUnwinder::unwind()`). A caller whose stack
+/// pointer isn't above its callee's is an error, as is a stack deeper than the depth limit. The
+/// error is given after the last frame that could be found, and ends the walk.
+pub struct CfiStackWalker<'a, M: MemoryReader + ?Sized> {
+    unwinder: &'a CfiUnwinder,
+    index: &'a RecordIndex,
+    mem: &'a M,
+    max_depth: usize,
+    depth: usize,
+    next: Option<SMParserResult<RegisterSet>>,
+}
+
+impl<'a, M: MemoryReader + ?Sized> CfiStackWalker<'a, M> {
+    /// Give up after `max_depth` frames, rather than the default of 1024.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Find the registers of the caller of the frame with registers `regs`, if there is one.
+    fn caller(&self, regs: &RegisterSet) -> SMParserResult<Option<RegisterSet>> {
+        let caller = self.unwinder.unwind(regs, self.mem)?;
+        if let Some(ref caller) = caller {
+            // The stack grows down, so each caller's frame must be above its callee's.
+            let sp = self.unwinder.arch.stack_pointer();
+            if let (Some(callee_sp), Some(caller_sp)) = (regs.register(sp), caller.register(sp)) {
+                if caller_sp <= callee_sp {
+                    return Err(SMParserError::Other(
+                        format!("The frame at {:#x} unwinds to a stack pointer {:#x} below its \
+                                 own {:#x}", regs.pc, caller_sp, callee_sp)));
+                }
+            }
+        }
+        Ok(caller)
+    }
+}
+
+impl<'a, M: MemoryReader + ?Sized> Iterator for CfiStackWalker<'a, M> {
+    type Item = SMParserResult<(RegisterSet, Option<IndexedRecord<'a>>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let regs = itry!(self.next.take()?);
+        if self.depth == self.max_depth {
+            return Some(Err(SMParserError::Other(
+                format!("The stack is deeper than {} frames", self.max_depth))));
+        }
+        self.depth += 1;
+        // Any error finding the caller is kept for the next call, so that this frame isn't lost.
+        self.next = self.caller(&regs).transpose();
+        let rec = self.index.lookup(regs.pc);
+        Some(Ok((regs, rec)))
+    }
+}
@@ -36,36 +36,45 @@
 // SOFTWARE.
 
 use std::io::{self, Cursor, Seek, SeekFrom};
-use {SMParserResult, SMParserError};
-use elf;
+use byteorder::ReadBytesExt;
+use SMParserResult;
 
-const STACKMAP_SECTION_NAME: &str = ".llvm_stackmaps";
+pub (crate) const STACKMAP_SECTION_NAME: &str = ".llvm_stackmaps";
 
-pub (crate) fn cursor_from_elf(elf_file: &elf::File, start_pos: u64)
-                               -> SMParserResult<Cursor<&Vec<u8>>> {
-    let sec_res = elf_file.get_section(STACKMAP_SECTION_NAME);
-
-    if let Some(sec) = sec_res {
-        println!("sec addr: {:x}", sec.shdr.offset);
-        let mut cursor = Cursor::new(&sec.data);
-        cursor.seek(SeekFrom::Start(start_pos))?;
-        Ok(cursor)
-    } else {
-        Err(SMParserError::Other(String::from("Can't find stackmap section in binary")))
-    }
+/// Make a cursor over `data` starting at `start_pos`.
+pub (crate) fn cursor_at(data: &[u8], start_pos: u64) -> Cursor<&[u8]> {
+    let mut cursor = Cursor::new(data);
+    cursor.set_position(start_pos);
+    cursor
 }
 
 /// Skip the cursor forward the specified number of bytes.
-pub (crate) fn cursor_skip(cursor: &mut Cursor<&Vec<u8>>, bytes: i64) -> io::Result<u64> {
+pub (crate) fn cursor_skip(cursor: &mut Cursor<&[u8]>, bytes: i64) -> io::Result<u64> {
     cursor.seek(SeekFrom::Current(bytes))
 }
 
 /// Align the cursor to the next 8-byte boundary.
-pub (crate) fn cursor_align8(cursor: &mut Cursor<&Vec<u8>>) -> io::Result<u64> {
+pub (crate) fn cursor_align8(cursor: &mut Cursor<&[u8]>) -> io::Result<u64> {
     let pad = (8 - (cursor.position() % 8)) % 8;
     cursor_skip(cursor, pad as i64)
 }
 
+/// Read an unsigned LEB128-encoded integer.
+pub (crate) fn read_uleb128(cursor: &mut Cursor<&[u8]>) -> SMParserResult<u64> {
+    let mut val = 0;
+    let mut shift = 0;
+    loop {
+        let byte = cursor.read_u8()?;
+        if shift < 64 {
+            val |= u64::from(byte & 0x7f) << shift;
+        }
+        if byte & 0x80 == 0 {
+            return Ok(val);
+        }
+        shift += 7;
+    }
+}
+
 /// A macro to assist in early returns of `Some<Err>` in `Iterator::next()` implementations.
 macro_rules! itry {
     ($x:expr) => {
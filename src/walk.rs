@@ -0,0 +1,199 @@
+// Copyright (c) 2018 King's College London
+// Created by the Software Development Team <http://soft-dev.org/>
+//
+// The Universal Permissive License (UPL), Version 1.0
+//
+// Subject to the condition set forth below, permission is hereby granted to any
+// person obtaining a copy of this software, associated documentation and/or
+// data (collectively the "Software"), free of charge and under any and all
+// copyright rights in the Software, and any and all patent rights owned or
+// freely licensable by each licensor hereunder covering either (i) the
+// unmodified Software as contributed to or provided by such licensor, or (ii)
+// the Larger Works (as defined below), to deal in both
+//
+// (a) the Software, and
+// (b) any piece of software and/or hardware listed in the lrgrwrks.txt file
+// if one is included with the Software (each a "Larger Work" to which the Software
+// is contributed by such licensors),
+//
+// without restriction, including without limitation the rights to copy, create
+// derivative works of, display, perform, and distribute the Software and make,
+// use, sell, offer for sale, import, export, have made, and have sold the
+// Software and the Larger Work(s), and to sublicense the foregoing rights on
+// either these or other terms.
+//
+// This license is subject to the following condition: The above copyright
+// notice and either this complete permission notice or at a minimum a reference
+// to the UPL must be included in all copies or substantial portions of the
+// Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Walk the native stack by following the chain of saved frame pointers, finding the stackmap
+// record (if any) at the return address of each frame.
+//
+// On both x86_64 and AArch64, a function that keeps a frame pointer saves its caller's frame
+// pointer at `[fp]` and the return address at `[fp + 8]`. On x86_64, these are pushed by the call
+// and the prologue, so the caller's stack pointer once the function returns is `fp + 16`. On
+// AArch64, the pair is stored at the bottom of the callee-save area (e.g. `stp x29, x30, [sp,
+// #-48]!`), so how far above it the caller's stack pointer is varies from function to function,
+// and only the CFI can say (see `CfiUnwinder`).
+
+use std::ops::Range;
+use byteorder::{ByteOrder, NativeEndian};
+use arch::Arch;
+use errors::{SMParserError, SMParserResult};
+use eval::{MemoryReader, RegisterContext};
+use index::{IndexedRecord, RecordIndex};
+
+// The depth at which a walk gives up, unless told otherwise.
+const DEFAULT_MAX_DEPTH: usize = 1024;
+
+// Offsets from a frame pointer.
+const OFFS_SAVED_FP: u64 = 0;
+const OFFS_RETURN_ADDR: u64 = 8;
+const OFFS_CALLER_SP: u64 = 16;     // Only on x86_64.
+
+/// A frame found by a stack walk.
+///
+/// A frame can act as the `RegisterContext` of its locations, but it only knows the values of the
+/// stack and frame pointers, and not even the stack pointer of the callers found on AArch64.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Frame {
+    arch: Arch,
+    pc: u64,
+    sp: Option<u64>,
+    fp: u64,
+}
+
+impl Frame {
+    /// Describe a frame of `arch` executing at `pc`, with the given stack and frame pointers.
+    pub fn new(arch: Arch, pc: u64, sp: u64, fp: u64) -> Self {
+        Self{arch, pc, sp: Some(sp), fp}
+    }
+
+    /// Get the address that the frame is executing at. For all but the first frame of a walk,
+    /// this is a return address.
+    pub fn pc(&self) -> u64 {
+        self.pc
+    }
+
+    /// Get the value of the stack pointer, if it's known. The frame pointers of AArch64 don't give
+    /// the stack pointers of the callers that a walk finds (see the `CfiUnwinder` for that).
+    pub fn sp(&self) -> Option<u64> {
+        self.sp
+    }
+
+    /// Get the value of the frame pointer.
+    pub fn fp(&self) -> u64 {
+        self.fp
+    }
+}
+
+impl RegisterContext for Frame {
+    fn read_register(&self, dwarf_reg: u16) -> Option<u64> {
+        if dwarf_reg == self.arch.stack_pointer() {
+            self.sp
+        } else if dwarf_reg == self.arch.frame_pointer() {
+            Some(self.fp)
+        } else {
+            None
+        }
+    }
+}
+
+/// An iterator over the frames of a stack, from the innermost outwards, following the chain of
+/// frame pointers. Each frame comes with the record at its `pc`, if there is one.
+///
+/// The walk ends at a null frame pointer or return address. A frame pointer that lies outside the
+/// stack, or that doesn't move towards the base of the stack, is an error, as is a stack deeper
+/// than the depth limit. The error is given after the last frame that could be found, and ends
+/// the walk.
+pub struct StackWalker<'a, M: MemoryReader + ?Sized> {
+    index: &'a RecordIndex,
+    mem: &'a M,
+    stack: Range<u64>,
+    max_depth: usize,
+    depth: usize,
+    next: Option<SMParserResult<Frame>>,
+}
+
+impl<'a, M: MemoryReader + ?Sized> StackWalker<'a, M> {
+    /// Walk the stack from the frame `start`, looking up records in `index` and reading the stack
+    /// through `mem`. `stack` is the range of addresses that the thread's stack occupies. Only
+    /// x86_64 and AArch64 are supported.
+    pub fn new(index: &'a RecordIndex, mem: &'a M, start: Frame, stack: Range<u64>)
+               -> SMParserResult<Self> {
+        match start.arch {
+            Arch::X86_64 | Arch::AArch64 => (),
+            arch => return Err(SMParserError::Other(
+                format!("Can't walk the frame pointers of {:?}", arch))),
+        }
+        Ok(Self{index, mem, stack, max_depth: DEFAULT_MAX_DEPTH, depth: 0, next: Some(Ok(start))})
+    }
+
+    /// Give up after `max_depth` frames, rather than the default of 1024.
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Find the frame that `frame` returns to, if there is one.
+    fn caller(&self, frame: &Frame) -> SMParserResult<Option<Frame>> {
+        let fp = frame.fp;
+        if fp == 0 {
+            return Ok(None);
+        }
+        // `fp` may have been read from the stack, so the end of its frame record can overflow.
+        let record_end = fp.checked_add(OFFS_RETURN_ADDR + 8);
+        if !fp.is_multiple_of(8) || fp < self.stack.start
+            || record_end.is_none_or(|end| end > self.stack.end) {
+            return Err(SMParserError::Other(
+                format!("Frame pointer {:#x} isn't in the stack {:#x}..{:#x}",
+                        fp, self.stack.start, self.stack.end)));
+        }
+        let pc = self.read_word(fp + OFFS_RETURN_ADDR)?;
+        if pc == 0 {
+            return Ok(None);
+        }
+        let caller_fp = self.read_word(fp + OFFS_SAVED_FP)?;
+        // The stack grows down, so each caller's frame must be above its callee's.
+        if caller_fp != 0 && caller_fp <= fp {
+            return Err(SMParserError::Other(
+                format!("Frame pointer {:#x} is saved below its callee's at {:#x}", caller_fp, fp)));
+        }
+        let sp = match frame.arch {
+            Arch::X86_64 => Some(fp + OFFS_CALLER_SP),
+            _ => None,
+        };
+        Ok(Some(Frame{arch: frame.arch, pc, sp, fp: caller_fp}))
+    }
+
+    fn read_word(&self, addr: u64) -> SMParserResult<u64> {
+        let mut buf = [0; 8];
+        self.mem.read_memory(addr, &mut buf)?;
+        Ok(NativeEndian::read_u64(&buf))
+    }
+}
+
+impl<'a, M: MemoryReader + ?Sized> Iterator for StackWalker<'a, M> {
+    type Item = SMParserResult<(Frame, Option<IndexedRecord<'a>>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let frame = itry!(self.next.take()?);
+        if self.depth == self.max_depth {
+            return Some(Err(SMParserError::Other(
+                format!("The stack is deeper than {} frames", self.max_depth))));
+        }
+        self.depth += 1;
+        // Any error finding the caller is kept for the next call, so that this frame isn't lost.
+        self.next = self.caller(&frame).transpose();
+        Some(Ok((frame, self.index.lookup(frame.pc))))
+    }
+}
@@ -0,0 +1,22 @@
+; Two functions with stackmaps, built for Windows. Unlike the other test inputs,
+; the object and image built from this are checked in (see ../README.md).
+
+target triple = "x86_64-pc-windows-msvc"
+
+declare void @llvm.experimental.stackmap(i64, i32, ...)
+
+define i64 @add(i64 %a, i64 %b) {
+entry:
+  %c = add i64 %a, %b
+  call void (i64, i32, ...) @llvm.experimental.stackmap(i64 1, i32 0, i64 %a, i64 %c, i64 7)
+  ret i64 %c
+}
+
+define i32 @mainCRTStartup() {
+entry:
+  %x = alloca i64, align 8
+  store i64 3, i64* %x
+  %r = call i64 @add(i64 1, i64 2)
+  call void (i64, i32, ...) @llvm.experimental.stackmap(i64 2, i32 0, i64* %x, i64 %r, i64 4294967296)
+  ret i32 0
+}
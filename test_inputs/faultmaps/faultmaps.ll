@@ -0,0 +1,37 @@
+; Two implicit null checks: one guarding a load and one guarding a store.
+; Build with `llc -enable-implicit-null-checks` to get a faultmap section.
+
+target datalayout = "e-m:e-i64:64-f80:128-n8:16:32:64-S128"
+target triple = "x86_64-pc-linux-gnu"
+
+define i32 @load(i32* %x) {
+entry:
+  %c = icmp eq i32* %x, null
+  br i1 %c, label %is_null, label %not_null, !make.implicit !0
+
+is_null:
+  ret i32 42
+
+not_null:
+  %t = load i32, i32* %x
+  ret i32 %t
+}
+
+define void @store(i32* %x, i32 %v) {
+entry:
+  %c = icmp eq i32* %x, null
+  br i1 %c, label %is_null, label %not_null, !make.implicit !0
+
+is_null:
+  ret void
+
+not_null:
+  store i32 %v, i32* %x
+  ret void
+}
+
+define i32 @main() {
+  ret i32 0
+}
+
+!0 = !{}
@@ -0,0 +1,21 @@
+; A caller with a stackmap at the return address of a call, whose callee hands the address of its
+; return address slot to a hook, which can redirect the return.
+
+declare void @llvm.experimental.stackmap(i64, i32, ...)
+declare i8* @llvm.addressofreturnaddress()
+
+define i64 @callee(i64 %a, void (i8*)* %hook) noinline {
+entry:
+  %slot = call i8* @llvm.addressofreturnaddress()
+  call void %hook(i8* %slot)
+  %r = mul i64 %a, 2
+  ret i64 %r
+}
+
+define i64 @caller(i64 %a, i64 %b, void (i8*)* %hook) {
+entry:
+  %r = call i64 @callee(i64 %a, void (i8*)* %hook)
+  call void (i64, i32, ...) @llvm.experimental.stackmap(i64 20, i32 0, i64 %b, i64 %r)
+  %sum = add i64 %r, %b
+  ret i64 %sum
+}
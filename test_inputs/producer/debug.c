@@ -0,0 +1,5 @@
+/* Compiled with debug info, so that the binary has a DW_AT_producer. */
+
+int debug_me(int x) {
+    return x + 1;
+}
@@ -0,0 +1,31 @@
+; A statepoint with both deopt operands and GC pointers. The GC pointers are a
+; base pointer and a pointer derived from it, which gives two (base, derived)
+; pairs: (%obj, %derived) and (%obj, %obj).
+
+declare void @callee()
+declare void @use(i8 addrspace(1)*)
+declare token @llvm.experimental.gc.statepoint.p0f_isVoidf(i64, i32, void ()*, i32, i32, ...)
+declare i8 addrspace(1)* @llvm.experimental.gc.relocate.p1i8(token, i32, i32)
+
+define i8 addrspace(1)* @test(i8 addrspace(1)* %obj, i64 %x) gc "statepoint-example" {
+entry:
+  %derived = getelementptr i8, i8 addrspace(1)* %obj, i64 %x
+  ; Statepoint ID 42, with the GC transition flag (1) set.
+  %tok = call token (i64, i32, void ()*, i32, i32, ...) @llvm.experimental.gc.statepoint.p0f_isVoidf(i64 42, i32 0, void ()* @callee, i32 0, i32 1, i32 0, i32 0) [ "deopt"(i64 %x, i32 7, i64 5000000000), "gc-live"(i8 addrspace(1)* %obj, i8 addrspace(1)* %derived) ]
+  %b = call i8 addrspace(1)* @llvm.experimental.gc.relocate.p1i8(token %tok, i32 0, i32 0)
+  %d = call i8 addrspace(1)* @llvm.experimental.gc.relocate.p1i8(token %tok, i32 0, i32 1)
+  call void @use(i8 addrspace(1)* %b)
+  ret i8 addrspace(1)* %d
+}
+
+; A statepoint whose GC pointers are constants: a null pointer and a non-null
+; one, neither of which can be relocated.
+define void @constants() gc "statepoint-example" {
+entry:
+  %tok = call token (i64, i32, void ()*, i32, i32, ...) @llvm.experimental.gc.statepoint.p0f_isVoidf(i64 43, i32 0, void ()* @callee, i32 0, i32 0, i32 0, i32 0) [ "gc-live"(i8 addrspace(1)* null, i8 addrspace(1)* inttoptr (i64 4096 to i8 addrspace(1)*)) ]
+  %a = call i8 addrspace(1)* @llvm.experimental.gc.relocate.p1i8(token %tok, i32 0, i32 0)
+  %b = call i8 addrspace(1)* @llvm.experimental.gc.relocate.p1i8(token %tok, i32 1, i32 1)
+  store volatile i8 0, i8 addrspace(1)* %a
+  store volatile i8 0, i8 addrspace(1)* %b
+  ret void
+}
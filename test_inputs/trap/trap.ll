@@ -0,0 +1,21 @@
+; Guards that trap on purpose, each followed by a stackmap describing the live values at the trap.
+
+declare void @llvm.experimental.stackmap(i64, i32, ...)
+
+define i64 @guard_int3(i64 %a, i64 %b) {
+entry:
+  call void asm sideeffect "int3", ""()
+  call void (i64, i32, ...) @llvm.experimental.stackmap(i64 10, i32 0, i64 %a, i64 %b, i64 42)
+  %sum = add i64 %a, %b
+  ret i64 %sum
+}
+
+define i64 @guard_ud2(i64 %a) {
+entry:
+  %slot = alloca i64
+  store volatile i64 %a, i64* %slot
+  call void asm sideeffect "ud2", ""()
+  call void (i64, i32, ...) @llvm.experimental.stackmap(i64 11, i32 0, i64 %a, i64* %slot)
+  %v = load volatile i64, i64* %slot
+  ret i64 %v
+}
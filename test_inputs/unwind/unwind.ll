@@ -0,0 +1,43 @@
+; A call chain that passes through a function without a frame pointer. `@middle` keeps a value in
+; RBP and saves other callee-saved registers, so following the frame pointer chain from `@inner`
+; gets lost, whereas the call frame information in `.eh_frame` describes how to unwind it.
+; (Functions with stackmaps always get a frame pointer on x86_64).
+
+declare void @llvm.experimental.stackmap(i64, i32, ...)
+
+define i64 @inner(i64 %a) #0 {
+entry:
+  call void asm sideeffect "", "~{rax},~{rcx},~{rdx},~{rsi},~{rdi},~{r8},~{r9},~{r10},~{r11}"()
+  call void (i64, i32, ...) @llvm.experimental.stackmap(i64 1, i32 0, i64 %a)
+  ret i64 %a
+}
+
+define i64 @middle(i64 %a) #1 {
+entry:
+  %buf = alloca [4 x i64], align 16
+  %p = getelementptr [4 x i64], [4 x i64]* %buf, i64 0, i64 1
+  store volatile i64 %a, i64* %p
+  call void asm sideeffect "", "~{rbx},~{rbp},~{r12}"()
+  %r = call i64 @inner(i64 %a)
+  %v = load volatile i64, i64* %p
+  %sum = add i64 %r, %v
+  ret i64 %sum
+}
+
+define i64 @outer(i64 %a) #0 {
+entry:
+  %r = call i64 @middle(i64 %a)
+  call void (i64, i32, ...) @llvm.experimental.stackmap(i64 2, i32 0, i64 %a, i64 %r)
+  %sum = add i64 %a, %r
+  ret i64 %sum
+}
+
+define i32 @main() #0 {
+entry:
+  %r = call i64 @outer(i64 1)
+  %t = trunc i64 %r to i32
+  ret i32 %t
+}
+
+attributes #0 = { noinline uwtable }
+attributes #1 = { noinline uwtable "frame-pointer"="none" }